@@ -0,0 +1,135 @@
+use crate::error::AppError;
+use crate::open_telemetry::LogFilterHandle;
+use axum::extract::{Request, State};
+use axum::http::header;
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::Router;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use tracing::{info, warn};
+
+/// Routes operating the service, to be nested under `/admin`:
+///
+/// - `GET /log-filter` returns the current filter directives, in the `RUST_LOG` syntax.
+/// - `PUT /log-filter` replaces them with the directives in the request body.
+/// - `GET /profile?seconds=10&format=flamegraph` samples the CPU and returns the profile, as a
+///   flamegraph SVG or in the pprof format (`format=pprof`). Linux only.
+///
+/// Every request must carry `Authorization: Bearer <token>`.
+pub fn admin_router<S>(token: impl Into<String>, log_filter: LogFilterHandle) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    let token: Arc<str> = token.into().into();
+    let router = Router::new().route(
+        "/log-filter",
+        get(get_log_filter_handler).put(put_log_filter_handler),
+    );
+    #[cfg(target_os = "linux")]
+    let router = router.route("/profile", get(crate::profiling::profile_handler));
+    router
+        .with_state(log_filter)
+        .layer(middleware::from_fn_with_state(token, require_token))
+}
+
+async fn require_token(State(token): State<Arc<str>>, request: Request, next: Next) -> Response {
+    let authorized = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|candidate| constant_time_eq(candidate.as_bytes(), token.as_bytes()));
+    if !authorized {
+        warn!(path = request.uri().path(), "Unauthorized admin request");
+        return AppError::Unauthorized.into_response();
+    }
+    next.run(request).await
+}
+
+// Compares the SHA-256 digests of the inputs, of the same length whatever the inputs, in full
+// whatever the first difference: timing leaks neither the token nor its length.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let (a, b) = (Sha256::digest(a), Sha256::digest(b));
+    a.iter().zip(&b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+async fn get_log_filter_handler(
+    State(log_filter): State<LogFilterHandle>,
+) -> Result<String, AppError> {
+    Ok(log_filter.directives()?)
+}
+
+async fn put_log_filter_handler(
+    State(log_filter): State<LogFilterHandle>,
+    directives: String,
+) -> Result<String, AppError> {
+    let directives = directives.trim();
+    log_filter.set_directives(directives)?;
+    info!(directives, "Log filter updated");
+    Ok(log_filter.directives()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::StatusCode;
+    use tracing_subscriber::EnvFilter;
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"s3cret", b"s3cret"));
+        assert!(!constant_time_eq(b"s3cret", b"s3creT"));
+        assert!(!constant_time_eq(b"s3cret", b"s3cret!"));
+        assert!(!constant_time_eq(b"", b"s3cret"));
+    }
+
+    #[tokio::test]
+    async fn test_log_filter_requires_token() {
+        let (_filter, log_filter) = LogFilterHandle::new(EnvFilter::new("info"));
+        let router: Router = Router::new().nest("/admin", admin_router("s3cret", log_filter));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/admin/log-filter", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        let client = reqwest::Client::new();
+
+        let response = client.get(&url).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = client.get(&url).bearer_auth("wrong").send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = client.get(&url).bearer_auth("s3cret").send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.text().await.unwrap(), "info");
+
+        let response = client
+            .put(&url)
+            .bearer_auth("s3cret")
+            .body("warn,crate_axum_opentelemetry=debug")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response
+            .text()
+            .await
+            .unwrap()
+            .contains("crate_axum_opentelemetry=debug"));
+
+        let response = client
+            .put(&url)
+            .bearer_auth("s3cret")
+            .body("crate_axum_opentelemetry=loud")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = client.get(&url).bearer_auth("s3cret").send().await.unwrap();
+        assert!(response
+            .text()
+            .await
+            .unwrap()
+            .contains("crate_axum_opentelemetry=debug"));
+    }
+}
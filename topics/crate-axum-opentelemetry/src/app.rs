@@ -0,0 +1,324 @@
+use crate::admin::admin_router;
+use crate::error::{AppError, Problem};
+use crate::health_outcomes::{request_seed, HealthOutcomes, Outcome};
+use crate::http_trace::trace_http_request;
+use crate::metrics::{track_http_metrics, AppMetrics};
+use crate::open_telemetry::LogFilterHandle;
+use crate::openapi::{api_router, openapi_router};
+use crate::rate_limit::Limiter;
+use crate::request_context::propagate_request_context;
+use crate::settings::ServiceConfig;
+use crate::state::AppState;
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+use axum::{middleware, Router};
+// use axum_tracing_opentelemetry::middleware::OtelAxumLayer;
+// use axum_tracing_opentelemetry::middleware::OtelInResponseLayer;
+use tracing::{error, info, warn};
+
+/// Answers `200 OK`, `401 Unauthorized` or `403 Forbidden` at random, as weighted by
+/// `HEALTH_OUTCOME_WEIGHTS`.
+#[utoipa::path(
+    get,
+    path = "/health",
+    tag = "demo",
+    params(
+        ("x-health-seed" = Option<u64>, Header, description = "Seed deciding the outcome of the request alone, to reproduce it"),
+    ),
+    responses(
+        (status = 200, description = "Drawn one time out of three, by default"),
+        (status = 401, description = "Drawn one time out of three, by default", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Drawn one time out of three, by default", body = Problem, content_type = "application/problem+json"),
+        (status = 400, description = "`x-health-seed` is not an unsigned integer", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Beyond a rate limit", body = Problem, content_type = "application/problem+json",
+            headers(("Retry-After" = u64, description = "Seconds to wait before retrying"))),
+        (status = 503, description = "Beyond the concurrency limit", body = Problem, content_type = "application/problem+json",
+            headers(("Retry-After" = u64, description = "Seconds to wait before retrying"))),
+    )
+)]
+#[tracing::instrument(name = "health_handler", level = "info", skip_all)]
+pub async fn health_handler(
+    State(metrics): State<AppMetrics>,
+    State(outcomes): State<HealthOutcomes>,
+    headers: HeaderMap,
+) -> Result<StatusCode, AppError> {
+    // A malformed seed draws no outcome: the request is not counted in the outcomes.
+    let seed = request_seed(&headers)?;
+    let result = match outcomes.draw(seed) {
+        Outcome::Ok => {
+            info!("Returning Ok Response");
+            Ok(StatusCode::OK)
+        }
+        Outcome::Unauthorized => {
+            error!("Returning Error Response");
+            Err(AppError::Unauthorized)
+        }
+        Outcome::Forbidden => {
+            warn!("Returning Forbidden");
+            Err(AppError::Forbidden)
+        }
+    };
+    metrics.record_health_outcome(match &result {
+        Ok(status) => *status,
+        Err(err) => err.status(),
+    });
+    result
+}
+
+/// Calls the downstream service and returns its status code, or `502 Bad Gateway` if it cannot be reached.
+#[utoipa::path(
+    get,
+    path = "/chain",
+    tag = "demo",
+    responses(
+        (status = 200, description = "Status code of the downstream service, `/health` by default"),
+        (status = 401, description = "Status code of the downstream service"),
+        (status = 403, description = "Status code of the downstream service"),
+        (status = 502, description = "The downstream service cannot be reached", body = Problem, content_type = "application/problem+json"),
+        (status = "default", description = "Any other status code of the downstream service, forwarded as is"),
+        (status = 429, description = "Beyond a rate limit", body = Problem, content_type = "application/problem+json",
+            headers(("Retry-After" = u64, description = "Seconds to wait before retrying"))),
+        (status = 503, description = "Beyond the concurrency limit", body = Problem, content_type = "application/problem+json",
+            headers(("Retry-After" = u64, description = "Seconds to wait before retrying"))),
+    )
+)]
+#[tracing::instrument(name = "chain_handler", level = "info", skip(state))]
+pub async fn chain_handler(State(state): State<AppState>) -> Result<StatusCode, AppError> {
+    match state.http_client.get(state.downstream_url.clone()).await {
+        Ok(response) => {
+            info!(
+                status = response.status().as_u16(),
+                "Downstream service responded"
+            );
+            Ok(response.status())
+        }
+        Err(err) => {
+            error!(error = %err, "Downstream service is unreachable");
+            Err(AppError::DownstreamUnavailable(err))
+        }
+    }
+}
+
+/// Builds the router of the service with its middlewares, without binding it: `serve` serves it,
+/// and the tests call it through `tower::ServiceExt` (see `crate::test_app::TestApp`).
+pub fn router(
+    state: AppState,
+    service: &ServiceConfig,
+    limiter: Limiter,
+    log_filter: LogFilterHandle,
+) -> Router {
+    // The document is generated from the routes actually served.
+    let (router, openapi) = api_router(&service.routes, limiter).split_for_parts();
+    let mut router = router.merge(openapi_router(openapi));
+    // The admin routes are only served when protected by a token.
+    match &service.admin_token {
+        Some(token) => {
+            router = router.nest("/admin", admin_router(token.as_str(), log_filter));
+        }
+        None => info!("ADMIN_TOKEN is not set, admin routes are disabled"),
+    }
+    router
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            track_http_metrics,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            propagate_request_context,
+        ))
+        .layer(middleware::from_fn(trace_http_request))
+        .with_state(state)
+    // .layer(OtelInResponseLayer::default())
+    // .layer(OtelAxumLayer::default());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::health_outcomes::SEED_HEADER;
+    use crate::test_app::{attribute, TestApp};
+    use axum::body::Body;
+    use axum::http::Request;
+    use opentelemetry::trace::{SpanKind, Status};
+    use opentelemetry::{KeyValue, Value};
+    use std::collections::HashMap;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_chain_is_traced_across_both_hops() {
+        // The service is its own downstream service, as with the default settings.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let downstream_port = listener.local_addr().unwrap().port();
+        let downstream_url = format!("http://127.0.0.1:{downstream_port}/health");
+        let app = TestApp::with_settings(&[("DOWNSTREAM_URL", &downstream_url)]);
+        app.serve(listener);
+
+        let response = app.get("/chain").await;
+
+        let chain = app.span_chain(&[
+            "GET /chain",
+            "chain_handler",
+            "GET",
+            "GET /health",
+            "health_handler",
+        ]);
+        let [server, _, client, downstream, _] = &chain[..] else {
+            unreachable!()
+        };
+        let trace_id = server.span_context.trace_id();
+        assert!(chain
+            .iter()
+            .all(|span| span.span_context.trace_id() == trace_id));
+        assert_eq!(client.span_kind, SpanKind::Client);
+        assert_eq!(
+            attribute(client, "server.port"),
+            Some(i64::from(downstream_port).into())
+        );
+        assert_eq!(downstream.span_kind, SpanKind::Server);
+        // The status code of `/health` is forwarded by `/chain`.
+        let status = Value::from(i64::from(response.status().as_u16()));
+        for span in [server, client, downstream] {
+            assert_eq!(
+                attribute(span, "http.response.status_code"),
+                Some(status.clone())
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_downstream_is_a_server_error() {
+        let app = TestApp::with_settings(&[("DOWNSTREAM_URL", "http://127.0.0.1:1/health")]);
+
+        let response = app.get("/chain").await;
+
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        let server = &app.span_chain(&["GET /chain", "chain_handler"])[0];
+        assert_eq!(
+            attribute(server, "error.type"),
+            Some("downstream_unavailable".into())
+        );
+        assert!(matches!(server.status, Status::Error { .. }));
+        let attributes = [
+            KeyValue::new("http.route", "/chain"),
+            KeyValue::new("http.response.status_code", 502),
+        ];
+        assert_eq!(app.counter("http.server.request.count", &attributes), 1);
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_requests_are_no_longer_active() {
+        // Accepts connections, but never answers: `/chain` waits until cancelled.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let downstream_url = format!("http://{}/health", listener.local_addr().unwrap());
+        let app = TestApp::with_settings(&[("DOWNSTREAM_URL", &downstream_url)]);
+        let attributes = [KeyValue::new("http.route", "/chain")];
+
+        let mut request = Box::pin(app.get("/chain"));
+        let pending = tokio::time::timeout(Duration::from_millis(100), &mut request).await;
+        assert!(pending.is_err());
+        assert_eq!(
+            app.up_down_counter("http.server.active_requests", &attributes),
+            1
+        );
+        // Dropped before the response, as when its client disconnects.
+        drop(request);
+        assert_eq!(
+            app.up_down_counter("http.server.active_requests", &attributes),
+            0
+        );
+        drop(listener);
+    }
+
+    #[tokio::test]
+    async fn test_health_outcomes_are_counted() {
+        let app = TestApp::new();
+
+        let mut statuses = HashMap::new();
+        for _ in 0..30 {
+            let status = app.get("/health").await.status();
+            *statuses.entry(status).or_insert(0) += 1;
+        }
+
+        for (status, count) in statuses {
+            let status = KeyValue::new("http.response.status_code", i64::from(status.as_u16()));
+            assert_eq!(
+                app.counter("health_handler.outcomes", std::slice::from_ref(&status)),
+                count
+            );
+            let attributes = [KeyValue::new("http.route", "/health"), status];
+            assert_eq!(app.counter("http.server.request.count", &attributes), count);
+        }
+        assert_eq!(app.spans().len(), 2 * 30);
+
+        // A malformed seed is rejected before any outcome is drawn.
+        let request = Request::get("/health").header(SEED_HEADER, "seven");
+        let response = app.request(request.body(Body::empty()).unwrap()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let status = KeyValue::new("http.response.status_code", 400_i64);
+        assert_eq!(
+            app.counter("health_handler.outcomes", std::slice::from_ref(&status)),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn test_health_outcomes_follow_the_weights() {
+        const REQUESTS: usize = 3000;
+        let app = TestApp::with_settings(&[
+            ("HEALTH_OUTCOME_WEIGHTS", "200=6,401=3,403=1"),
+            ("HEALTH_SEED", "7"),
+        ]);
+
+        let mut statuses = HashMap::new();
+        for _ in 0..REQUESTS {
+            let status = app.get("/health").await.status();
+            *statuses.entry(status.as_u16()).or_insert(0) += 1;
+        }
+
+        for (status, expected_ratio) in [(200_u16, 0.6), (401, 0.3), (403, 0.1)] {
+            let count = statuses.get(&status).copied().unwrap_or(0);
+            let ratio = count as f64 / REQUESTS as f64;
+            assert!(
+                (ratio - expected_ratio).abs() < 0.03,
+                "{status} answered {ratio:.3} of the time, expected {expected_ratio}"
+            );
+            // Every response is counted, by both the handler and the middleware.
+            let status = KeyValue::new("http.response.status_code", i64::from(status));
+            assert_eq!(
+                app.counter("health_handler.outcomes", std::slice::from_ref(&status)),
+                count
+            );
+            let attributes = [KeyValue::new("http.route", "/health"), status];
+            assert_eq!(app.counter("http.server.request.count", &attributes), count);
+        }
+        let handler_spans = app
+            .spans()
+            .into_iter()
+            .filter(|span| span.name == "health_handler")
+            .count();
+        assert_eq!(handler_spans, REQUESTS);
+
+        // The seed of a request decides its outcome alone.
+        let seeded = || {
+            let request = Request::get("/health").header(SEED_HEADER, "42");
+            app.request(request.body(Body::empty()).unwrap())
+        };
+        let status = seeded().await.status();
+        for _ in 0..10 {
+            assert_eq!(seeded().await.status(), status);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_routes_left_out_are_not_served() {
+        let app = TestApp::with_settings(&[("ROUTES", "/health")]);
+
+        assert_eq!(app.get("/chain").await.status(), StatusCode::NOT_FOUND);
+        assert_ne!(app.get("/health").await.status(), StatusCode::NOT_FOUND);
+        assert_eq!(app.get("/livez").await.status(), StatusCode::OK);
+        // Without `ADMIN_TOKEN`, neither are the admin routes.
+        let response = app.get("/admin/log-filter").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,8 @@
+/// An unsigned integer as an `i64` attribute value, saturating at `i64::MAX`.
+///
+/// Attribute values have no unsigned type: `tracing-opentelemetry` exports the `u64` span fields
+/// (and so the `u16` and `u32` ones) as strings, e.g. `"200"` for a status code. Recorded as an
+/// `i64`, the value stays an integer that backends can compare and aggregate.
+pub fn unsigned(value: impl TryInto<i64>) -> i64 {
+    value.try_into().unwrap_or(i64::MAX)
+}
@@ -0,0 +1,268 @@
+use crate::open_telemetry::{LogFilterError, TelemetryError};
+use crate::settings::SettingsError;
+use crate::telemetry_config::ConfigError;
+use axum::http::{header, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use opentelemetry::trace::TraceContextExt;
+use serde::{Deserialize, Serialize};
+use std::error::Error as _;
+use std::net::SocketAddr;
+use std::time::Duration;
+use thiserror::Error;
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use utoipa::ToSchema;
+
+/// Errors returned by the handlers, turned into RFC 7807 `application/problem+json` responses.
+#[derive(Debug, Error)]
+pub enum AppError {
+    #[error("Authentication is required")]
+    Unauthorized,
+    #[error("Access to this resource is forbidden")]
+    Forbidden,
+    #[error("Bad request: {0}")]
+    BadRequest(String),
+    // The source is left out of the message, as it names internal hosts.
+    #[error("The downstream service is unreachable")]
+    DownstreamUnavailable(#[from] reqwest::Error),
+    #[error(transparent)]
+    LogFilter(#[from] LogFilterError),
+    #[error("A CPU profile is already being recorded")]
+    ProfilingInProgress,
+    #[error("Unable to record the CPU profile: {0}")]
+    Profiling(String),
+    #[error("Too many requests, retry later")]
+    RateLimited { retry_after: Duration },
+    #[error("The service is overloaded, retry later")]
+    Overloaded { retry_after: Duration },
+    #[error("The job queue is full, retry later")]
+    QueueFull,
+    #[error("The job worker is stopped")]
+    JobWorkerStopped,
+}
+
+impl AppError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::Unauthorized => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden => StatusCode::FORBIDDEN,
+            AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::DownstreamUnavailable(_) => StatusCode::BAD_GATEWAY,
+            AppError::LogFilter(LogFilterError::Invalid(_)) => StatusCode::BAD_REQUEST,
+            AppError::LogFilter(LogFilterError::Reload(_)) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::ProfilingInProgress => StatusCode::CONFLICT,
+            AppError::Profiling(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            AppError::Overloaded { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::QueueFull => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::JobWorkerStopped => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Low-cardinality name of the error, recorded as the `error.type` attribute.
+    pub fn error_type(&self) -> &'static str {
+        match self {
+            AppError::Unauthorized => "unauthorized",
+            AppError::Forbidden => "forbidden",
+            AppError::BadRequest(_) => "bad_request",
+            AppError::DownstreamUnavailable(_) => "downstream_unavailable",
+            AppError::LogFilter(LogFilterError::Invalid(_)) => "invalid_log_filter",
+            AppError::LogFilter(LogFilterError::Reload(_)) => "log_filter_reload",
+            AppError::ProfilingInProgress => "profiling_in_progress",
+            AppError::Profiling(_) => "profiling_failed",
+            AppError::RateLimited { .. } => "rate_limited",
+            AppError::Overloaded { .. } => "overloaded",
+            AppError::QueueFull => "job_queue_full",
+            AppError::JobWorkerStopped => "job_worker_stopped",
+        }
+    }
+
+    /// Time after which the request may be retried, sent in the `Retry-After` header.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            AppError::RateLimited { retry_after } | AppError::Overloaded { retry_after } => {
+                Some(*retry_after)
+            }
+            // The worker takes a job every 100 ms by default.
+            AppError::QueueFull => Some(Duration::from_secs(1)),
+            _ => None,
+        }
+    }
+}
+
+/// Body of the error responses (RFC 7807). `trace_id` links the response to its trace.
+#[derive(Debug, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub problem_type: String,
+    pub title: String,
+    pub status: u16,
+    pub detail: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trace_id: Option<String>,
+}
+
+/// Left in the extensions of an error response for `trace_http_request`, which records it on the
+/// server span: `error.type`, and the span status for server errors.
+#[derive(Debug, Clone)]
+pub struct ResponseError {
+    pub error_type: &'static str,
+    /// Message of the error and of its sources: unlike the response, it may hold internal details.
+    pub description: String,
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        let span_context = Span::current().context().span().span_context().clone();
+        let problem = Problem {
+            problem_type: "about:blank".to_owned(),
+            title: status.canonical_reason().unwrap_or_default().to_owned(),
+            status: status.as_u16(),
+            detail: self.to_string(),
+            trace_id: span_context
+                .is_valid()
+                .then(|| span_context.trace_id().to_string()),
+        };
+
+        let mut description = self.to_string();
+        let mut source = self.source();
+        while let Some(err) = source {
+            description.push_str(&format!(": {err}"));
+            source = err.source();
+        }
+
+        let mut response = (status, Json(problem)).into_response();
+        response.headers_mut().insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/problem+json"),
+        );
+        if let Some(retry_after) = self.retry_after() {
+            // Whole seconds, rounded up so that the client does not come back too early.
+            let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(seconds.max(1)));
+        }
+        response.extensions_mut().insert(ResponseError {
+            error_type: self.error_type(),
+            description,
+        });
+        response
+    }
+}
+
+/// Errors that prevent the service from starting, reported by `main` before exiting.
+#[derive(Debug, Error)]
+pub enum StartupError {
+    #[error("Invalid telemetry configuration: {0}")]
+    Config(#[from] ConfigError),
+    #[error(transparent)]
+    Settings(#[from] SettingsError),
+    #[error(transparent)]
+    Telemetry(#[from] TelemetryError),
+    #[error("Invalid value {value:?} for {name}: {reason}")]
+    InvalidVariable {
+        name: &'static str,
+        value: String,
+        reason: String,
+    },
+    /// An invalid value, with the source and the key that set it (see `Settings::read`).
+    #[error("{error} (set by {origin})")]
+    InvalidSetting {
+        error: Box<StartupError>,
+        origin: String,
+    },
+    #[error("Unable to build the HTTP client: {0}")]
+    HttpClient(#[from] reqwest::Error),
+    #[error("Unable to listen on {address}: {source}")]
+    Bind {
+        address: SocketAddr,
+        source: std::io::Error,
+    },
+    #[error("The server stopped with an error: {0}")]
+    Serve(#[source] std::io::Error),
+    #[error("The telemetry shutdown task failed: {0}")]
+    ShutdownTask(#[from] tokio::task::JoinError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_app::{attribute, TestApp};
+    use axum::body::to_bytes;
+    use opentelemetry::trace::Status;
+
+    #[tokio::test]
+    async fn test_problem_json_response() {
+        let response = AppError::Forbidden.into_response();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/problem+json"
+        );
+        assert_eq!(
+            response
+                .extensions()
+                .get::<ResponseError>()
+                .unwrap()
+                .error_type,
+            "forbidden"
+        );
+
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let problem: Problem = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            problem,
+            Problem {
+                problem_type: "about:blank".to_owned(),
+                title: "Forbidden".to_owned(),
+                status: 403,
+                detail: "Access to this resource is forbidden".to_owned(),
+                // No span outside of a request.
+                trace_id: None,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_error_is_recorded_on_the_server_span() {
+        let app = TestApp::with_settings(&[
+            ("HEALTH_OUTCOME_WEIGHTS", "403=1"),
+            // Connecting to port 1 is refused.
+            ("DOWNSTREAM_URL", "http://127.0.0.1:1/health"),
+        ]);
+
+        let response = app.get("/health").await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let forbidden: Problem = serde_json::from_slice(&body).unwrap();
+        let response = app.get("/chain").await;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let unavailable: Problem = serde_json::from_slice(&body).unwrap();
+        // The response does not tell which host could not be reached...
+        assert_eq!(unavailable.detail, "The downstream service is unreachable");
+
+        // A client error is recorded, but does not set the status of a server span.
+        let server = app.span("GET /health");
+        assert_eq!(attribute(&server, "error.type"), Some("forbidden".into()));
+        assert_eq!(server.status, Status::Unset);
+        assert_eq!(
+            forbidden.trace_id,
+            Some(server.span_context.trace_id().to_string())
+        );
+        // ...unlike the span.
+        let server = app.span("GET /chain");
+        assert_eq!(
+            attribute(&server, "error.type"),
+            Some("downstream_unavailable".into())
+        );
+        let Status::Error { description } = &server.status else {
+            panic!("expected an error status");
+        };
+        assert!(description.starts_with("The downstream service is unreachable: "));
+        assert!(description.contains("127.0.0.1:1"));
+    }
+}
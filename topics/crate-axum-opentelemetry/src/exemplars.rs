@@ -0,0 +1,380 @@
+use axum::http::{header, HeaderMap, HeaderValue};
+use flate2::write::GzEncoder;
+use opentelemetry::trace::{SpanContext, SpanId, TraceId};
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::tonic_types::metadata::MetadataMap;
+use opentelemetry_proto::tonic::collector::metrics::v1::metrics_service_client::MetricsServiceClient;
+use opentelemetry_proto::tonic::collector::metrics::v1::ExportMetricsServiceRequest;
+use opentelemetry_proto::tonic::common::v1::{any_value, KeyValue as ProtoKeyValue};
+use opentelemetry_proto::tonic::metrics::v1::{exemplar, metric, Exemplar as ProtoExemplar};
+use opentelemetry_sdk::error::{OTelSdkError, OTelSdkResult};
+use opentelemetry_sdk::metrics::data::ResourceMetrics;
+use opentelemetry_sdk::metrics::exporter::PushMetricExporter;
+use opentelemetry_sdk::metrics::Temporality;
+use prost::Message;
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tonic::codec::CompressionEncoding;
+use tonic::transport::Channel;
+
+/// Latest sampled measurement of each bucket of the histograms, kept until the next export,
+/// which attaches it to the data point of its series as an exemplar: a latency spike on a
+/// dashboard then links to a trace of a request that fell in the slow buckets.
+///
+/// The SDK does not record exemplars: they are recorded here, by the instruments' callers.
+#[derive(Debug, Clone, Default)]
+pub struct Exemplars {
+    series: Arc<Mutex<HashMap<SeriesKey, Vec<Option<Exemplar>>>>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct SeriesKey {
+    metric: String,
+    /// Sorted `key=value` pairs, as the attributes of a data point may come in any order.
+    attributes: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Exemplar {
+    value: f64,
+    time: SystemTime,
+    trace_id: TraceId,
+    span_id: SpanId,
+}
+
+impl Exemplars {
+    /// Records `value`, measured by the histogram `metric` with `attributes`, as the exemplar of
+    /// its bucket. Measurements outside of a sampled span are ignored: they have no trace to link.
+    pub fn record(
+        &self,
+        metric: &str,
+        boundaries: &[f64],
+        value: f64,
+        attributes: &[KeyValue],
+        span_context: &SpanContext,
+    ) {
+        if !span_context.is_sampled() {
+            return;
+        }
+        // Upper bounds are inclusive, as in the histogram aggregation of the SDK.
+        let bucket = boundaries
+            .iter()
+            .position(|boundary| value <= *boundary)
+            .unwrap_or(boundaries.len());
+        let key = SeriesKey {
+            metric: metric.to_owned(),
+            attributes: sorted(
+                attributes
+                    .iter()
+                    .map(|kv| format!("{}={}", kv.key, kv.value)),
+            ),
+        };
+        let mut series = self.series.lock().unwrap();
+        let buckets = series
+            .entry(key)
+            .or_insert_with(|| vec![None; boundaries.len() + 1]);
+        buckets[bucket] = Some(Exemplar {
+            value,
+            time: SystemTime::now(),
+            trace_id: span_context.trace_id(),
+            span_id: span_context.span_id(),
+        });
+    }
+
+    /// Moves the recorded exemplars to the histogram data points of `request`.
+    fn attach(&self, request: &mut ExportMetricsServiceRequest) {
+        let mut series = std::mem::take(&mut *self.series.lock().unwrap());
+        if series.is_empty() {
+            return;
+        }
+        let metrics = request
+            .resource_metrics
+            .iter_mut()
+            .flat_map(|resource| &mut resource.scope_metrics)
+            .flat_map(|scope| &mut scope.metrics);
+        for metric in metrics {
+            let Some(metric::Data::Histogram(histogram)) = &mut metric.data else {
+                continue;
+            };
+            for point in &mut histogram.data_points {
+                let key = SeriesKey {
+                    metric: metric.name.clone(),
+                    attributes: sorted(point.attributes.iter().map(proto_attribute)),
+                };
+                let Some(buckets) = series.remove(&key) else {
+                    continue;
+                };
+                point.exemplars = buckets.into_iter().flatten().map(to_proto).collect();
+            }
+        }
+    }
+}
+
+fn sorted(attributes: impl Iterator<Item = String>) -> Vec<String> {
+    let mut attributes: Vec<String> = attributes.collect();
+    attributes.sort();
+    attributes
+}
+
+// Same rendering as `opentelemetry::Value` for the scalar values recorded by the instruments.
+fn proto_attribute(kv: &ProtoKeyValue) -> String {
+    let value = match kv.value.as_ref().and_then(|value| value.value.as_ref()) {
+        Some(any_value::Value::StringValue(value)) => value.clone(),
+        Some(any_value::Value::IntValue(value)) => value.to_string(),
+        Some(any_value::Value::DoubleValue(value)) => value.to_string(),
+        Some(any_value::Value::BoolValue(value)) => value.to_string(),
+        _ => String::new(),
+    };
+    format!("{}={value}", kv.key)
+}
+
+fn to_proto(exemplar: Exemplar) -> ProtoExemplar {
+    ProtoExemplar {
+        filtered_attributes: Vec::new(),
+        time_unix_nano: exemplar
+            .time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64,
+        span_id: exemplar.span_id.to_bytes().to_vec(),
+        trace_id: exemplar.trace_id.to_bytes().to_vec(),
+        value: Some(exemplar::Value::AsDouble(exemplar.value)),
+    }
+}
+
+/// Transport of `ExemplarMetricExporter`, built by `OtlpExporters::metric_exporter`.
+pub enum MetricTransport {
+    Grpc {
+        client: MetricsServiceClient<Channel>,
+        metadata: MetadataMap,
+    },
+    /// Protobuf messages posted to `url`, compressed with gzip if `gzip` is set.
+    Http {
+        client: reqwest::blocking::Client,
+        url: String,
+        headers: HeaderMap,
+        gzip: bool,
+    },
+}
+
+impl MetricTransport {
+    pub fn grpc(channel: Channel, metadata: MetadataMap, gzip: bool) -> Self {
+        let mut client = MetricsServiceClient::new(channel);
+        if gzip {
+            client = client
+                .send_compressed(CompressionEncoding::Gzip)
+                .accept_compressed(CompressionEncoding::Gzip);
+        }
+        MetricTransport::Grpc { client, metadata }
+    }
+}
+
+/// OTLP metric exporter attaching `Exemplars` to the exported histograms.
+///
+/// It stands in for the exporter of `opentelemetry_otlp`, which cannot carry exemplars. Failed
+/// exports are not retried: the temporality is cumulative, so the next export carries the same
+/// measurements.
+pub struct ExemplarMetricExporter {
+    transport: MetricTransport,
+    exemplars: Exemplars,
+    is_shutdown: AtomicBool,
+}
+
+impl ExemplarMetricExporter {
+    pub fn new(transport: MetricTransport, exemplars: Exemplars) -> Self {
+        ExemplarMetricExporter {
+            transport,
+            exemplars,
+            is_shutdown: AtomicBool::new(false),
+        }
+    }
+
+    async fn send(&self, request: ExportMetricsServiceRequest) -> Result<(), String> {
+        match &self.transport {
+            MetricTransport::Grpc { client, metadata } => {
+                let request = tonic::Request::from_parts(
+                    metadata.clone(),
+                    tonic::Extensions::default(),
+                    request,
+                );
+                // The connections of the channel run on the Tokio runtime: the thread of the
+                // periodic reader only waits for the response.
+                client
+                    .clone()
+                    .export(request)
+                    .await
+                    .map(|_| ())
+                    .map_err(|status| status.to_string())
+            }
+            MetricTransport::Http {
+                client,
+                url,
+                headers,
+                gzip,
+            } => {
+                // The periodic reader exports from its own thread, outside of the Tokio runtime.
+                let mut body = request.encode_to_vec();
+                let mut request = client.post(url).headers(headers.clone()).header(
+                    header::CONTENT_TYPE,
+                    HeaderValue::from_static("application/x-protobuf"),
+                );
+                if *gzip {
+                    let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                    encoder.write_all(&body).map_err(|err| err.to_string())?;
+                    body = encoder.finish().map_err(|err| err.to_string())?;
+                    request = request.header(header::CONTENT_ENCODING, "gzip");
+                }
+                request
+                    .body(body)
+                    .send()
+                    .and_then(|response| response.error_for_status())
+                    .map(|_| ())
+                    .map_err(|err| err.to_string())
+            }
+        }
+    }
+}
+
+impl PushMetricExporter for ExemplarMetricExporter {
+    async fn export(&self, metrics: &ResourceMetrics) -> OTelSdkResult {
+        if self.is_shutdown.load(Ordering::Relaxed) {
+            return Err(OTelSdkError::AlreadyShutdown);
+        }
+        let mut request = ExportMetricsServiceRequest::from(metrics);
+        self.exemplars.attach(&mut request);
+        self.send(request)
+            .await
+            .map_err(OTelSdkError::InternalFailure)
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        Ok(())
+    }
+
+    fn shutdown_with_timeout(&self, _timeout: Duration) -> OTelSdkResult {
+        self.is_shutdown.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn temporality(&self) -> Temporality {
+        Temporality::Cumulative
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_collector::{MockCollector, Storage};
+    use crate::otlp_exporter::OtlpExporters;
+    use crate::telemetry_config::TelemetryConfig;
+    use opentelemetry::metrics::MeterProvider;
+    use opentelemetry::trace::{TraceFlags, TraceState};
+    use opentelemetry_sdk::metrics::SdkMeterProvider;
+
+    const BOUNDARIES: [f64; 2] = [0.1, 1.0];
+
+    fn span_context(trace_id: u128, flags: TraceFlags) -> SpanContext {
+        SpanContext::new(
+            TraceId::from(trace_id),
+            SpanId::from(7),
+            flags,
+            false,
+            TraceState::default(),
+        )
+    }
+
+    // Records two requests of the same series in the same bucket, one in another bucket, and one
+    // outside of a sampled trace; then exports the histogram over `protocol`.
+    async fn export_exemplars(protocol: &str) -> MockCollector {
+        let collector = MockCollector::start(Storage::Memory).await;
+        let endpoint = match protocol {
+            "grpc" => collector.grpc_endpoint(),
+            _ => collector.http_endpoint(),
+        };
+        let vars = HashMap::from([
+            ("OTEL_EXPORTER_OTLP_PROTOCOL", protocol),
+            ("OTEL_EXPORTER_OTLP_ENDPOINT", endpoint),
+            ("OTEL_EXPORTER_OTLP_COMPRESSION", "gzip"),
+        ]);
+        let config =
+            TelemetryConfig::from_lookup(|name| vars.get(name).map(|value| value.to_string()))
+                .unwrap();
+        let exemplars = Exemplars::default();
+        let exporter = OtlpExporters::new(&config)
+            .unwrap()
+            .metric_exporter(exemplars.clone())
+            .unwrap();
+        let provider = SdkMeterProvider::builder()
+            .with_periodic_exporter(exporter)
+            .build();
+        let histogram = provider
+            .meter("test")
+            .f64_histogram("request.duration")
+            .with_boundaries(BOUNDARIES.to_vec())
+            .build();
+
+        let measurements = [
+            (0.05, 1, TraceFlags::SAMPLED),
+            (0.5, 2, TraceFlags::SAMPLED),
+            (0.07, 3, TraceFlags::SAMPLED),
+            (5.0, 4, TraceFlags::default()),
+        ];
+        for (value, trace_id, flags) in measurements {
+            // The attributes of the exemplars are sorted, unlike those of the data points.
+            histogram.record(value, &[KeyValue::new("a", 1), KeyValue::new("b", "x")]);
+            exemplars.record(
+                "request.duration",
+                &BOUNDARIES,
+                value,
+                &[KeyValue::new("b", "x"), KeyValue::new("a", 1)],
+                &span_context(trace_id, flags),
+            );
+        }
+        tokio::task::spawn_blocking(move || provider.shutdown())
+            .await
+            .unwrap()
+            .unwrap();
+        collector
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_exemplars_are_attached_to_their_bucket() {
+        for protocol in ["grpc", "http/protobuf"] {
+            let collector = export_exemplars(protocol).await;
+            let Some(metric::Data::Histogram(histogram)) =
+                collector.metric("request.duration").unwrap().data
+            else {
+                panic!("expected a histogram");
+            };
+            let point = &histogram.data_points[0];
+            assert_eq!(point.count, 4);
+            // The latest measurement of each bucket wins.
+            let exemplars: Vec<_> = point
+                .exemplars
+                .iter()
+                .map(|exemplar| (exemplar.value, exemplar.trace_id.clone()))
+                .collect();
+            assert_eq!(
+                exemplars,
+                [
+                    (
+                        Some(exemplar::Value::AsDouble(0.07)),
+                        TraceId::from(3).to_bytes().to_vec()
+                    ),
+                    (
+                        Some(exemplar::Value::AsDouble(0.5)),
+                        TraceId::from(2).to_bytes().to_vec()
+                    ),
+                ],
+                "{protocol}"
+            );
+            assert_eq!(
+                point.exemplars[0].span_id,
+                SpanId::from(7).to_bytes().to_vec()
+            );
+        }
+    }
+}
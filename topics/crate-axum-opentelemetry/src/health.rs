@@ -0,0 +1,295 @@
+use crate::http_client::TracedHttpClient;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::Json;
+use reqwest::Url;
+use serde::Serialize;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::task::JoinSet;
+use tracing::{warn, Instrument};
+use utoipa::ToSchema;
+
+pub type CheckFuture<'a> = Pin<Box<dyn Future<Output = Result<(), String>> + Send + 'a>>;
+
+/// A dependency the service needs to serve requests (database, downstream service, exporter...).
+pub trait HealthCheck: Send + Sync {
+    /// Name of the check in the readiness report.
+    fn name(&self) -> &str;
+
+    /// Resolves to `Err` with a human-readable reason when the dependency is unusable.
+    fn check(&self) -> CheckFuture<'_>;
+}
+
+/// Checks a downstream HTTP service: healthy as long as it answers without a 5xx status code.
+pub struct HttpHealthCheck {
+    name: String,
+    client: TracedHttpClient,
+    url: Url,
+}
+
+impl HttpHealthCheck {
+    pub fn new(name: impl Into<String>, client: TracedHttpClient, url: Url) -> Self {
+        HttpHealthCheck {
+            name: name.into(),
+            client,
+            url,
+        }
+    }
+}
+
+impl HealthCheck for HttpHealthCheck {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn check(&self) -> CheckFuture<'_> {
+        Box::pin(async move {
+            let response = self
+                .client
+                .get(self.url.clone())
+                .await
+                .map_err(|err| err.to_string())?;
+            if response.status().is_server_error() {
+                return Err(format!("{} answered {}", self.url, response.status()));
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Checks that a TCP connection can be opened, e.g. to the OTLP collector the exporters push to.
+pub struct TcpHealthCheck {
+    name: String,
+    address: String,
+}
+
+impl TcpHealthCheck {
+    /// Uses the host and port of `url`, or the default port of its scheme.
+    pub fn from_url(name: impl Into<String>, url: &Url) -> Option<Self> {
+        Some(TcpHealthCheck {
+            name: name.into(),
+            address: format!("{}:{}", url.host_str()?, url.port_or_known_default()?),
+        })
+    }
+}
+
+impl HealthCheck for TcpHealthCheck {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn check(&self) -> CheckFuture<'_> {
+        Box::pin(async move {
+            TcpStream::connect(&self.address)
+                .await
+                .map(|_| ())
+                .map_err(|err| format!("{}: {err}", self.address))
+        })
+    }
+}
+
+#[derive(Debug, Serialize, PartialEq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Up,
+    Down,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CheckReport {
+    pub name: String,
+    pub status: Status,
+    pub latency_ms: f64,
+    /// Reason of a `down` status.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Body of `/readyz`, whatever its status code.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ReadinessReport {
+    pub ready: bool,
+    pub shutting_down: bool,
+    pub checks: Vec<CheckReport>,
+}
+
+/// Body of `/livez`.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Liveness {
+    /// Always `alive`.
+    pub status: String,
+}
+
+/// Registered health checks and readiness state of the service.
+///
+/// The service stops being ready when it starts shutting down, whatever the checks say.
+#[derive(Clone)]
+pub struct Health {
+    checks: Arc<Vec<Arc<dyn HealthCheck>>>,
+    timeout: Duration,
+    shutting_down: Arc<AtomicBool>,
+}
+
+impl Health {
+    /// Each check taking longer than `timeout` is reported down.
+    pub fn new(timeout: Duration) -> Self {
+        Health {
+            checks: Arc::new(Vec::new()),
+            timeout,
+            shutting_down: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn with_check(mut self, check: impl HealthCheck + 'static) -> Self {
+        Arc::make_mut(&mut self.checks).push(Arc::new(check));
+        self
+    }
+
+    pub fn start_shutdown(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+    }
+
+    /// Runs every check concurrently.
+    pub async fn readiness(&self) -> ReadinessReport {
+        let mut tasks = JoinSet::new();
+        for (index, check) in self.checks.iter().cloned().enumerate() {
+            let timeout = self.timeout;
+            tasks.spawn(
+                async move {
+                    let start = Instant::now();
+                    let result = match tokio::time::timeout(timeout, check.check()).await {
+                        Ok(result) => result,
+                        Err(_) => Err(format!("timed out after {timeout:?}")),
+                    };
+                    let latency_ms = start.elapsed().as_secs_f64() * 1000.0;
+                    if let Err(err) = &result {
+                        warn!(check = check.name(), error = %err, "Health check failed");
+                    }
+                    let report = CheckReport {
+                        name: check.name().to_owned(),
+                        status: if result.is_ok() {
+                            Status::Up
+                        } else {
+                            Status::Down
+                        },
+                        latency_ms,
+                        error: result.err(),
+                    };
+                    (index, report)
+                }
+                .in_current_span(),
+            );
+        }
+
+        let mut checks: Vec<(usize, CheckReport)> = tasks.join_all().await;
+        checks.sort_by_key(|(index, _)| *index);
+        let checks: Vec<CheckReport> = checks.into_iter().map(|(_, report)| report).collect();
+
+        let shutting_down = self.shutting_down.load(Ordering::SeqCst);
+        ReadinessReport {
+            ready: !shutting_down && checks.iter().all(|check| check.status == Status::Up),
+            shutting_down,
+            checks,
+        }
+    }
+}
+
+/// Liveness probe: the process is able to serve requests. Dependencies are not checked on purpose,
+/// an unavailable dependency must not get the service restarted.
+#[utoipa::path(
+    get,
+    path = "/livez",
+    tag = "probes",
+    responses((status = 200, description = "The process is alive", body = Liveness))
+)]
+pub async fn livez_handler() -> Json<Liveness> {
+    Json(Liveness {
+        status: "alive".to_owned(),
+    })
+}
+
+/// Readiness probe: `200` when every dependency is up, `503` otherwise or while shutting down.
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    tag = "probes",
+    responses(
+        (status = 200, description = "Every dependency is up", body = ReadinessReport),
+        (status = 503, description = "A dependency is down, or the service is shutting down", body = ReadinessReport),
+    )
+)]
+#[tracing::instrument(name = "readyz_handler", level = "info", skip(health))]
+pub async fn readyz_handler(State(health): State<Health>) -> (StatusCode, Json<ReadinessReport>) {
+    let report = health.readiness().await;
+    let status = if report.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct StaticCheck(&'static str, Result<(), String>, Duration);
+
+    impl HealthCheck for StaticCheck {
+        fn name(&self) -> &str {
+            self.0
+        }
+
+        fn check(&self) -> CheckFuture<'_> {
+            Box::pin(async move {
+                tokio::time::sleep(self.2).await;
+                self.1.clone()
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_readiness_reports_each_check() {
+        let health = Health::new(Duration::from_millis(100))
+            .with_check(StaticCheck("db", Ok(()), Duration::ZERO))
+            .with_check(StaticCheck("cache", Err("refused".into()), Duration::ZERO))
+            .with_check(StaticCheck("slow", Ok(()), Duration::from_secs(1)));
+
+        let report = health.readiness().await;
+        assert!(!report.ready);
+        let statuses: Vec<_> = report
+            .checks
+            .iter()
+            .map(|check| (check.name.as_str(), &check.status))
+            .collect();
+        assert_eq!(
+            statuses,
+            [
+                ("db", &Status::Up),
+                ("cache", &Status::Down),
+                ("slow", &Status::Down)
+            ]
+        );
+        assert_eq!(report.checks[1].error.as_deref(), Some("refused"));
+    }
+
+    #[tokio::test]
+    async fn test_not_ready_while_shutting_down() {
+        let health = Health::new(Duration::from_millis(100)).with_check(StaticCheck(
+            "db",
+            Ok(()),
+            Duration::ZERO,
+        ));
+        assert!(health.readiness().await.ready);
+
+        health.start_shutdown();
+        let report = health.readiness().await;
+        assert!(!report.ready);
+        assert!(report.shutting_down);
+    }
+}
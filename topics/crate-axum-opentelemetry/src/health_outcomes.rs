@@ -0,0 +1,250 @@
+use crate::error::{AppError, StartupError};
+use crate::settings::invalid;
+use axum::http::{HeaderMap, StatusCode};
+use rand::distr::weighted::WeightedIndex;
+use rand::rngs::ChaCha8Rng;
+use rand::{RngExt, SeedableRng};
+use std::sync::{Arc, Mutex};
+
+/// Header seeding the outcome of a single request, e.g. to reproduce it whatever the process.
+pub const SEED_HEADER: &str = "x-health-seed";
+
+/// Responses `health_handler` answers with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Ok,
+    Unauthorized,
+    Forbidden,
+}
+
+impl Outcome {
+    pub const ALL: [Outcome; 3] = [Outcome::Ok, Outcome::Unauthorized, Outcome::Forbidden];
+
+    pub fn status(self) -> StatusCode {
+        match self {
+            Outcome::Ok => StatusCode::OK,
+            Outcome::Unauthorized => StatusCode::UNAUTHORIZED,
+            Outcome::Forbidden => StatusCode::FORBIDDEN,
+        }
+    }
+}
+
+/// How the outcomes of `health_handler` are drawn, read from the environment:
+///
+/// | Variable | Default | Example |
+/// | --- | --- | --- |
+/// | `HEALTH_OUTCOME_WEIGHTS` | `200=1,401=1,403=1` | `200=8,401=1,403=1` |
+/// | `HEALTH_SEED` | random, logged at startup | `42` |
+///
+/// A status code left out of the weights is never drawn.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutcomesConfig {
+    /// Relative weights of the outcomes, in the order of `Outcome::ALL`.
+    pub weights: WeightedIndex<u32>,
+    /// Seed of the generator shared by the requests without `x-health-seed`.
+    pub seed: Option<u64>,
+}
+
+impl Default for OutcomesConfig {
+    fn default() -> Self {
+        OutcomesConfig {
+            weights: WeightedIndex::new([1; Outcome::ALL.len()]).unwrap(),
+            seed: None,
+        }
+    }
+}
+
+impl OutcomesConfig {
+    pub fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Result<Self, StartupError> {
+        let lookup = |name: &str| lookup(name).filter(|value| !value.trim().is_empty());
+
+        Ok(OutcomesConfig {
+            weights: match lookup("HEALTH_OUTCOME_WEIGHTS") {
+                Some(weights) => parse_weights(&weights)?,
+                None => OutcomesConfig::default().weights,
+            },
+            seed: lookup("HEALTH_SEED")
+                .map(|seed| {
+                    seed.trim()
+                        .parse()
+                        .map_err(|err| invalid("HEALTH_SEED", &seed, err))
+                })
+                .transpose()?,
+        })
+    }
+}
+
+// Format: `status=weight` separated by commas, e.g. `200=8,401=1,403=1`.
+fn parse_weights(rules: &str) -> Result<WeightedIndex<u32>, StartupError> {
+    let mut weights = [0; Outcome::ALL.len()];
+    for rule in rules
+        .split(',')
+        .map(str::trim)
+        .filter(|rule| !rule.is_empty())
+    {
+        let (index, weight) = rule
+            .split_once('=')
+            .and_then(|(status, weight)| {
+                let status: u16 = status.trim().parse().ok()?;
+                let index = Outcome::ALL
+                    .iter()
+                    .position(|outcome| outcome.status() == status)?;
+                Some((index, weight.trim().parse().ok()?))
+            })
+            .ok_or_else(|| {
+                invalid(
+                    "HEALTH_OUTCOME_WEIGHTS",
+                    rules,
+                    format!("expected `status=weight` rules separated by commas, the status being 200, 401 or 403, found {rule:?}"),
+                )
+            })?;
+        weights[index] = weight;
+    }
+    WeightedIndex::new(weights).map_err(|err| invalid("HEALTH_OUTCOME_WEIGHTS", rules, err))
+}
+
+/// Draws the outcomes of `health_handler`, from a generator seeded once for the process: a run
+/// with the same `HEALTH_SEED` answers the same sequence of outcomes. The generator is ChaCha8,
+/// whose output is stable across releases of `rand`, unlike the one of `StdRng`: a seed keeps its
+/// outcomes after an upgrade.
+#[derive(Debug, Clone)]
+pub struct HealthOutcomes {
+    weights: WeightedIndex<u32>,
+    seed: u64,
+    rng: Arc<Mutex<ChaCha8Rng>>,
+}
+
+impl HealthOutcomes {
+    pub fn new(config: OutcomesConfig) -> Self {
+        let seed = config.seed.unwrap_or_else(rand::random);
+        HealthOutcomes {
+            weights: config.weights,
+            seed,
+            rng: Arc::new(Mutex::new(ChaCha8Rng::seed_from_u64(seed))),
+        }
+    }
+
+    /// Seed of the generator of the process, to be given to `HEALTH_SEED` to replay a run.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Draws the next outcome of the process, or the outcome of `request_seed` alone when given.
+    pub fn draw(&self, request_seed: Option<u64>) -> Outcome {
+        let index = match request_seed {
+            Some(seed) => ChaCha8Rng::seed_from_u64(seed).sample(&self.weights),
+            None => self.rng.lock().unwrap().sample(&self.weights),
+        };
+        Outcome::ALL[index]
+    }
+}
+
+/// Seed of the request, from its `x-health-seed` header.
+pub fn request_seed(headers: &HeaderMap) -> Result<Option<u64>, AppError> {
+    headers
+        .get(SEED_HEADER)
+        .map(|seed| {
+            seed.to_str()
+                .ok()
+                .and_then(|seed| seed.trim().parse().ok())
+                .ok_or_else(|| {
+                    AppError::BadRequest(format!("{SEED_HEADER} must be an unsigned integer"))
+                })
+        })
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn config_from(vars: &[(&str, &str)]) -> Result<OutcomesConfig, StartupError> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        OutcomesConfig::from_lookup(|name| vars.get(name).cloned())
+    }
+
+    #[test]
+    fn test_config() {
+        assert_eq!(config_from(&[]).unwrap(), OutcomesConfig::default());
+
+        let config = config_from(&[
+            ("HEALTH_OUTCOME_WEIGHTS", "403=1, 200=8"),
+            ("HEALTH_SEED", "42"),
+        ])
+        .unwrap();
+        assert_eq!(config.weights.weights().collect::<Vec<_>>(), [8, 0, 1]);
+        assert_eq!(config.seed, Some(42));
+
+        for (name, value) in [
+            ("HEALTH_OUTCOME_WEIGHTS", "500=1"),
+            ("HEALTH_OUTCOME_WEIGHTS", "200"),
+            ("HEALTH_OUTCOME_WEIGHTS", "200=-1"),
+            // Nothing left to draw.
+            ("HEALTH_OUTCOME_WEIGHTS", "200=0"),
+            ("HEALTH_SEED", "-1"),
+        ] {
+            let err = config_from(&[(name, value)]).unwrap_err();
+            assert!(err.to_string().contains(name), "{err}");
+        }
+    }
+
+    #[test]
+    fn test_draws_follow_the_seeds() {
+        let config = config_from(&[
+            ("HEALTH_OUTCOME_WEIGHTS", "200=1,401=1"),
+            ("HEALTH_SEED", "7"),
+        ])
+        .unwrap();
+        let draws = |outcomes: &HealthOutcomes| -> Vec<_> {
+            (0..100).map(|_| outcomes.draw(None)).collect()
+        };
+
+        // The same seed draws the same outcomes, never an outcome without weight.
+        let first = draws(&HealthOutcomes::new(config.clone()));
+        assert_eq!(first, draws(&HealthOutcomes::new(config.clone())));
+        assert!(first.contains(&Outcome::Ok) && first.contains(&Outcome::Unauthorized));
+        assert!(!first.contains(&Outcome::Forbidden));
+
+        // The seed of a request decides its outcome alone, whatever the seed of the process.
+        let other = HealthOutcomes::new(OutcomesConfig {
+            seed: Some(8),
+            ..config.clone()
+        });
+        let outcomes = HealthOutcomes::new(config);
+        for seed in 0..20 {
+            assert_eq!(outcomes.draw(Some(seed)), other.draw(Some(seed)));
+        }
+    }
+
+    #[test]
+    fn test_request_seed() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(request_seed(&headers).unwrap(), None);
+        headers.insert(SEED_HEADER, " 42".parse().unwrap());
+        assert_eq!(request_seed(&headers).unwrap(), Some(42));
+        headers.insert(SEED_HEADER, "forty-two".parse().unwrap());
+        assert!(matches!(
+            request_seed(&headers),
+            Err(AppError::BadRequest(_))
+        ));
+    }
+
+    #[test]
+    fn test_draws_are_stable() {
+        let outcomes = HealthOutcomes::new(OutcomesConfig {
+            seed: Some(42),
+            ..OutcomesConfig::default()
+        });
+        let draws: Vec<_> = (0..12).map(|_| outcomes.draw(None)).collect();
+        let seeded: Vec<_> = (0..12).map(|seed| outcomes.draw(Some(seed))).collect();
+
+        // Pinned: a seed logged by an earlier release must replay the same outcomes.
+        use Outcome::{Forbidden as F, Ok as O, Unauthorized as U};
+        assert_eq!(draws, [O, F, O, F, F, U, U, U, F, O, U, O]);
+        assert_eq!(seeded, [U, U, O, O, F, O, O, O, O, U, U, O]);
+    }
+}
@@ -0,0 +1,218 @@
+use crate::attribute::unsigned;
+use opentelemetry::global;
+use opentelemetry_http::HeaderInjector;
+use reqwest::{Client, Request, Response, Url};
+use tracing::{field, info_span, Instrument, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// HTTP client creating a client span for each request.
+///
+/// The context of that span is injected in the request headers (`traceparent`, `tracestate`)
+/// with the global propagator, so that the downstream service continues the same trace.
+#[derive(Debug, Clone, Default)]
+pub struct TracedHttpClient {
+    client: Client,
+}
+
+impl TracedHttpClient {
+    pub fn new(client: Client) -> Self {
+        TracedHttpClient { client }
+    }
+
+    pub async fn get(&self, url: Url) -> Result<Response, reqwest::Error> {
+        let request = self.client.get(url).build()?;
+        self.execute(request).await
+    }
+
+    pub async fn execute(&self, mut request: Request) -> Result<Response, reqwest::Error> {
+        let method = request.method().clone();
+        let url = request.url();
+        let span = info_span!(
+            "HTTP client request",
+            otel.name = %method,
+            otel.kind = "client",
+            otel.status_code = field::Empty,
+            http.request.method = %method,
+            url.full = %url,
+            server.address = url.host_str(),
+            server.port = url.port_or_known_default().map(unsigned),
+            http.response.status_code = field::Empty,
+            error.type = field::Empty,
+        );
+
+        let cx = span.context();
+        global::get_text_map_propagator(|propagator| {
+            propagator.inject_context(&cx, &mut HeaderInjector(request.headers_mut()))
+        });
+
+        async move {
+            let result = self.client.execute(request).await;
+
+            // Unlike server spans, client spans are in error for 4xx responses too.
+            let span = Span::current();
+            match &result {
+                Ok(response) => {
+                    let status = response.status();
+                    span.record("http.response.status_code", unsigned(status.as_u16()));
+                    if status.is_client_error() || status.is_server_error() {
+                        span.record("otel.status_code", "error");
+                        span.record("error.type", status.as_str());
+                    }
+                }
+                Err(err) => {
+                    span.record("otel.status_code", "error");
+                    span.record("error.type", error_type(err));
+                }
+            }
+
+            result
+        }
+        .instrument(span)
+        .await
+    }
+}
+
+fn error_type(err: &reqwest::Error) -> &'static str {
+    if err.is_timeout() {
+        "timeout"
+    } else if err.is_connect() {
+        "connect"
+    } else {
+        "request"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_trace::trace_http_request;
+    use crate::test_app::install_propagator;
+    use axum::http::HeaderMap;
+    use axum::routing::get;
+    use axum::{middleware, Router};
+    use opentelemetry::trace::{TraceContextExt, TracerProvider};
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use tokio::sync::mpsc;
+    use tracing::instrument::WithSubscriber;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[tokio::test]
+    async fn test_trace_context_is_propagated_downstream() {
+        install_propagator();
+
+        // Downstream service reporting the `traceparent` header it receives.
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let downstream = Router::new().route(
+            "/health",
+            get(move |headers: HeaderMap| async move {
+                let traceparent = headers
+                    .get("traceparent")
+                    .and_then(|value| value.to_str().ok())
+                    .map(str::to_owned);
+                tx.send(traceparent).unwrap();
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}/health", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move { axum::serve(listener, downstream).await });
+
+        let tracer_provider = SdkTracerProvider::builder().build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(tracer_provider.tracer("test")));
+
+        let trace_id = async {
+            let span = info_span!("upstream");
+            let trace_id = span.context().span().span_context().trace_id();
+            let response = TracedHttpClient::default()
+                .get(url)
+                .instrument(span)
+                .await
+                .unwrap();
+            assert!(response.status().is_success());
+            trace_id
+        }
+        .with_subscriber(subscriber)
+        .await;
+
+        let traceparent = rx
+            .recv()
+            .await
+            .unwrap()
+            .expect("missing traceparent header");
+        assert!(traceparent.starts_with(&format!("00-{trace_id}-")));
+    }
+
+    fn traceparent(headers: &HeaderMap) -> Option<String> {
+        headers
+            .get("traceparent")
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_owned)
+    }
+
+    async fn serve(router: Router) -> Url {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        url
+    }
+
+    #[tokio::test]
+    async fn test_trace_context_is_propagated_across_hops() {
+        install_propagator();
+        let tracer_provider = SdkTracerProvider::builder().build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(tracer_provider.tracer("test")));
+        // Installed for the thread, so that the servers spawned on the runtime of the test are
+        // traced too.
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        // Two services behind the tracing middleware: `/chain` calls `/health` of the second one.
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let back_tx = tx.clone();
+        let back = serve(
+            Router::new()
+                .route(
+                    "/health",
+                    get(move |headers: HeaderMap| async move {
+                        back_tx.send(("/health", traceparent(&headers))).unwrap();
+                    }),
+                )
+                .layer(middleware::from_fn(trace_http_request)),
+        )
+        .await;
+        let front = serve(
+            Router::new()
+                .route(
+                    "/chain",
+                    get(move |headers: HeaderMap| async move {
+                        tx.send(("/chain", traceparent(&headers))).unwrap();
+                        let url = back.join("/health").unwrap();
+                        TracedHttpClient::default().get(url).await.unwrap().status()
+                    }),
+                )
+                .layer(middleware::from_fn(trace_http_request)),
+        )
+        .await;
+
+        let span = info_span!("upstream");
+        let trace_id = span.context().span().span_context().trace_id();
+        let response = TracedHttpClient::default()
+            .get(front.join("/chain").unwrap())
+            .instrument(span)
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+
+        // Both hops carry the trace of the caller, each one from the client span calling it.
+        let mut parents = Vec::new();
+        for route in ["/chain", "/health"] {
+            let (received, traceparent) = rx.recv().await.unwrap();
+            assert_eq!(received, route);
+            let traceparent = traceparent.expect("missing traceparent header");
+            let (trace, parent) = traceparent[3..].split_once('-').unwrap();
+            assert_eq!(trace, trace_id.to_string(), "{route}");
+            parents.push(parent[..16].to_owned());
+        }
+        assert_ne!(parents[0], parents[1]);
+    }
+}
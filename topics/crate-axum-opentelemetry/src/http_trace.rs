@@ -0,0 +1,71 @@
+use crate::attribute::unsigned;
+use crate::error::ResponseError;
+use axum::extract::{MatchedPath, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use opentelemetry::global;
+use opentelemetry_http::HeaderExtractor;
+use tracing::{field, info_span, Instrument, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// Middleware creating the server span of each request.
+///
+/// The span continues the trace propagated by the caller in the request headers, if any.
+/// The route template is recorded when the span is created, so that samplers can use it.
+/// Following the HTTP semantic conventions, only 5xx responses set the span status to error.
+/// The `error.type` of an `AppError` is recorded whatever the status, otherwise the status code of
+/// 5xx responses.
+/// `request.id` is recorded by `crate::request_context::propagate_request_context`.
+pub async fn trace_http_request(
+    matched_path: Option<MatchedPath>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let method = request.method().clone();
+    let route = matched_path
+        .map(|path| path.as_str().to_owned())
+        .unwrap_or_default();
+
+    let span = info_span!(
+        "HTTP request",
+        otel.name = format!("{method} {route}").trim_end(),
+        otel.kind = "server",
+        otel.status_code = field::Empty,
+        otel.status_description = field::Empty,
+        http.request.method = %method,
+        http.route = route,
+        url.path = request.uri().path(),
+        http.response.status_code = field::Empty,
+        error.type = field::Empty,
+        request.id = field::Empty,
+    );
+
+    let parent_cx = global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(request.headers()))
+    });
+    // Fails only if the span is filtered out or no OpenTelemetry layer is installed: nothing is traced then.
+    let _ = span.set_parent(parent_cx);
+
+    async move {
+        let response = next.run(request).await;
+
+        let span = Span::current();
+        let status = response.status();
+        span.record("http.response.status_code", unsigned(status.as_u16()));
+        let error = response.extensions().get::<ResponseError>();
+        if let Some(error) = error {
+            span.record("error.type", error.error_type);
+        }
+        if status.is_server_error() {
+            match error {
+                Some(error) => span.record("otel.status_description", error.description.as_str()),
+                None => span.record("error.type", status.as_str()),
+            };
+            span.record("otel.status_code", "error");
+        }
+
+        response
+    }
+    .instrument(span)
+    .await
+}
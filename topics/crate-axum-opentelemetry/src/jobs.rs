@@ -0,0 +1,252 @@
+use crate::attribute::unsigned;
+use crate::error::{AppError, Problem};
+use crate::metrics::DURATION_BOUNDARIES;
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use axum::Json;
+use opentelemetry::metrics::{Histogram, Meter};
+use opentelemetry::trace::{SpanContext, TraceContextExt};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::{info, info_span, Instrument, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use utoipa::{IntoParams, ToSchema};
+
+/// Jobs waiting for the worker at most: beyond, enqueuing fails rather than growing the memory.
+pub const QUEUE_CAPACITY: usize = 100;
+const QUEUE_NAME: &str = "jobs";
+const DEFAULT_WORK_MS: u64 = 100;
+const MAX_WORK_MS: u64 = 10_000;
+
+/// Work done outside of the request which enqueued it.
+#[derive(Debug)]
+pub struct Job {
+    id: u64,
+    /// Simulated processing time.
+    work: Duration,
+    enqueued_at: Instant,
+    /// Span of the enqueuing request, linked from the span processing the job.
+    enqueued_by: SpanContext,
+}
+
+/// Sending half of the in-process job queue, held by the handlers.
+#[derive(Debug, Clone)]
+pub struct JobQueue {
+    sender: mpsc::Sender<Job>,
+    next_id: Arc<AtomicU64>,
+}
+
+/// Background task processing the jobs of a `JobQueue` one at a time, until every `JobQueue` is
+/// dropped and the remaining jobs are processed.
+pub struct JobWorker {
+    receiver: mpsc::Receiver<Job>,
+    wait_time: Histogram<f64>,
+}
+
+/// Creates a job queue holding `capacity` jobs at most, and the worker processing them.
+pub fn job_queue(capacity: usize, meter: &Meter) -> (JobQueue, JobWorker) {
+    let (sender, receiver) = mpsc::channel(capacity);
+    let queued = sender.downgrade();
+    meter
+        .u64_observable_gauge("jobs.queue.depth")
+        .with_description("Number of jobs waiting for the worker.")
+        .with_unit("{job}")
+        .with_callback(move |observer| {
+            // Once the queue is closed, there is nothing left to report.
+            if let Some(sender) = queued.upgrade() {
+                observer.observe((sender.max_capacity() - sender.capacity()) as u64, &[]);
+            }
+        })
+        .build();
+    let worker = JobWorker {
+        receiver,
+        wait_time: meter
+            .f64_histogram("jobs.queue.wait_time")
+            .with_description("Time spent by the jobs in the queue, until the worker takes them.")
+            .with_unit("s")
+            .with_boundaries(DURATION_BOUNDARIES.to_vec())
+            .build(),
+    };
+    let queue = JobQueue {
+        sender,
+        next_id: Arc::new(AtomicU64::new(1)),
+    };
+    (queue, worker)
+}
+
+impl JobQueue {
+    /// Enqueues a job linked to `enqueued_by`, and returns its ID.
+    fn enqueue(&self, work: Duration, enqueued_by: SpanContext) -> Result<u64, AppError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let job = Job {
+            id,
+            work,
+            enqueued_at: Instant::now(),
+            enqueued_by,
+        };
+        match self.sender.try_send(job) {
+            Ok(()) => Ok(id),
+            Err(TrySendError::Full(_)) => Err(AppError::QueueFull),
+            // The worker only stops once every queue is dropped, unless it panicked.
+            Err(TrySendError::Closed(_)) => Err(AppError::JobWorkerStopped),
+        }
+    }
+}
+
+impl JobWorker {
+    pub async fn run(mut self) {
+        while let Some(job) = self.receiver.recv().await {
+            self.process(job).await;
+        }
+        info!("Job queue closed, worker stopped");
+    }
+
+    /// Processes `job` in a trace of its own: a request does not wait for its jobs, so a job is
+    /// not part of its trace, but linked to the span which enqueued it.
+    async fn process(&self, job: Job) {
+        let wait_time = job.enqueued_at.elapsed().as_secs_f64();
+        self.wait_time.record(wait_time, &[]);
+
+        let span = info_span!(
+            parent: None,
+            "job",
+            otel.name = format!("process {QUEUE_NAME}"),
+            otel.kind = "consumer",
+            messaging.operation.type = "process",
+            messaging.destination.name = QUEUE_NAME,
+            messaging.message.id = %job.id,
+            job.queue_wait_s = wait_time,
+        );
+        // Added before the span starts, so that the sampler sees the link.
+        span.add_link(job.enqueued_by);
+
+        async {
+            tokio::time::sleep(job.work).await;
+            info!(job.id, "Job processed");
+        }
+        .instrument(span)
+        .await;
+    }
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct JobQuery {
+    /// Simulated processing time, in milliseconds: 100 by default, 10,000 at most.
+    work_ms: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct EnqueuedJob {
+    pub job_id: u64,
+}
+
+/// Enqueues a job simulating `work_ms` milliseconds of work (100 by default, 10,000 at most), and
+/// returns its ID with `202 Accepted` without waiting for it.
+#[utoipa::path(
+    post,
+    path = "/jobs",
+    tag = "demo",
+    params(JobQuery),
+    responses(
+        (status = 202, description = "The job is enqueued", body = EnqueuedJob),
+        (status = 400, description = "`work_ms` is beyond the maximum", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Beyond a rate limit", body = Problem, content_type = "application/problem+json",
+            headers(("Retry-After" = u64, description = "Seconds to wait before retrying"))),
+        (status = 503, description = "The job queue is full, or beyond the concurrency limit", body = Problem, content_type = "application/problem+json",
+            headers(("Retry-After" = u64, description = "Seconds to wait before retrying"))),
+        (status = 500, description = "The job worker stopped", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[tracing::instrument(name = "enqueue_job", skip_all, fields(job.id))]
+pub async fn enqueue_job_handler(
+    State(jobs): State<JobQueue>,
+    Query(query): Query<JobQuery>,
+) -> Result<(StatusCode, Json<EnqueuedJob>), AppError> {
+    let work_ms = query.work_ms.unwrap_or(DEFAULT_WORK_MS);
+    if work_ms > MAX_WORK_MS {
+        return Err(AppError::BadRequest(format!(
+            "work_ms must be at most {MAX_WORK_MS}"
+        )));
+    }
+    let span = Span::current();
+    let enqueued_by = span.context().span().span_context().clone();
+    let job_id = jobs.enqueue(Duration::from_millis(work_ms), enqueued_by)?;
+    span.record("job.id", unsigned(job_id));
+    info!(job.id = job_id, "Job enqueued");
+    Ok((StatusCode::ACCEPTED, Json(EnqueuedJob { job_id })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_app::{attribute, TestApp};
+    use axum::body::{to_bytes, Body};
+    use axum::http::Request;
+    use opentelemetry::trace::SpanId;
+    use opentelemetry::Value;
+
+    #[tokio::test]
+    async fn test_job_is_linked_to_the_enqueuing_request() {
+        let app = TestApp::new();
+        let enqueue = |query: &str| {
+            let request = Request::post(format!("/jobs?{query}"))
+                .body(Body::empty())
+                .unwrap();
+            app.request(request)
+        };
+
+        let response = enqueue("work_ms=10").await;
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let job: EnqueuedJob = serde_json::from_slice(&body).unwrap();
+        let response = enqueue("work_ms=60000").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // The worker of the app processes the job in the background.
+        let process = app.wait_for_span("process jobs").await;
+        // The span of the first request, whose `job.id` is recorded as an integer.
+        let job_id = Value::from(unsigned(job.job_id));
+        let enqueue = app
+            .spans()
+            .into_iter()
+            .find(|span| attribute(span, "job.id") == Some(job_id.clone()))
+            .unwrap();
+        // The job has a trace of its own...
+        assert_ne!(
+            process.span_context.trace_id(),
+            enqueue.span_context.trace_id()
+        );
+        assert_eq!(process.parent_span_id, SpanId::INVALID);
+        // ...linked to the span which enqueued it.
+        assert_eq!(process.links.len(), 1);
+        assert_eq!(process.links[0].span_context, enqueue.span_context);
+
+        assert_eq!(
+            attribute(&process, "messaging.message.id"),
+            Some(Value::from(job.job_id.to_string()))
+        );
+        assert!(matches!(
+            attribute(&process, "job.queue_wait_s"),
+            Some(Value::F64(wait)) if wait >= 0.0
+        ));
+    }
+
+    #[test]
+    fn test_full_queue_rejects_jobs() {
+        let (jobs, _worker) = job_queue(1, &opentelemetry::global::meter("test"));
+        assert_eq!(
+            jobs.enqueue(Duration::ZERO, SpanContext::empty_context())
+                .unwrap(),
+            1
+        );
+        let err = jobs
+            .enqueue(Duration::ZERO, SpanContext::empty_context())
+            .unwrap_err();
+        assert!(matches!(err, AppError::QueueFull));
+        assert_eq!(err.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
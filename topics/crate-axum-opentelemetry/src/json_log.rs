@@ -0,0 +1,302 @@
+use crate::request_context::BaggageRules;
+use opentelemetry::baggage::BaggageExt;
+use opentelemetry::trace::TraceContextExt;
+use opentelemetry::Key;
+use opentelemetry_sdk::resource::Resource;
+use opentelemetry_semantic_conventions::attribute::{SERVICE_NAME, SERVICE_VERSION};
+use serde_json::{Map, Value};
+use std::fmt;
+use std::io::Write;
+use std::sync::{Arc, OnceLock};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Dispatch, Event, Subscriber};
+use tracing_core::dispatcher::WeakDispatch;
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::time::{FormatTime, SystemTime};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// Layer writing each event as one JSON object per line:
+///
+/// ```json
+/// {"timestamp":"2026-10-19T08:02:26.447751Z","level":"INFO","target":"crate_axum_opentelemetry",
+///  "message":"Number is 1 returning Ok Response","fields":{},"service.name":"crate-axum-opentelemetry",
+///  "service.version":"0.1.2","trace_id":"4bf9...","span_id":"00f0...",
+///  "span":{"name":"health_handler"},"spans":[{"name":"HTTP request","http.route":"/health"},{"name":"health_handler"}]}
+/// ```
+///
+/// Timestamps are RFC 3339 in UTC. `trace_id` and `span_id` are those of the OpenTelemetry span the
+/// event belongs to, so that a log line can be linked to its trace. The W3C Baggage entries of that
+/// span allowed by `BaggageRules`, if any, are written in a `baggage` object.
+pub struct JsonLogLayer<W> {
+    make_writer: W,
+    service_name: Option<String>,
+    service_version: Option<String>,
+    baggage: Arc<BaggageRules>,
+    // Needed to look the OpenTelemetry context of a span up, see `on_register_dispatch`.
+    dispatch: OnceLock<WeakDispatch>,
+}
+
+impl<W> JsonLogLayer<W> {
+    /// Service name and version are taken from the resource shared with the exporters.
+    pub fn new(make_writer: W, resource: &Resource, baggage: Arc<BaggageRules>) -> Self {
+        let attribute = |key: &'static str| {
+            resource
+                .get(&Key::from_static_str(key))
+                .map(|value| value.to_string())
+        };
+        JsonLogLayer {
+            make_writer,
+            service_name: attribute(SERVICE_NAME),
+            service_version: attribute(SERVICE_VERSION),
+            baggage,
+            dispatch: OnceLock::new(),
+        }
+    }
+
+    // The current dispatcher cannot be used while an event is being dispatched (nested calls to
+    // `tracing::dispatcher::get_default` get no dispatcher), hence the one captured at registration.
+    fn otel_context<S>(
+        &self,
+        span: &tracing_subscriber::registry::SpanRef<'_, S>,
+    ) -> Option<opentelemetry::Context>
+    where
+        S: for<'a> LookupSpan<'a>,
+    {
+        let dispatch = self.dispatch.get()?.upgrade()?;
+        tracing_opentelemetry::get_otel_context(&span.id(), &dispatch)
+    }
+}
+
+// Fields of a span, serialized once when recorded.
+struct SpanFields(Map<String, Value>);
+
+impl<S, W> Layer<S> for JsonLogLayer<W>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + 'static,
+{
+    fn on_register_dispatch(&self, dispatch: &Dispatch) {
+        let _ = self.dispatch.set(dispatch.downgrade());
+    }
+
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else { return };
+        let mut fields = Map::new();
+        attrs.record(&mut JsonVisitor(&mut fields));
+        span.extensions_mut().insert(SpanFields(fields));
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else { return };
+        let mut extensions = span.extensions_mut();
+        if let Some(SpanFields(fields)) = extensions.get_mut::<SpanFields>() {
+            values.record(&mut JsonVisitor(fields));
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+
+        let mut timestamp = String::new();
+        let _ = SystemTime.format_time(&mut Writer::new(&mut timestamp));
+
+        let mut fields = Map::new();
+        event.record(&mut JsonVisitor(&mut fields));
+        let message = fields.remove("message").unwrap_or(Value::Null);
+
+        let mut record = Map::new();
+        record.insert("timestamp".into(), timestamp.into());
+        record.insert("level".into(), metadata.level().as_str().into());
+        record.insert("target".into(), metadata.target().into());
+        record.insert("message".into(), message);
+        record.insert("fields".into(), fields.into());
+        if let Some(service_name) = &self.service_name {
+            record.insert("service.name".into(), service_name.as_str().into());
+        }
+        if let Some(service_version) = &self.service_version {
+            record.insert("service.version".into(), service_version.as_str().into());
+        }
+
+        if let Some(span) = ctx.event_span(event) {
+            if let Some(cx) = self.otel_context(&span) {
+                let span_context = cx.span().span_context().clone();
+                if span_context.is_valid() {
+                    record.insert(
+                        "trace_id".into(),
+                        span_context.trace_id().to_string().into(),
+                    );
+                    record.insert("span_id".into(), span_context.span_id().to_string().into());
+                }
+                let baggage: Map<String, Value> = self
+                    .baggage
+                    .entries(cx.baggage())
+                    .map(|(key, value)| (key.to_owned(), value.as_str().into()))
+                    .collect();
+                if !baggage.is_empty() {
+                    record.insert("baggage".into(), baggage.into());
+                }
+            }
+
+            let spans: Vec<Value> = span
+                .scope()
+                .from_root()
+                .map(|span| {
+                    let mut object = Map::new();
+                    object.insert("name".into(), span.name().into());
+                    if let Some(SpanFields(fields)) = span.extensions().get::<SpanFields>() {
+                        object.extend(fields.clone());
+                    }
+                    Value::Object(object)
+                })
+                .collect();
+            if let Some(current) = spans.last() {
+                record.insert("span".into(), current.clone());
+            }
+            record.insert("spans".into(), spans.into());
+        }
+
+        let mut line = Value::Object(record).to_string();
+        line.push('\n');
+        let _ = self
+            .make_writer
+            .make_writer_for(metadata)
+            .write_all(line.as_bytes());
+    }
+}
+
+struct JsonVisitor<'a>(&'a mut Map<String, Value>);
+
+impl Visit for JsonVisitor<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().into(), format!("{value:?}").into());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry::KeyValue;
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use std::sync::{Arc, Mutex};
+    use tracing::{info, info_span};
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> MakeWriter<'a> for Buffer {
+        type Writer = Buffer;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    #[test]
+    fn test_one_json_object_per_event() {
+        let buffer = Buffer::default();
+        let resource = Resource::builder_empty()
+            .with_service_name("checkout")
+            .with_attribute(KeyValue::new(SERVICE_VERSION, "1.2.3"))
+            .build();
+        let tracer_provider = SdkTracerProvider::builder().build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(tracer_provider.tracer("test")))
+            .with(JsonLogLayer::new(
+                buffer.clone(),
+                &resource,
+                Arc::new(BaggageRules::default()),
+            ));
+
+        let (trace_id, span_id) = tracing::subscriber::with_default(subscriber, || {
+            let span = info_span!("HTTP request", http.route = "/health");
+            let _guard = span.enter();
+            info!(attempt = 2, "Checking health");
+            let span_context = span.context().span().span_context().clone();
+            (span_context.trace_id(), span_context.span_id())
+        });
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 1);
+
+        let record: Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(record["level"], "INFO");
+        assert_eq!(record["message"], "Checking health");
+        assert_eq!(record["fields"]["attempt"], 2);
+        assert_eq!(record["service.name"], "checkout");
+        assert_eq!(record["service.version"], "1.2.3");
+        assert_eq!(record["trace_id"], trace_id.to_string());
+        assert_eq!(record["span_id"], span_id.to_string());
+        assert_eq!(record["span"]["name"], "HTTP request");
+        assert_eq!(record["span"]["http.route"], "/health");
+        assert!(record["timestamp"].as_str().unwrap().ends_with('Z'));
+    }
+
+    #[test]
+    fn test_baggage_of_the_span() {
+        let buffer = Buffer::default();
+        let tracer_provider = SdkTracerProvider::builder().build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(tracer_provider.tracer("test")))
+            .with(JsonLogLayer::new(
+                buffer.clone(),
+                &Resource::builder_empty().build(),
+                Arc::new(BaggageRules::default()),
+            ));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let span = info_span!("HTTP request");
+            // As extracted from the `baggage` header of the request.
+            let _ = span.set_parent(opentelemetry::Context::current_with_baggage([
+                KeyValue::new("tenant", "acme"),
+                KeyValue::new("session", "abc"),
+            ]));
+            let _guard = span.enter();
+            info!("Checking health");
+        });
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let record: Value = serde_json::from_str(output.lines().next().unwrap()).unwrap();
+        assert_eq!(record["baggage"]["tenant"], "acme");
+        // Not in `BAGGAGE_KEYS`.
+        assert_eq!(record["baggage"].get("session"), None);
+    }
+}
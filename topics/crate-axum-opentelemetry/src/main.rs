@@ -1,53 +1,156 @@
+mod admin;
+mod app;
+mod attribute;
+mod error;
+mod exemplars;
+mod health;
+mod health_outcomes;
+mod http_client;
+mod http_trace;
+mod jobs;
+mod json_log;
+mod metrics;
+#[cfg(test)]
+mod mock_collector;
 mod open_telemetry;
+mod openapi;
+mod otlp_exporter;
+#[cfg(target_os = "linux")]
+mod profiling;
+mod rate_limit;
+mod redaction;
+mod request_context;
+mod resource;
+mod runtime_metrics;
+mod sampling;
+mod settings;
+mod shutdown;
+mod state;
+mod tail_sampling;
+mod telemetry_config;
+#[cfg(test)]
+mod test_app;
 
-use crate::open_telemetry::init_tracing_subscriber;
-use axum::http::StatusCode;
-use axum::routing::get;
-use axum::Router;
-// use axum_tracing_opentelemetry::middleware::OtelAxumLayer;
-// use axum_tracing_opentelemetry::middleware::OtelInResponseLayer;
-use opentelemetry::trace::TraceError;
-use rand::Rng;
-use tracing::{error, event, info, warn, Level};
-
-#[tracing::instrument(name = "health_handler", level = "info")]
-async fn health_handler() -> StatusCode {
-    let number = rand::rng().random_range(1..4);
-    match number {
-        1 => {
-            info!("Number is 1 returning Ok Response");
-            StatusCode::OK
-        }
-        2 => {
-            error!("Number is 2 returning Error Response");
-            StatusCode::UNAUTHORIZED
-        }
-        3 => {
-            warn!("Number is 3 returning Forbidden");
-            StatusCode::FORBIDDEN
-        }
-        _ => {
-            event!(Level::INFO, "reason" = "Unknown number detected");
-            StatusCode::BAD_REQUEST
+use crate::error::StartupError;
+use crate::health::TcpHealthCheck;
+use crate::health_outcomes::OutcomesConfig;
+use crate::open_telemetry::{
+    init_tracing_subscriber, propagator, OtelProviders, INSTRUMENTATION_SCOPE,
+};
+use crate::rate_limit::LimitsConfig;
+use crate::settings::{Cli, ServiceConfig, Settings};
+use crate::shutdown::shutdown_signal;
+use crate::telemetry_config::TelemetryConfig;
+use clap::Parser;
+use std::net::SocketAddr;
+use std::process::ExitCode;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+// Maximum time given to the job worker to process the queued jobs, once the server stopped.
+const JOB_DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Maximum time given to each telemetry provider to export what it still holds.
+const TELEMETRY_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    match run().await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            // Printed rather than logged: the subscriber may not be installed, or be shut down.
+            eprintln!("Error: {err}");
+            ExitCode::FAILURE
         }
     }
 }
 
-#[tokio::main]
-#[tracing::instrument]
-async fn main() -> Result<(), TraceError> {
-    let _guard = init_tracing_subscriber();
-
-    let router = Router::new().route("/health", get(health_handler));
-    // .layer(OtelInResponseLayer::default())
-    // .layer(OtelAxumLayer::default());
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
+async fn run() -> Result<(), StartupError> {
+    let cli = Cli::parse();
+    let settings = Settings::load(&cli)?;
+    // Every setting is validated before anything starts, `--print-config` included.
+    let config = settings.read(|lookup| TelemetryConfig::from_lookup(lookup))?;
+    let limits = settings.read(|lookup| LimitsConfig::from_lookup(lookup))?;
+    let service = settings.read(|lookup| ServiceConfig::from_lookup(lookup))?;
+    let outcomes = settings.read(|lookup| OutcomesConfig::from_lookup(lookup))?;
+    if cli.print_config {
+        print!("{}", settings.render());
+        return Ok(());
+    }
+
+    let otel_providers = init_tracing_subscriber(&config)?;
+    // The only global telemetry state besides the subscriber: neither has a scoped equivalent.
+    opentelemetry::global::set_meter_provider(otel_providers.meter_provider().clone());
+    opentelemetry::global::set_text_map_propagator(propagator());
+    runtime_metrics::register_runtime_metrics(
+        &opentelemetry::global::meter(INSTRUMENTATION_SCOPE),
+        tokio::runtime::Handle::current().metrics(),
+    );
+
+    let result = serve(service, &config, limits, outcomes, &otel_providers).await;
+    if let Err(err) = &result {
+        error!(error = %err, "Application is dying...");
+    }
+
+    // The telemetry is flushed whatever the outcome, so that the error above is exported too.
+    info!("Flushing telemetry...");
+    let shutdown =
+        tokio::task::spawn_blocking(move || otel_providers.shutdown(TELEMETRY_SHUTDOWN_TIMEOUT))
+            .await;
+
+    // The error of the service is the one worth reporting, if both fail.
+    result?;
+    Ok(shutdown??)
+}
+
+async fn serve(
+    service: ServiceConfig,
+    config: &TelemetryConfig,
+    limits: LimitsConfig,
+    outcomes: OutcomesConfig,
+    otel_providers: &OtelProviders,
+) -> Result<(), StartupError> {
+    let (mut state, limiter, job_worker) = state::build(
+        &service,
+        config,
+        limits,
+        outcomes,
+        &opentelemetry::global::meter(INSTRUMENTATION_SCOPE),
+        otel_providers.exemplars(),
+    )?;
+    // Only checked when served: the tests have no collector.
+    if let Some(check) = TcpHealthCheck::from_url("otlp_exporter", &config.otlp_endpoint_url()) {
+        state.health = state.health.with_check(check);
+    }
+    let health = state.health.clone();
+    let job_worker = tokio::spawn(job_worker.run());
+
+    let router = app::router(state, &service, limiter, otel_providers.log_filter());
+    let listener = tokio::net::TcpListener::bind(service.bind_address)
+        .await
+        .map_err(|source| StartupError::Bind {
+            address: service.bind_address,
+            source,
+        })?;
     info!("App is running...");
-    let result = axum::serve(listener, router.into_make_service()).await;
+    // The address of the client identifies it for the rate limits.
+    let served = axum::serve(
+        listener,
+        router.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(async move {
+        shutdown_signal().await;
+        health.start_shutdown();
+        tokio::time::sleep(service.drain_delay).await;
+    })
+    .await
+    .map_err(StartupError::Serve);
 
-    if let Err(_) = result {
-        error!("Application is dying...");
-        // tracer_provider.shutdown().unwrap();
+    // The router is dropped with the job queue: the worker stops once the queued jobs are processed.
+    match tokio::time::timeout(JOB_DRAIN_TIMEOUT, job_worker).await {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => error!(error = %err, "The job worker failed"),
+        Err(_) => warn!("Queued jobs were not processed before the shutdown"),
     }
-    Ok(())
+    served
 }
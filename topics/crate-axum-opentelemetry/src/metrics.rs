@@ -0,0 +1,148 @@
+use crate::attribute::unsigned;
+use crate::exemplars::Exemplars;
+use axum::extract::{MatchedPath, Request, State};
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::Response;
+use opentelemetry::metrics::{Counter, Histogram, Meter, UpDownCounter};
+use opentelemetry::trace::TraceContextExt;
+use opentelemetry::KeyValue;
+use opentelemetry_semantic_conventions::{
+    attribute::{HTTP_REQUEST_METHOD, HTTP_RESPONSE_STATUS_CODE, HTTP_ROUTE},
+    metric::{HTTP_SERVER_ACTIVE_REQUESTS, HTTP_SERVER_REQUEST_DURATION},
+};
+use std::time::Instant;
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+// Bucket boundaries (in seconds) recommended by the semantic conventions for `http.server.request.duration`.
+pub const DURATION_BOUNDARIES: [f64; 14] = [
+    0.005, 0.01, 0.025, 0.05, 0.075, 0.1, 0.25, 0.5, 0.75, 1.0, 2.5, 5.0, 7.5, 10.0,
+];
+
+/// Instruments recorded by the service: RED metrics for every HTTP request
+/// plus a business counter for the outcomes of `health_handler`.
+///
+/// Request durations also record exemplars, linking the buckets of the histogram to sampled traces.
+#[derive(Clone)]
+pub struct AppMetrics {
+    request_count: Counter<u64>,
+    request_duration: Histogram<f64>,
+    active_requests: UpDownCounter<i64>,
+    health_outcomes: Counter<u64>,
+    exemplars: Exemplars,
+}
+
+impl AppMetrics {
+    pub fn new(meter: &Meter, exemplars: Exemplars) -> Self {
+        AppMetrics {
+            request_count: meter
+                .u64_counter("http.server.request.count")
+                .with_description("Number of HTTP requests received by the server.")
+                .with_unit("{request}")
+                .build(),
+            request_duration: meter
+                .f64_histogram(HTTP_SERVER_REQUEST_DURATION)
+                .with_description("Duration of HTTP server requests.")
+                .with_unit("s")
+                .with_boundaries(DURATION_BOUNDARIES.to_vec())
+                .build(),
+            active_requests: meter
+                .i64_up_down_counter(HTTP_SERVER_ACTIVE_REQUESTS)
+                .with_description("Number of active HTTP server requests.")
+                .with_unit("{request}")
+                .build(),
+            health_outcomes: meter
+                .u64_counter("health_handler.outcomes")
+                .with_description(
+                    "Number of responses returned by the health handler, by status code.",
+                )
+                .with_unit("{response}")
+                .build(),
+            exemplars,
+        }
+    }
+
+    pub fn record_health_outcome(&self, status: StatusCode) {
+        self.health_outcomes.add(
+            1,
+            &[KeyValue::new(
+                HTTP_RESPONSE_STATUS_CODE,
+                unsigned(status.as_u16()),
+            )],
+        );
+    }
+}
+
+// Counts a request in `http.server.active_requests` until dropped: also when the request is
+// cancelled, e.g. when the client disconnects before the response.
+struct ActiveRequest {
+    active_requests: UpDownCounter<i64>,
+    attributes: [KeyValue; 2],
+}
+
+impl ActiveRequest {
+    fn start(active_requests: UpDownCounter<i64>, attributes: [KeyValue; 2]) -> Self {
+        active_requests.add(1, &attributes);
+        ActiveRequest {
+            active_requests,
+            attributes,
+        }
+    }
+}
+
+impl Drop for ActiveRequest {
+    fn drop(&mut self) {
+        self.active_requests.add(-1, &self.attributes);
+    }
+}
+
+/// Middleware recording request count, duration and in-flight requests.
+///
+/// The route template (e.g. `/health`) is used rather than the raw URI to keep the cardinality bounded.
+pub async fn track_http_metrics(
+    State(metrics): State<AppMetrics>,
+    matched_path: Option<MatchedPath>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let method = request.method().to_string();
+    let route = matched_path
+        .map(|path| path.as_str().to_owned())
+        .unwrap_or_default();
+
+    let active_request = ActiveRequest::start(
+        metrics.active_requests.clone(),
+        [
+            KeyValue::new(HTTP_REQUEST_METHOD, method.clone()),
+            KeyValue::new(HTTP_ROUTE, route.clone()),
+        ],
+    );
+
+    let start = Instant::now();
+    let response = next.run(request).await;
+    let elapsed = start.elapsed().as_secs_f64();
+
+    drop(active_request);
+
+    let attributes = [
+        KeyValue::new(HTTP_REQUEST_METHOD, method),
+        KeyValue::new(HTTP_ROUTE, route),
+        KeyValue::new(
+            HTTP_RESPONSE_STATUS_CODE,
+            unsigned(response.status().as_u16()),
+        ),
+    ];
+    metrics.request_count.add(1, &attributes);
+    metrics.request_duration.record(elapsed, &attributes);
+    // The middleware runs within the server span of `trace_http_request`.
+    metrics.exemplars.record(
+        HTTP_SERVER_REQUEST_DURATION,
+        &DURATION_BOUNDARIES,
+        elapsed,
+        &attributes,
+        Span::current().context().span().span_context(),
+    );
+
+    response
+}
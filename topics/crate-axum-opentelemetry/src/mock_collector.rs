@@ -0,0 +1,535 @@
+//! OTLP receiver for tests, accepting traces, metrics and logs over gRPC and HTTP
+//! (protobuf or JSON, optionally gzipped), so that exporters can be checked without a collector.
+//! It can be served over TLS, with a certificate issued by a test certificate authority.
+
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::Router;
+use flate2::read::GzDecoder;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use hyper_util::service::TowerToHyperService;
+use opentelemetry_proto::tonic::collector::logs::v1::logs_service_server::{
+    LogsService, LogsServiceServer,
+};
+use opentelemetry_proto::tonic::collector::logs::v1::{
+    ExportLogsServiceRequest, ExportLogsServiceResponse,
+};
+use opentelemetry_proto::tonic::collector::metrics::v1::metrics_service_server::{
+    MetricsService, MetricsServiceServer,
+};
+use opentelemetry_proto::tonic::collector::metrics::v1::{
+    ExportMetricsServiceRequest, ExportMetricsServiceResponse,
+};
+use opentelemetry_proto::tonic::collector::trace::v1::trace_service_server::{
+    TraceService, TraceServiceServer,
+};
+use opentelemetry_proto::tonic::collector::trace::v1::{
+    ExportTraceServiceRequest, ExportTraceServiceResponse,
+};
+use opentelemetry_proto::tonic::logs::v1::LogRecord;
+use opentelemetry_proto::tonic::metrics::v1::Metric;
+use opentelemetry_proto::tonic::trace::v1::Span;
+use prost::Message;
+use rcgen::{BasicConstraints, CertificateParams, CertifiedIssuer, IsCa, KeyPair};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::Notify;
+use tokio::task::JoinSet;
+use tokio_rustls::rustls::crypto::aws_lc_rs;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+use tonic::codec::CompressionEncoding;
+use tonic::transport::server::TcpIncoming;
+use tonic::transport::{Identity, ServerTlsConfig};
+
+/// Where the received requests are kept, besides memory.
+pub enum Storage {
+    Memory,
+    /// Also appends each request to the file as one JSON object per line:
+    /// `{"signal":"traces","request":{...}}`, in the OTLP/JSON encoding.
+    JsonFile(PathBuf),
+}
+
+/// Export request received by the collector, with the headers (or gRPC metadata) it came with.
+#[derive(Debug, Clone)]
+pub struct Export {
+    pub headers: HeaderMap,
+    pub request: ExportRequest,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "signal", content = "request", rename_all = "snake_case")]
+pub enum ExportRequest {
+    Traces(ExportTraceServiceRequest),
+    Metrics(ExportMetricsServiceRequest),
+    Logs(ExportLogsServiceRequest),
+}
+
+#[derive(Default)]
+struct Store {
+    exports: Mutex<Vec<Export>>,
+    file: Option<Mutex<File>>,
+    received: Notify,
+}
+
+impl Store {
+    fn push(&self, headers: HeaderMap, request: ExportRequest) {
+        if let Some(file) = &self.file {
+            let mut line = serde_json::to_string(&request).unwrap();
+            line.push('\n');
+            file.lock().unwrap().write_all(line.as_bytes()).unwrap();
+        }
+        self.exports
+            .lock()
+            .unwrap()
+            .push(Export { headers, request });
+        self.received.notify_waiters();
+    }
+}
+
+/// Certificate of `localhost` for a collector served over TLS, with the certificate authority
+/// issuing it.
+pub struct TestCertificates {
+    /// Certificate authority, for `OTEL_EXPORTER_OTLP_CERTIFICATE`.
+    pub ca_pem: String,
+    cert_pem: String,
+    key_pem: String,
+}
+
+impl TestCertificates {
+    pub fn generate() -> Self {
+        let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = CertifiedIssuer::self_signed(ca_params, KeyPair::generate().unwrap()).unwrap();
+        let key = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(vec!["localhost".to_owned()])
+            .unwrap()
+            .signed_by(&key, &ca)
+            .unwrap();
+        TestCertificates {
+            ca_pem: ca.pem(),
+            cert_pem: cert.pem(),
+            key_pem: key.serialize_pem(),
+        }
+    }
+}
+
+/// Collector listening on two local ports, one for OTLP/gRPC and one for OTLP/HTTP.
+/// It stops when dropped.
+pub struct MockCollector {
+    grpc_endpoint: String,
+    http_endpoint: String,
+    store: Arc<Store>,
+    _servers: JoinSet<()>,
+}
+
+impl MockCollector {
+    pub async fn start(storage: Storage) -> Self {
+        Self::start_with(storage, None).await
+    }
+
+    /// Collector served over TLS as `localhost`, with the certificate of `certificates`.
+    pub async fn start_tls(storage: Storage, certificates: &TestCertificates) -> Self {
+        Self::start_with(storage, Some(certificates)).await
+    }
+
+    async fn start_with(storage: Storage, certificates: Option<&TestCertificates>) -> Self {
+        let file = match storage {
+            Storage::Memory => None,
+            Storage::JsonFile(path) => Some(Mutex::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .unwrap(),
+            )),
+        };
+        let store = Arc::new(Store {
+            file,
+            ..Store::default()
+        });
+        let mut servers = JoinSet::new();
+
+        // The certificate names `localhost`, rather than the address the servers listen on.
+        let endpoint = |address: std::net::SocketAddr| match certificates {
+            Some(_) => format!("https://localhost:{}", address.port()),
+            None => format!("http://{address}"),
+        };
+
+        let incoming = TcpIncoming::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let grpc_endpoint = endpoint(incoming.local_addr().unwrap());
+        let mut grpc = tonic::transport::Server::builder();
+        if let Some(certificates) = certificates {
+            let identity = Identity::from_pem(&certificates.cert_pem, &certificates.key_pem);
+            grpc = grpc
+                .tls_config(ServerTlsConfig::new().identity(identity))
+                .unwrap();
+        }
+        let grpc = grpc
+            .add_service(
+                TraceServiceServer::new(GrpcReceiver(store.clone()))
+                    .accept_compressed(CompressionEncoding::Gzip),
+            )
+            .add_service(
+                MetricsServiceServer::new(GrpcReceiver(store.clone()))
+                    .accept_compressed(CompressionEncoding::Gzip),
+            )
+            .add_service(
+                LogsServiceServer::new(GrpcReceiver(store.clone()))
+                    .accept_compressed(CompressionEncoding::Gzip),
+            )
+            .serve_with_incoming(incoming);
+        servers.spawn(async move { grpc.await.unwrap() });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let http_endpoint = endpoint(listener.local_addr().unwrap());
+        let router = Router::new()
+            .route("/v1/traces", post(receive_traces))
+            .route("/v1/metrics", post(receive_metrics))
+            .route("/v1/logs", post(receive_logs))
+            .with_state(store.clone());
+        match certificates {
+            Some(certificates) => {
+                servers.spawn(serve_tls(listener, router, tls_acceptor(certificates)))
+            }
+            None => servers.spawn(async move { axum::serve(listener, router).await.unwrap() }),
+        };
+
+        MockCollector {
+            grpc_endpoint,
+            http_endpoint,
+            store,
+            _servers: servers,
+        }
+    }
+
+    /// Endpoint for `OTEL_EXPORTER_OTLP_PROTOCOL=grpc`.
+    pub fn grpc_endpoint(&self) -> &str {
+        &self.grpc_endpoint
+    }
+
+    /// Endpoint for `OTEL_EXPORTER_OTLP_PROTOCOL=http/protobuf`, without the path of the signals.
+    pub fn http_endpoint(&self) -> &str {
+        &self.http_endpoint
+    }
+
+    /// Every request received so far, in order.
+    pub fn exports(&self) -> Vec<Export> {
+        self.store.exports.lock().unwrap().clone()
+    }
+
+    pub fn spans(&self) -> Vec<Span> {
+        self.flat_map(|request| match request {
+            ExportRequest::Traces(request) => request
+                .resource_spans
+                .into_iter()
+                .flat_map(|resource| resource.scope_spans)
+                .flat_map(|scope| scope.spans)
+                .collect(),
+            _ => Vec::new(),
+        })
+    }
+
+    pub fn metrics(&self) -> Vec<Metric> {
+        self.flat_map(|request| match request {
+            ExportRequest::Metrics(request) => request
+                .resource_metrics
+                .into_iter()
+                .flat_map(|resource| resource.scope_metrics)
+                .flat_map(|scope| scope.metrics)
+                .collect(),
+            _ => Vec::new(),
+        })
+    }
+
+    pub fn log_records(&self) -> Vec<LogRecord> {
+        self.flat_map(|request| match request {
+            ExportRequest::Logs(request) => request
+                .resource_logs
+                .into_iter()
+                .flat_map(|resource| resource.scope_logs)
+                .flat_map(|scope| scope.log_records)
+                .collect(),
+            _ => Vec::new(),
+        })
+    }
+
+    /// First span named `name`.
+    pub fn span(&self, name: &str) -> Option<Span> {
+        self.spans().into_iter().find(|span| span.name == name)
+    }
+
+    /// Last data points of the metric named `name`.
+    pub fn metric(&self, name: &str) -> Option<Metric> {
+        self.metrics()
+            .into_iter()
+            .rev()
+            .find(|metric| metric.name == name)
+    }
+
+    /// Waits until `condition` holds, or `timeout` elapses. Returns whether the condition holds.
+    pub async fn wait_until(&self, timeout: Duration, condition: impl Fn(&Self) -> bool) -> bool {
+        let wait = async {
+            loop {
+                let received = self.store.received.notified();
+                if condition(self) {
+                    return;
+                }
+                received.await;
+            }
+        };
+        tokio::time::timeout(timeout, wait).await.is_ok()
+    }
+
+    fn flat_map<T>(&self, f: impl Fn(ExportRequest) -> Vec<T>) -> Vec<T> {
+        self.store
+            .exports
+            .lock()
+            .unwrap()
+            .iter()
+            .flat_map(|export| f(export.request.clone()))
+            .collect()
+    }
+}
+
+fn tls_acceptor(certificates: &TestCertificates) -> TlsAcceptor {
+    let cert = CertificateDer::from_pem_slice(certificates.cert_pem.as_bytes()).unwrap();
+    let key = PrivateKeyDer::from_pem_slice(certificates.key_pem.as_bytes()).unwrap();
+    let config = ServerConfig::builder_with_provider(Arc::new(aws_lc_rs::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert], key)
+        .unwrap();
+    TlsAcceptor::from(Arc::new(config))
+}
+
+// `axum::serve` has no TLS support: connections are accepted here, then served by hyper.
+async fn serve_tls(listener: TcpListener, router: Router, acceptor: TlsAcceptor) {
+    let mut connections = JoinSet::new();
+    loop {
+        let Ok((stream, _)) = listener.accept().await else {
+            continue;
+        };
+        let (acceptor, router) = (acceptor.clone(), router.clone());
+        connections.spawn(async move {
+            // A failed handshake is the business of the client under test.
+            let Ok(stream) = acceptor.accept(stream).await else {
+                return;
+            };
+            let _ = auto::Builder::new(TokioExecutor::new())
+                .serve_connection(TokioIo::new(stream), TowerToHyperService::new(router))
+                .await;
+        });
+    }
+}
+
+struct GrpcReceiver(Arc<Store>);
+
+impl GrpcReceiver {
+    fn receive<T, R: Default>(
+        &self,
+        request: tonic::Request<T>,
+        wrap: fn(T) -> ExportRequest,
+    ) -> Result<tonic::Response<R>, tonic::Status> {
+        let (metadata, _, message) = request.into_parts();
+        self.0.push(metadata.into_headers(), wrap(message));
+        Ok(tonic::Response::new(R::default()))
+    }
+}
+
+#[tonic::async_trait]
+impl TraceService for GrpcReceiver {
+    async fn export(
+        &self,
+        request: tonic::Request<ExportTraceServiceRequest>,
+    ) -> Result<tonic::Response<ExportTraceServiceResponse>, tonic::Status> {
+        self.receive(request, ExportRequest::Traces)
+    }
+}
+
+#[tonic::async_trait]
+impl MetricsService for GrpcReceiver {
+    async fn export(
+        &self,
+        request: tonic::Request<ExportMetricsServiceRequest>,
+    ) -> Result<tonic::Response<ExportMetricsServiceResponse>, tonic::Status> {
+        self.receive(request, ExportRequest::Metrics)
+    }
+}
+
+#[tonic::async_trait]
+impl LogsService for GrpcReceiver {
+    async fn export(
+        &self,
+        request: tonic::Request<ExportLogsServiceRequest>,
+    ) -> Result<tonic::Response<ExportLogsServiceResponse>, tonic::Status> {
+        self.receive(request, ExportRequest::Logs)
+    }
+}
+
+async fn receive_traces(store: State<Arc<Store>>, headers: HeaderMap, body: Bytes) -> Response {
+    receive::<ExportTraceServiceRequest, ExportTraceServiceResponse>(
+        store,
+        headers,
+        body,
+        ExportRequest::Traces,
+    )
+}
+
+async fn receive_metrics(store: State<Arc<Store>>, headers: HeaderMap, body: Bytes) -> Response {
+    receive::<ExportMetricsServiceRequest, ExportMetricsServiceResponse>(
+        store,
+        headers,
+        body,
+        ExportRequest::Metrics,
+    )
+}
+
+async fn receive_logs(store: State<Arc<Store>>, headers: HeaderMap, body: Bytes) -> Response {
+    receive::<ExportLogsServiceRequest, ExportLogsServiceResponse>(
+        store,
+        headers,
+        body,
+        ExportRequest::Logs,
+    )
+}
+
+// The response uses the encoding of the request, as required by OTLP/HTTP.
+fn receive<T, R>(
+    State(store): State<Arc<Store>>,
+    headers: HeaderMap,
+    body: Bytes,
+    wrap: fn(T) -> ExportRequest,
+) -> Response
+where
+    T: Message + Default + DeserializeOwned,
+    R: Message + Default + Serialize,
+{
+    let body = match headers.get(header::CONTENT_ENCODING) {
+        Some(encoding) if encoding == "gzip" => {
+            let mut decoded = Vec::new();
+            if let Err(err) = GzDecoder::new(&body[..]).read_to_end(&mut decoded) {
+                return (StatusCode::BAD_REQUEST, err.to_string()).into_response();
+            }
+            Bytes::from(decoded)
+        }
+        _ => body,
+    };
+    let json = headers
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|content_type| content_type == "application/json");
+
+    let request = if json {
+        serde_json::from_slice::<T>(&body).map_err(|err| err.to_string())
+    } else {
+        T::decode(body).map_err(|err| err.to_string())
+    };
+    match request {
+        Ok(request) => {
+            store.push(headers, wrap(request));
+            if json {
+                axum::Json(R::default()).into_response()
+            } else {
+                (
+                    [(header::CONTENT_TYPE, "application/x-protobuf")],
+                    R::default().encode_to_vec(),
+                )
+                    .into_response()
+            }
+        }
+        Err(err) => (StatusCode::BAD_REQUEST, err).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry_proto::tonic::collector::trace::v1::trace_service_client::TraceServiceClient;
+    use opentelemetry_proto::tonic::logs::v1::{ResourceLogs, ScopeLogs};
+    use opentelemetry_proto::tonic::trace::v1::{ResourceSpans, ScopeSpans};
+
+    #[tokio::test]
+    async fn test_wait_until_received() {
+        let collector = MockCollector::start(Storage::Memory).await;
+        let endpoint = collector.grpc_endpoint().to_owned();
+        tokio::spawn(async move {
+            let request = ExportTraceServiceRequest {
+                resource_spans: vec![ResourceSpans {
+                    scope_spans: vec![ScopeSpans {
+                        spans: vec![Span {
+                            name: "GET /health".to_owned(),
+                            ..Span::default()
+                        }],
+                        ..ScopeSpans::default()
+                    }],
+                    ..ResourceSpans::default()
+                }],
+            };
+            let mut client = TraceServiceClient::connect(endpoint).await.unwrap();
+            client.export(request).await.unwrap();
+        });
+
+        let timeout = Duration::from_secs(5);
+        assert!(
+            collector
+                .wait_until(timeout, |collector| collector.span("GET /health").is_some())
+                .await
+        );
+        assert!(
+            !collector
+                .wait_until(Duration::from_millis(50), |collector| {
+                    collector.spans().len() > 1
+                })
+                .await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_json_file_storage() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let collector = MockCollector::start(Storage::JsonFile(file.path().to_owned())).await;
+
+        let request = ExportLogsServiceRequest {
+            resource_logs: vec![ResourceLogs {
+                scope_logs: vec![ScopeLogs {
+                    log_records: vec![LogRecord {
+                        severity_text: "INFO".to_owned(),
+                        ..LogRecord::default()
+                    }],
+                    ..ScopeLogs::default()
+                }],
+                ..ResourceLogs::default()
+            }],
+        };
+        let response = reqwest::Client::new()
+            .post(format!("{}/v1/logs", collector.http_endpoint()))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(&request).unwrap())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(collector.log_records()[0].severity_text, "INFO");
+
+        let contents = std::fs::read_to_string(file.path()).unwrap();
+        let line: serde_json::Value = serde_json::from_str(contents.trim()).unwrap();
+        assert_eq!(line["signal"], "logs");
+        assert_eq!(
+            line["request"]["resourceLogs"][0]["scopeLogs"][0]["logRecords"][0]["severityText"],
+            "INFO"
+        );
+    }
+}
@@ -1,60 +1,346 @@
-use opentelemetry::{trace::TracerProvider, KeyValue};
-use opentelemetry_otlp::WithExportConfig;
-use opentelemetry_sdk::{resource::Resource, trace::SdkTracerProvider};
-use opentelemetry_semantic_conventions::{
-    attribute::{DEPLOYMENT_ENVIRONMENT_NAME, SERVICE_VERSION},
-    SCHEMA_URL,
+use crate::exemplars::Exemplars;
+use crate::json_log::JsonLogLayer;
+use crate::otlp_exporter::{CertificateError, OtlpExporters};
+use crate::redaction::{RedactingLogProcessor, RedactingSpanProcessor};
+use crate::request_context::BaggageLogProcessor;
+use crate::resource::resource;
+use crate::sampling::{RuleBasedSampler, RuleBasedSpanProcessor};
+use crate::tail_sampling::TailSamplingSpanProcessor;
+use crate::telemetry_config::{ConfigError, LogFormat, TelemetryConfig, TracesSampler};
+use opentelemetry::propagation::TextMapCompositePropagator;
+use opentelemetry::{metrics::MeterProvider, trace::TracerProvider};
+use opentelemetry_appender_tracing::layer::OpenTelemetryTracingBridge;
+use opentelemetry_otlp::ExporterBuildError;
+use opentelemetry_sdk::{
+    error::OTelSdkError,
+    logs::{BatchLogProcessor, SdkLoggerProvider},
+    metrics::SdkMeterProvider,
+    propagation::{BaggagePropagator, TraceContextPropagator},
+    resource::Resource,
+    trace::{BatchSpanProcessor, Sampler, SdkTracerProvider},
 };
-use tracing_core::Level;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tracing::Dispatch;
 use tracing_opentelemetry::OpenTelemetryLayer;
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
-
-// Create a Resource that captures information about the entity for which telemetry is recorded.
-fn resource() -> Resource {
-    let ressouce = Resource::builder()
-        .with_schema_url(
-            [
-                // KeyValue::new(SERVICE_NAME, env!("CARGO_PKG_NAME")), doesn't work
-                KeyValue::new(SERVICE_VERSION, env!("CARGO_PKG_VERSION")),
-                KeyValue::new(DEPLOYMENT_ENVIRONMENT_NAME, "develop"),
-            ],
-            SCHEMA_URL,
-        )
-        .with_service_name(env!("CARGO_PKG_NAME"))
-        .build();
-
-    println!("Resource: {:?}", ressouce);
-
-    ressouce
-}
-
-fn init_tracer_provider() -> SdkTracerProvider {
-    let resource = resource();
-
-    let exporter = opentelemetry_otlp::SpanExporter::builder()
-        .with_tonic()
-        .with_endpoint("http://localhost:4317")
-        .build()
-        .unwrap();
-
-    SdkTracerProvider::builder()
-        .with_batch_exporter(exporter)
-        .with_resource(resource)
-        .build()
+use tracing_subscriber::{
+    filter::{LevelFilter, ParseError, Targets},
+    layer::SubscriberExt,
+    reload,
+    util::{SubscriberInitExt, TryInitError},
+    EnvFilter, Layer, Registry,
+};
+
+/// Name of the instrumentation scope of the spans and metrics of the service: the crate, whatever
+/// `OTEL_SERVICE_NAME` says, so that the scope stays the same across deployments.
+pub const INSTRUMENTATION_SCOPE: &str = env!("CARGO_PKG_NAME");
+
+/// Errors that prevent the telemetry pipelines from being installed.
+#[derive(Debug, Error)]
+pub enum TelemetryError {
+    #[error("Invalid telemetry configuration: {0}")]
+    Config(#[from] ConfigError),
+    #[error(transparent)]
+    Certificate(#[from] CertificateError),
+    #[error("Unable to build the OTLP exporter: {0}")]
+    Exporter(#[from] ExporterBuildError),
+    #[error("Unable to install the tracing subscriber: {0}")]
+    Subscriber(#[from] TryInitError),
+    #[error("Unable to shut down the {provider} provider: {source}")]
+    Shutdown {
+        provider: &'static str,
+        source: OTelSdkError,
+    },
+}
+
+/// Errors returned when the filter directives cannot be changed.
+#[derive(Debug, Error)]
+pub enum LogFilterError {
+    #[error("Invalid filter directives: {0}")]
+    Invalid(#[from] ParseError),
+    #[error("Unable to reload the filter: {0}")]
+    Reload(#[from] reload::Error),
 }
 
-pub fn init_tracing_subscriber() -> SdkTracerProvider {
-    let tracer_provider = init_tracer_provider();
+/// Handle to the `EnvFilter` of the subscriber, to change the filter directives at runtime.
+///
+/// The filter applies to every layer: the directives select the logs and the spans exported alike.
+#[derive(Clone)]
+pub struct LogFilterHandle(reload::Handle<EnvFilter, Registry>);
+
+impl LogFilterHandle {
+    /// Wraps `filter` in a layer whose filter can be replaced through the returned handle.
+    pub fn new(filter: EnvFilter) -> (reload::Layer<EnvFilter, Registry>, Self) {
+        let (layer, handle) = reload::Layer::new(filter);
+        (layer, LogFilterHandle(handle))
+    }
+
+    /// Current directives, in the `RUST_LOG` syntax.
+    pub fn directives(&self) -> Result<String, LogFilterError> {
+        Ok(self.0.with_current(|filter| filter.to_string())?)
+    }
+
+    /// Replaces the directives, e.g. `info,crate_axum_opentelemetry=debug`.
+    /// The current filter is kept when `directives` are invalid.
+    pub fn set_directives(&self, directives: &str) -> Result<(), LogFilterError> {
+        let filter = EnvFilter::try_new(directives)?;
+        Ok(self.0.reload(filter)?)
+    }
+}
+
+/// Providers created by `init_tracing_subscriber`, kept alive for the lifetime of the application.
+pub struct OtelProviders {
+    tracer_provider: SdkTracerProvider,
+    meter_provider: SdkMeterProvider,
+    logger_provider: SdkLoggerProvider,
+    log_filter: LogFilterHandle,
+    exemplars: Exemplars,
+    resource: Resource,
+}
 
-    let tracer = tracer_provider.tracer("tracing-otel-subscriber");
+impl OtelProviders {
+    /// Provider of the meters, to be installed as the global one.
+    pub fn meter_provider(&self) -> &SdkMeterProvider {
+        &self.meter_provider
+    }
 
-    tracing_subscriber::registry()
-        .with(tracing_subscriber::filter::LevelFilter::from_level(
-            Level::INFO,
+    pub fn log_filter(&self) -> LogFilterHandle {
+        self.log_filter.clone()
+    }
+
+    /// Exemplars attached to the histograms exported by the meter provider.
+    pub fn exemplars(&self) -> Exemplars {
+        self.exemplars.clone()
+    }
+
+    /// Flushes the pending spans, metrics and log records, then shuts the providers down.
+    ///
+    /// Each provider gets at most `timeout`. The logger provider goes last so that the records
+    /// emitted while the others shut down are still exported.
+    /// Blocking: call it from `spawn_blocking` in async code.
+    pub fn shutdown(self, timeout: Duration) -> Result<(), TelemetryError> {
+        let results = [
+            (
+                "tracer",
+                self.tracer_provider.shutdown_with_timeout(timeout),
+            ),
+            ("meter", self.meter_provider.shutdown_with_timeout(timeout)),
+            (
+                "logger",
+                self.logger_provider.shutdown_with_timeout(timeout),
+            ),
+        ];
+
+        // Every provider has been shut down at this point: report the first failure.
+        results.into_iter().try_for_each(|(provider, result)| {
+            result.map_err(|source| TelemetryError::Shutdown { provider, source })
+        })
+    }
+}
+
+fn init_tracer_provider(
+    config: &TelemetryConfig,
+    exporters: &OtlpExporters,
+    meter_provider: &SdkMeterProvider,
+    resource: Resource,
+) -> Result<SdkTracerProvider, ExporterBuildError> {
+    let exporter = exporters.span_exporter()?;
+    // Spans are redacted before the batch: unredacted values are never queued for export.
+    let processor = RedactingSpanProcessor::new(
+        BatchSpanProcessor::builder(exporter).build(),
+        Arc::new(config.redaction.clone()),
+    );
+
+    let builder = SdkTracerProvider::builder().with_resource(resource);
+    let builder = match &config.sampler {
+        TracesSampler::Sdk(sampler) => builder
+            .with_span_processor(processor)
+            .with_sampler(sampler.clone()),
+        // Errors and slow spans are only known when they end: the processor finishes the sampling.
+        TracesSampler::RuleBased(rules) => {
+            let rules = Arc::new(rules.clone());
+            builder
+                .with_span_processor(RuleBasedSpanProcessor::new(processor, rules.clone()))
+                .with_sampler(RuleBasedSampler::new(rules))
+        }
+        // Every span is recorded: the processor decides once the local trace is complete.
+        TracesSampler::TailBased(rules) => builder
+            .with_span_processor(TailSamplingSpanProcessor::new(
+                processor,
+                rules.clone(),
+                &meter_provider.meter(INSTRUMENTATION_SCOPE),
+            ))
+            .with_sampler(Sampler::AlwaysOn),
+    };
+
+    Ok(builder.build())
+}
+
+// Metrics are pushed to the same OTLP endpoint as traces, every 60 seconds by default, with the
+// exemplars recorded since the previous export.
+fn init_meter_provider(
+    exporters: &OtlpExporters,
+    resource: Resource,
+    exemplars: Exemplars,
+) -> Result<SdkMeterProvider, ExporterBuildError> {
+    let exporter = exporters.metric_exporter(exemplars)?;
+
+    Ok(SdkMeterProvider::builder()
+        .with_periodic_exporter(exporter)
+        .with_resource(resource)
+        .build())
+}
+
+// Log records are emitted from `tracing` events by the appender bridge.
+// The trace and span IDs of the active span are attached to each record.
+fn init_logger_provider(
+    config: &TelemetryConfig,
+    exporters: &OtlpExporters,
+    resource: Resource,
+) -> Result<SdkLoggerProvider, ExporterBuildError> {
+    let exporter = exporters.log_exporter()?;
+
+    Ok(SdkLoggerProvider::builder()
+        // Baggage entries are added first, so that they are redacted too.
+        .with_log_processor(BaggageLogProcessor::new(
+            RedactingLogProcessor::new(
+                BatchLogProcessor::builder(exporter).build(),
+                Arc::new(config.redaction.clone()),
+            ),
+            Arc::new(config.baggage.clone()),
         ))
-        .with(tracing_subscriber::fmt::layer())
+        .with_resource(resource)
+        .build())
+}
+
+/// W3C Trace Context (`traceparent`/`tracestate`) and W3C Baggage (`baggage`), used to propagate
+/// traces and request-scoped entries such as the tenant across services.
+pub fn propagator() -> TextMapCompositePropagator {
+    TextMapCompositePropagator::new(vec![
+        Box::new(TraceContextPropagator::new()),
+        Box::new(BaggagePropagator::new()),
+    ])
+}
+
+/// Installs the subscriber of `build_tracing_dispatch` as the global default.
+pub fn init_tracing_subscriber(config: &TelemetryConfig) -> Result<OtelProviders, TelemetryError> {
+    let (dispatch, providers) = build_tracing_dispatch(config)?;
+    dispatch.try_init()?;
+
+    // Logged rather than printed, so that JSON logs only contain JSON lines.
+    tracing::info!(resource = ?providers.resource, "Telemetry resource");
+
+    Ok(providers)
+}
+
+/// Builds the subscriber exporting spans and logs, and the OTLP providers behind it.
+/// Nothing is installed globally: the caller installs the meter provider and `propagator()`,
+/// which have no scoped equivalent.
+pub fn build_tracing_dispatch(
+    config: &TelemetryConfig,
+) -> Result<(Dispatch, OtelProviders), TelemetryError> {
+    let resource = resource(config);
+    let exporters = OtlpExporters::new(config)?;
+    let exemplars = Exemplars::default();
+    let meter_provider = init_meter_provider(&exporters, resource.clone(), exemplars.clone())?;
+    let tracer_provider =
+        init_tracer_provider(config, &exporters, &meter_provider, resource.clone())?;
+    let logger_provider = init_logger_provider(config, &exporters, resource.clone())?;
+
+    let tracer = tracer_provider.tracer(INSTRUMENTATION_SCOPE);
+
+    // The exporters' own transport stack logs through `tracing` too: exclude it from the bridge
+    // so that exporting a log record never produces another log record.
+    let log_bridge_filter = Targets::new()
+        .with_default(LevelFilter::TRACE)
+        .with_target("hyper", LevelFilter::OFF)
+        .with_target("h2", LevelFilter::OFF)
+        .with_target("tonic", LevelFilter::OFF)
+        .with_target("reqwest", LevelFilter::OFF)
+        .with_target("tower", LevelFilter::OFF)
+        .with_target("opentelemetry", LevelFilter::OFF);
+
+    // Boxed rather than optional: `Option<Layer>` does not forward `on_register_dispatch`,
+    // which `JsonLogLayer` relies on.
+    let log_layer = match config.log_format {
+        LogFormat::Text => tracing_subscriber::fmt::layer().boxed(),
+        LogFormat::Json => {
+            JsonLogLayer::new(std::io::stdout, &resource, Arc::new(config.baggage.clone())).boxed()
+        }
+    };
+
+    // Only the filter is reloadable: `tracing_opentelemetry` cannot find its layer behind a
+    // `reload::Layer`, which would break `set_parent` on the server spans.
+    let (env_filter, log_filter) = LogFilterHandle::new(config.env_filter());
+
+    let subscriber = tracing_subscriber::registry()
+        .with(env_filter)
+        .with(log_layer)
         .with(OpenTelemetryLayer::new(tracer))
-        .init();
+        .with(OpenTelemetryTracingBridge::new(&logger_provider).with_filter(log_bridge_filter));
+
+    let providers = OtelProviders {
+        tracer_provider,
+        meter_provider,
+        logger_provider,
+        log_filter,
+        exemplars,
+        resource,
+    };
+    Ok((Dispatch::new(subscriber), providers))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_collector::{MockCollector, Storage};
+    use opentelemetry::trace::TraceContextExt;
+    use std::collections::HashMap;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_signals_reach_the_collector() {
+        let collector = MockCollector::start(Storage::Memory).await;
+        let vars = HashMap::from([
+            ("OTEL_EXPORTER_OTLP_PROTOCOL", "http/protobuf"),
+            ("OTEL_EXPORTER_OTLP_ENDPOINT", collector.http_endpoint()),
+            ("OTEL_SERVICE_NAME", "checkout"),
+        ]);
+        let config =
+            TelemetryConfig::from_lookup(|name| vars.get(name).map(|value| value.to_string()))
+                .unwrap();
+        let (dispatch, providers) = build_tracing_dispatch(&config).unwrap();
+
+        let trace_id = tracing::dispatcher::with_default(&dispatch, || {
+            let span = tracing::info_span!("HTTP request");
+            let _guard = span.enter();
+            tracing::info!("Handling request");
+            span.context().span().span_context().trace_id()
+        });
+        providers
+            .meter_provider()
+            .meter("test")
+            .u64_counter("orders")
+            .build()
+            .add(1, &[]);
+        tokio::task::spawn_blocking(move || providers.shutdown(Duration::from_secs(5)))
+            .await
+            .unwrap()
+            .unwrap();
 
-    tracer_provider
+        let span = collector.span("HTTP request").unwrap();
+        assert_eq!(span.trace_id, trace_id.to_bytes());
+        let record = collector
+            .log_records()
+            .into_iter()
+            .find(|record| record.trace_id == trace_id.to_bytes())
+            .unwrap();
+        assert_eq!(record.severity_text, "INFO");
+        assert!(collector.metric("orders").is_some());
+        assert!(collector
+            .exports()
+            .iter()
+            .all(|export| { export.headers["content-type"] == "application/x-protobuf" }));
+    }
 }
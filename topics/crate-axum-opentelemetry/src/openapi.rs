@@ -0,0 +1,236 @@
+use crate::rate_limit::{limit_requests, Limiter};
+use crate::settings::Route;
+use crate::state::AppState;
+use crate::{app, health, jobs};
+use axum::routing::get;
+use axum::{middleware, Json, Router};
+use utoipa::OpenApi;
+use utoipa_axum::router::OpenApiRouter;
+use utoipa_axum::routes;
+
+pub const OPENAPI_PATH: &str = "/openapi.json";
+
+/// OpenAPI document of the service. Its paths are not listed here: `api_router` adds them as it
+/// registers the handlers, from their `#[utoipa::path]` annotations.
+#[derive(OpenApi)]
+#[openapi(tags(
+    (name = "demo", description = "Routes generating telemetry, which can be left out with `ROUTES`"),
+    (name = "probes", description = "Liveness and readiness probes, always served"),
+))]
+pub struct ApiDoc;
+
+/// Documented routes of the service: `routes`, limited by `limiter`, and the probes.
+///
+/// The admin routes are left out of the document, as an internal API.
+pub fn api_router(routes: &[Route], limiter: Limiter) -> OpenApiRouter<AppState> {
+    let mut router = OpenApiRouter::with_openapi(ApiDoc::openapi());
+    for route in routes {
+        router = match route {
+            Route::Health => router.routes(routes!(app::health_handler)),
+            Route::Chain => router.routes(routes!(app::chain_handler)),
+            Route::Jobs => router.routes(routes!(jobs::enqueue_job_handler)),
+        };
+    }
+    // The probes and the admin routes are not limited: an overloaded service stays alive.
+    if !routes.is_empty() {
+        router = router.route_layer(middleware::from_fn_with_state(limiter, limit_requests));
+    }
+    router
+        .routes(routes!(health::livez_handler))
+        .routes(routes!(health::readyz_handler))
+}
+
+/// Serves `openapi` at `/openapi.json`.
+pub fn openapi_router<S>(openapi: utoipa::openapi::OpenApi) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    Router::new().route(OPENAPI_PATH, get(move || async move { Json(openapi) }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_app::TestApp;
+    use axum::body::{to_bytes, Body};
+    use axum::extract::Path;
+    use axum::http::{header, Method, Request, StatusCode};
+    use std::collections::BTreeSet;
+    use tokio::net::TcpListener;
+    use utoipa::openapi::path::{Operation, PathItem};
+    use utoipa::openapi::response::Response as DocumentedResponse;
+    use utoipa::openapi::schema::Schema;
+    use utoipa::openapi::RefOr;
+
+    // Requests accepted by each route before it is rate limited.
+    const BURST: usize = 20;
+
+    fn operations(item: &PathItem) -> impl Iterator<Item = (Method, &Operation)> {
+        [
+            (Method::GET, &item.get),
+            (Method::POST, &item.post),
+            (Method::PUT, &item.put),
+            (Method::PATCH, &item.patch),
+            (Method::DELETE, &item.delete),
+        ]
+        .into_iter()
+        .filter_map(|(method, operation)| Some((method, operation.as_ref()?)))
+    }
+
+    // Response documented for `status`: its own, else the one of its range (`5XX`), else the default.
+    fn documented(operation: &Operation, status: StatusCode) -> Option<&DocumentedResponse> {
+        let range = format!("{}XX", status.as_u16() / 100);
+        let responses = &operation.responses.responses;
+        let response = [status.as_str(), &range, "default"]
+            .into_iter()
+            .find_map(|key| responses.get(key))?;
+        match response {
+            RefOr::T(response) => Some(response),
+            RefOr::Ref(_) => None,
+        }
+    }
+
+    /// The service, served by `TestApp`, with the document it publishes.
+    async fn app_and_document(settings: &[(&str, &str)]) -> (TestApp, utoipa::openapi::OpenApi) {
+        let app = TestApp::with_settings(settings);
+        let response = app.get(OPENAPI_PATH).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let published: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(published["openapi"], "3.1.0");
+        (app, serde_json::from_value(published).unwrap())
+    }
+
+    /// Sends `method path` to `app`, and checks that the response is documented by `operation`:
+    /// its status, its content type, and the required properties of its body.
+    async fn check_response(
+        app: &TestApp,
+        document: &utoipa::openapi::OpenApi,
+        method: &Method,
+        path: &str,
+        operation: &Operation,
+    ) -> StatusCode {
+        let request = Request::builder()
+            .method(method.clone())
+            .uri(path)
+            .body(Body::empty())
+            .unwrap();
+        let response = app.request(request).await;
+        let status = response.status();
+        let Some(documented) = documented(operation, status) else {
+            panic!("{method} {path} answered {status}, which is not documented");
+        };
+        let Some(content_type) = response.headers().get(header::CONTENT_TYPE) else {
+            return status;
+        };
+        let content_type = content_type.to_str().unwrap().to_owned();
+        let Some(content) = documented.content.get(&content_type) else {
+            panic!("{method} {path} answered {status} with undocumented {content_type}");
+        };
+        let schema = match &content.schema {
+            Some(RefOr::Ref(reference)) => {
+                let name = reference.ref_location.rsplit('/').next().unwrap();
+                document.components.as_ref().unwrap().schemas.get(name)
+            }
+            schema => schema.as_ref(),
+        };
+        if let Some(RefOr::T(Schema::Object(schema))) = schema {
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            for property in &schema.required {
+                assert!(
+                    body.get(property).is_some(),
+                    "{method} {path} answered {status} without {property:?}: {body}"
+                );
+            }
+        }
+        status
+    }
+
+    #[tokio::test]
+    async fn test_routes_answer_as_documented() {
+        let limit = format!("0.001:{BURST}");
+        let limits = Route::ALL
+            .map(|route| format!("{}={limit}", route.path()))
+            .join(",");
+        let (app, document) = app_and_document(&[
+            // Nothing listens there: `/chain` answers `502 Bad Gateway`.
+            ("DOWNSTREAM_URL", "http://127.0.0.1:1/health"),
+            // Limited without refill, for the responses of the limiter to be checked as well.
+            ("RATE_LIMIT_ROUTES", &limits),
+        ])
+        .await;
+
+        // Every route served answers some method as documented, whatever the document lists...
+        for path in Route::ALL
+            .map(Route::path)
+            .into_iter()
+            .chain(["/livez", "/readyz"])
+        {
+            let item = document.paths.paths.get(path);
+            let mut served = false;
+            for method in [Method::GET, Method::POST] {
+                let request = Request::builder()
+                    .method(method.clone())
+                    .uri(path)
+                    .body(Body::empty())
+                    .unwrap();
+                let status = app.request(request).await.status();
+                if status == StatusCode::METHOD_NOT_ALLOWED {
+                    continue;
+                }
+                served = true;
+                let operation = item.and_then(|item| operations(item).find(|(m, _)| *m == method));
+                assert!(
+                    operation.is_some(),
+                    "{method} {path} is served, but not documented"
+                );
+            }
+            assert!(served, "{path} is not served");
+        }
+
+        // ...and every documented operation is served, with documented responses only.
+        let mut statuses = BTreeSet::new();
+        for (path, item) in &document.paths.paths {
+            for (method, operation) in operations(item) {
+                for _ in 0..BURST + 1 {
+                    let status = check_response(&app, &document, &method, path, operation).await;
+                    statuses.insert((path.as_str(), status));
+                }
+            }
+        }
+        // Without a downstream service, the outcomes of `/chain` are limited to its errors, and
+        // `/readyz` reports the downstream service down.
+        for (path, status) in [
+            ("/chain", StatusCode::BAD_GATEWAY),
+            ("/jobs", StatusCode::ACCEPTED),
+            ("/health", StatusCode::TOO_MANY_REQUESTS),
+            ("/readyz", StatusCode::SERVICE_UNAVAILABLE),
+        ] {
+            assert!(
+                statuses.contains(&(path, status)),
+                "{path} never answered {status}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_forwarded_statuses_are_documented() {
+        // Answers the status code of its path, e.g. `/418`.
+        let downstream = Router::new().route(
+            "/{status}",
+            get(|Path(status): Path<u16>| async move { StatusCode::from_u16(status).unwrap() }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, downstream).await });
+
+        for status in [StatusCode::IM_A_TEAPOT, StatusCode::INTERNAL_SERVER_ERROR] {
+            let downstream_url = format!("http://{address}/{}", status.as_u16());
+            let (app, document) = app_and_document(&[("DOWNSTREAM_URL", &downstream_url)]).await;
+            let operation = document.paths.paths["/chain"].get.as_ref().unwrap();
+            let answered = check_response(&app, &document, &Method::GET, "/chain", operation).await;
+            assert_eq!(answered, status);
+        }
+    }
+}
@@ -0,0 +1,309 @@
+use crate::exemplars::{ExemplarMetricExporter, Exemplars, MetricTransport};
+use crate::telemetry_config::{OtlpProtocol, TelemetryConfig};
+use axum::http::HeaderMap;
+use opentelemetry_otlp::tonic_types::metadata::MetadataMap;
+use opentelemetry_otlp::tonic_types::transport::{Certificate, ClientTlsConfig};
+use opentelemetry_otlp::{
+    Compression, ExporterBuildError, LogExporter, Protocol, SpanExporter, WithExportConfig,
+    WithHttpConfig, WithTonicConfig,
+};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
+use thiserror::Error;
+use tonic::transport::Endpoint;
+
+// Same as the default of the exporters, which do not apply it to a client they are given.
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Error raised when the certificate of `OTEL_EXPORTER_OTLP_CERTIFICATE` cannot be used.
+#[derive(Debug, Error)]
+#[error("Unable to load the OTLP certificate {path:?}: {reason}")]
+pub struct CertificateError {
+    path: PathBuf,
+    reason: String,
+}
+
+/// Builds the span, metric and log exporters with the transport settings of the configuration:
+/// protocol, endpoint, headers, compression and certificate authority.
+pub struct OtlpExporters {
+    protocol: OtlpProtocol,
+    endpoint: String,
+    headers: HeaderMap,
+    compression: Option<Compression>,
+    tls_config: Option<ClientTlsConfig>,
+    http_client: Option<reqwest::blocking::Client>,
+}
+
+impl OtlpExporters {
+    /// Reads the certificate authority, if any. Collectors are otherwise authenticated with the
+    /// certificate authorities of the system.
+    pub fn new(config: &TelemetryConfig) -> Result<Self, CertificateError> {
+        let mut exporters = OtlpExporters {
+            protocol: config.otlp_protocol,
+            endpoint: config.otlp_endpoint.clone(),
+            headers: config.otlp_headers.clone(),
+            compression: config.otlp_compression,
+            tls_config: None,
+            http_client: None,
+        };
+
+        let certificate = match &config.otlp_certificate {
+            Some(path) => Some(std::fs::read(path).map_err(|err| CertificateError {
+                path: path.clone(),
+                reason: err.to_string(),
+            })?),
+            None => None,
+        };
+
+        match (config.otlp_protocol, certificate) {
+            (OtlpProtocol::Grpc, certificate) => {
+                // Without a TLS configuration, the gRPC exporters trust no certificate authority.
+                if certificate.is_some() || config.otlp_endpoint.starts_with("https:") {
+                    let tls_config = ClientTlsConfig::new().with_native_roots();
+                    exporters.tls_config = Some(match certificate {
+                        Some(pem) => tls_config.ca_certificate(Certificate::from_pem(pem)),
+                        None => tls_config,
+                    });
+                }
+            }
+            // The same client serves the three signals: the metric exporter is not the one of
+            // `opentelemetry_otlp`, which would otherwise build its own.
+            (OtlpProtocol::HttpProtobuf, certificate) => {
+                let error = |reason: String| CertificateError {
+                    path: config.otlp_certificate.clone().unwrap_or_default(),
+                    reason,
+                };
+                let certificate = certificate
+                    .map(|pem| reqwest::Certificate::from_pem(&pem))
+                    .transpose()
+                    .map_err(|err| error(err.to_string()))?;
+                // A blocking client cannot be built in an async context, as in `opentelemetry_otlp`.
+                let client = std::thread::spawn(move || {
+                    let builder = reqwest::blocking::Client::builder().timeout(EXPORT_TIMEOUT);
+                    match certificate {
+                        Some(certificate) => builder.add_root_certificate(certificate),
+                        None => builder,
+                    }
+                    .build()
+                })
+                .join()
+                .map_err(|_| error("the HTTP client could not be built".to_owned()))?
+                .map_err(|err| error(err.to_string()))?;
+                exporters.http_client = Some(client);
+            }
+        }
+
+        Ok(exporters)
+    }
+
+    pub fn span_exporter(&self) -> Result<SpanExporter, ExporterBuildError> {
+        match self.protocol {
+            OtlpProtocol::Grpc => self.grpc(SpanExporter::builder().with_tonic()).build(),
+            OtlpProtocol::HttpProtobuf => self
+                .http(SpanExporter::builder().with_http(), "/v1/traces")
+                .build(),
+        }
+    }
+
+    /// Builds the metric exporter attaching `exemplars` to the histograms, in place of the
+    /// exporter of `opentelemetry_otlp`. Like it, the gRPC channel connects lazily.
+    pub fn metric_exporter(
+        &self,
+        exemplars: Exemplars,
+    ) -> Result<ExemplarMetricExporter, ExporterBuildError> {
+        let transport = match (&self.protocol, &self.http_client) {
+            (OtlpProtocol::Grpc, _) => {
+                let invalid_uri = |err: &dyn std::fmt::Display| {
+                    ExporterBuildError::InvalidUri(self.endpoint.clone(), err.to_string())
+                };
+                let mut endpoint = Endpoint::from_shared(self.endpoint.clone())
+                    .map_err(|err| invalid_uri(&err))?
+                    .timeout(EXPORT_TIMEOUT);
+                if let Some(tls_config) = &self.tls_config {
+                    endpoint = endpoint
+                        .tls_config(tls_config.clone())
+                        .map_err(|err| invalid_uri(&err))?;
+                }
+                MetricTransport::grpc(
+                    endpoint.connect_lazy(),
+                    MetadataMap::from_headers(self.headers.clone()),
+                    self.compression == Some(Compression::Gzip),
+                )
+            }
+            (OtlpProtocol::HttpProtobuf, Some(client)) => MetricTransport::Http {
+                client: client.clone(),
+                url: self.signal_url("/v1/metrics"),
+                headers: self.headers.clone(),
+                gzip: self.compression == Some(Compression::Gzip),
+            },
+            (OtlpProtocol::HttpProtobuf, None) => return Err(ExporterBuildError::NoHttpClient),
+        };
+        Ok(ExemplarMetricExporter::new(transport, exemplars))
+    }
+
+    pub fn log_exporter(&self) -> Result<LogExporter, ExporterBuildError> {
+        match self.protocol {
+            OtlpProtocol::Grpc => self.grpc(LogExporter::builder().with_tonic()).build(),
+            OtlpProtocol::HttpProtobuf => self
+                .http(LogExporter::builder().with_http(), "/v1/logs")
+                .build(),
+        }
+    }
+
+    fn grpc<B: WithExportConfig + WithTonicConfig>(&self, builder: B) -> B {
+        let mut builder = builder
+            .with_endpoint(&self.endpoint)
+            .with_metadata(MetadataMap::from_headers(self.headers.clone()));
+        if let Some(compression) = self.compression {
+            builder = builder.with_compression(compression);
+        }
+        if let Some(tls_config) = &self.tls_config {
+            builder = builder.with_tls_config(tls_config.clone());
+        }
+        builder
+    }
+
+    // Unlike `OTEL_EXPORTER_OTLP_ENDPOINT` read by the exporters, an endpoint given to an HTTP
+    // exporter is used as is: the path of the signal is appended here.
+    fn http<B: WithExportConfig + WithHttpConfig>(&self, builder: B, path: &str) -> B {
+        let headers: HashMap<String, String> = self
+            .headers
+            .iter()
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_owned())))
+            .collect();
+        let mut builder = builder
+            .with_protocol(Protocol::HttpBinary)
+            .with_endpoint(self.signal_url(path))
+            .with_headers(headers);
+        if let Some(compression) = self.compression {
+            builder = builder.with_compression(compression);
+        }
+        if let Some(client) = &self.http_client {
+            builder = builder.with_http_client(client.clone());
+        }
+        builder
+    }
+
+    fn signal_url(&self, path: &str) -> String {
+        format!("{}{path}", self.endpoint.trim_end_matches('/'))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_collector::{ExportRequest, MockCollector, Storage, TestCertificates};
+    use opentelemetry::trace::{Tracer, TracerProvider};
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+
+    fn config_from(vars: &[(&str, &str)]) -> TelemetryConfig {
+        let vars: HashMap<&str, &str> = vars.iter().copied().collect();
+        TelemetryConfig::from_lookup(|name| vars.get(name).map(|value| value.to_string())).unwrap()
+    }
+
+    // Exports one span synchronously. Blocking, as the HTTP exporter uses a blocking client.
+    async fn export_span(config: TelemetryConfig) {
+        let exporters = OtlpExporters::new(&config).unwrap();
+        tokio::task::spawn_blocking(move || {
+            let provider = SdkTracerProvider::builder()
+                .with_simple_exporter(exporters.span_exporter().unwrap())
+                .build();
+            provider.tracer("test").in_span("GET /health", |_| {});
+            provider.shutdown().unwrap();
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_http_protobuf_transport() {
+        let collector = MockCollector::start(Storage::Memory).await;
+        export_span(config_from(&[
+            ("OTEL_EXPORTER_OTLP_PROTOCOL", "http/protobuf"),
+            ("OTEL_EXPORTER_OTLP_ENDPOINT", collector.http_endpoint()),
+            (
+                "OTEL_EXPORTER_OTLP_HEADERS",
+                "authorization=Bearer%20s3cret",
+            ),
+            ("OTEL_EXPORTER_OTLP_COMPRESSION", "gzip"),
+        ]))
+        .await;
+
+        let exports = collector.exports();
+        assert!(matches!(exports[0].request, ExportRequest::Traces(_)));
+        let headers = &exports[0].headers;
+        assert_eq!(headers["content-type"], "application/x-protobuf");
+        assert_eq!(headers["content-encoding"], "gzip");
+        assert_eq!(headers["authorization"], "Bearer s3cret");
+        assert!(collector.span("GET /health").is_some());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_grpc_transport() {
+        let collector = MockCollector::start(Storage::Memory).await;
+        export_span(config_from(&[
+            ("OTEL_EXPORTER_OTLP_ENDPOINT", collector.grpc_endpoint()),
+            ("OTEL_EXPORTER_OTLP_HEADERS", "x-tenant=acme"),
+            ("OTEL_EXPORTER_OTLP_COMPRESSION", "gzip"),
+        ]))
+        .await;
+
+        let exports = collector.exports();
+        assert!(matches!(exports[0].request, ExportRequest::Traces(_)));
+        let metadata = &exports[0].headers;
+        assert_eq!(metadata["x-tenant"], "acme");
+        assert_eq!(metadata["grpc-encoding"], "gzip");
+        assert!(collector.span("GET /health").is_some());
+    }
+
+    // Exports a span to a collector served over TLS, which is only trusted with the certificate
+    // authority issuing its certificate.
+    async fn check_certificate_authority(protocol: &str) {
+        let certificates = TestCertificates::generate();
+        let ca = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(ca.path(), &certificates.ca_pem).unwrap();
+        let collector = MockCollector::start_tls(Storage::Memory, &certificates).await;
+        let endpoint = match protocol {
+            "grpc" => collector.grpc_endpoint(),
+            _ => collector.http_endpoint(),
+        };
+        let settings = [
+            ("OTEL_EXPORTER_OTLP_PROTOCOL", protocol),
+            ("OTEL_EXPORTER_OTLP_ENDPOINT", endpoint),
+        ];
+
+        // The handshake fails with the certificate authorities of the system...
+        export_span(config_from(&settings)).await;
+        assert!(collector.exports().is_empty());
+
+        // ...and succeeds with the one of `OTEL_EXPORTER_OTLP_CERTIFICATE`.
+        let mut settings = settings.to_vec();
+        settings.push((
+            "OTEL_EXPORTER_OTLP_CERTIFICATE",
+            ca.path().to_str().unwrap(),
+        ));
+        export_span(config_from(&settings)).await;
+        assert!(collector.span("GET /health").is_some());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_grpc_certificate_authority() {
+        check_certificate_authority("grpc").await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_http_protobuf_certificate_authority() {
+        check_certificate_authority("http/protobuf").await;
+    }
+
+    #[test]
+    fn test_missing_certificate() {
+        let config = config_from(&[(
+            "OTEL_EXPORTER_OTLP_CERTIFICATE",
+            "/nonexistent/collector-ca.pem",
+        )]);
+        let err = OtlpExporters::new(&config).err().unwrap();
+        assert!(err.to_string().contains("/nonexistent/collector-ca.pem"));
+    }
+}
@@ -0,0 +1,189 @@
+use crate::attribute::unsigned;
+use crate::error::AppError;
+use axum::extract::Query;
+use axum::http::{header, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use opentelemetry::trace::TraceContextExt;
+use pprof::protos::Message;
+use pprof::{ProfilerGuardBuilder, Report};
+use serde::Deserialize;
+use std::time::Duration;
+use tracing::{field, info, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+const DEFAULT_DURATION_SECS: u64 = 10;
+const MAX_DURATION_SECS: u64 = 60;
+// Slightly off 100 Hz, so that sampling does not run in lockstep with periodic work.
+const SAMPLING_FREQUENCY: i32 = 99;
+// Frames of these libraries are skipped, as unwinding through them can crash the process.
+const BLOCKLIST: [&str; 4] = ["libc", "libgcc", "pthread", "vdso"];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProfileFormat {
+    /// Interactive SVG, to open in a browser.
+    #[default]
+    Flamegraph,
+    /// Protobuf of `go tool pprof`, to explore or compare profiles.
+    Pprof,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ProfileQuery {
+    seconds: Option<u64>,
+    #[serde(default)]
+    format: ProfileFormat,
+}
+
+/// Samples the CPU of the whole process during `seconds` (10 by default, 60 at most), then returns
+/// the profile as a flamegraph or in the pprof format.
+///
+/// The sampling runs in a `cpu_profile` span, whose trace ID is written in the profile (subtitle of
+/// the flamegraph, comment of the pprof profile) and in its file name: the profile can be looked
+/// up from the traces recorded during the same window, and the other way around.
+#[tracing::instrument(
+    name = "cpu_profile",
+    skip_all,
+    fields(
+        profile.duration_s = field::Empty,
+        profile.format = ?query.format,
+        profile.samples = field::Empty,
+    )
+)]
+pub async fn profile_handler(Query(query): Query<ProfileQuery>) -> Result<Response, AppError> {
+    let seconds = query.seconds.unwrap_or(DEFAULT_DURATION_SECS);
+    if !(1..=MAX_DURATION_SECS).contains(&seconds) {
+        return Err(AppError::BadRequest(format!(
+            "seconds must be between 1 and {MAX_DURATION_SECS}"
+        )));
+    }
+    let span = Span::current();
+    span.record("profile.duration_s", unsigned(seconds));
+    let trace_id = span.context().span().span_context().trace_id().to_string();
+
+    // Starting the profiler loads the debug information of the binary the first time, and
+    // resolving the symbols of the report takes a while: both are kept off the runtime workers.
+    let guard = tokio::task::spawn_blocking(|| {
+        ProfilerGuardBuilder::default()
+            .frequency(SAMPLING_FREQUENCY)
+            .blocklist(&BLOCKLIST)
+            .build()
+    })
+    .await
+    .map_err(|err| AppError::Profiling(err.to_string()))?
+    .map_err(|err| match err {
+        pprof::Error::Running => AppError::ProfilingInProgress,
+        err => AppError::Profiling(err.to_string()),
+    })?;
+    info!(seconds, "CPU profiling started");
+    tokio::time::sleep(Duration::from_secs(seconds)).await;
+
+    let report = tokio::task::spawn_blocking(move || guard.report().build())
+        .await
+        .map_err(|err| AppError::Profiling(err.to_string()))?
+        .map_err(|err| AppError::Profiling(err.to_string()))?;
+    let samples: isize = report.data.values().sum();
+    span.record("profile.samples", samples);
+    info!(samples, "CPU profiling finished");
+    if samples == 0 {
+        // The service was idle: there is nothing to draw.
+        return Ok(StatusCode::NO_CONTENT.into_response());
+    }
+
+    let (body, content_type, extension) = match query.format {
+        ProfileFormat::Flamegraph => (
+            flamegraph(&report, seconds, &trace_id)?,
+            "image/svg+xml",
+            "svg",
+        ),
+        ProfileFormat::Pprof => (pprof(&report, &trace_id)?, "application/octet-stream", "pb"),
+    };
+    let mut response = body.into_response();
+    let headers = response.headers_mut();
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    if let Ok(disposition) = HeaderValue::from_str(&format!(
+        "attachment; filename=\"profile-{trace_id}.{extension}\""
+    )) {
+        headers.insert(header::CONTENT_DISPOSITION, disposition);
+    }
+    Ok(response)
+}
+
+fn flamegraph(report: &Report, seconds: u64, trace_id: &str) -> Result<Vec<u8>, AppError> {
+    let mut options = pprof::flamegraph::Options::default();
+    options.title = "CPU profile".to_owned();
+    options.subtitle = Some(format!("{seconds} s, trace {trace_id}"));
+    let mut svg = Vec::new();
+    report
+        .flamegraph_with_options(&mut svg, &mut options)
+        .map_err(|err| AppError::Profiling(err.to_string()))?;
+    Ok(svg)
+}
+
+fn pprof(report: &Report, trace_id: &str) -> Result<Vec<u8>, AppError> {
+    let mut profile = report
+        .pprof()
+        .map_err(|err| AppError::Profiling(err.to_string()))?;
+    profile.string_table.push(format!("trace_id={trace_id}"));
+    profile.comment.push(profile.string_table.len() as i64 - 1);
+    Ok(profile.encode_to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_app::{attribute, TestApp};
+    use axum::body::{to_bytes, Body};
+    use axum::http::Request;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_profile_is_linked_to_its_trace() {
+        let app = TestApp::with_settings(&[("ADMIN_TOKEN", "s3cret")]);
+        let profile = |query: &str| {
+            let request = Request::get(format!("/admin/profile?{query}"))
+                .header(header::AUTHORIZATION, "Bearer s3cret")
+                .body(Body::empty())
+                .unwrap();
+            app.request(request)
+        };
+
+        // Gives the profiler some CPU time to sample until the profile is returned, as starting
+        // the profiler can take a while (it loads the debug information of the binary).
+        let running = Arc::new(AtomicBool::new(true));
+        let busy = std::thread::spawn({
+            let running = running.clone();
+            move || {
+                let mut count = 0u64;
+                while running.load(Ordering::Relaxed) {
+                    count = std::hint::black_box(count.wrapping_add(1));
+                }
+            }
+        });
+        let response = profile("seconds=1&format=pprof").await;
+        running.store(false, Ordering::Relaxed);
+        busy.join().unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let disposition = response.headers()[header::CONTENT_DISPOSITION]
+            .to_str()
+            .unwrap()
+            .to_owned();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let profile_data = pprof::protos::Profile::decode(body.as_ref()).unwrap();
+        assert!(!profile_data.sample.is_empty());
+
+        let span = app.span("cpu_profile");
+        assert_eq!(attribute(&span, "profile.duration_s"), Some(1_i64.into()));
+        let trace_id = span.span_context.trace_id().to_string();
+        let comment = profile_data.comment[0] as usize;
+        assert_eq!(
+            profile_data.string_table[comment],
+            format!("trace_id={trace_id}")
+        );
+        assert!(disposition.contains(&format!("profile-{trace_id}.pb")));
+
+        let response = profile("seconds=600").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,641 @@
+use crate::error::{AppError, StartupError};
+use crate::settings::invalid;
+use axum::extract::{ConnectInfo, MatchedPath, Request, State};
+use axum::http::HeaderName;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use opentelemetry::metrics::{Counter, Meter};
+use opentelemetry::KeyValue;
+use opentelemetry_semantic_conventions::attribute::HTTP_ROUTE;
+use std::collections::{BTreeSet, HashMap};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::{warn, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+// Beyond this number of clients, the buckets full again are dropped. When none is, the new clients
+// are limited until one is.
+const MAX_CLIENT_BUCKETS: usize = 10_000;
+// Requests rejected by the concurrency cap may be retried as soon as one request completes.
+const CONCURRENCY_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// Token bucket: `burst` requests at once, refilled at `rate` requests per second.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub rate: f64,
+    pub burst: f64,
+}
+
+/// Limits applied by `limit_requests`, read from the environment:
+///
+/// | Variable | Default | Example |
+/// | --- | --- | --- |
+/// | `RATE_LIMIT_ROUTES` | none | `/health=100:200,/chain=10` |
+/// | `RATE_LIMIT_PER_CLIENT` | none | `5:10` |
+/// | `RATE_LIMIT_CLIENT_HEADER` | none, the client is its IP address | `x-client-id` |
+/// | `CONCURRENCY_LIMIT` | none | `256` |
+///
+/// Rates are written `rate[:burst]`, in requests per second; the burst defaults to the rate.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LimitsConfig {
+    /// Shared by all the clients of a route, identified by its template (e.g. `/health`).
+    pub routes: HashMap<String, RateLimit>,
+    pub per_client: Option<RateLimit>,
+    /// Header identifying the clients, when they share an IP address (e.g. behind a proxy).
+    pub client_header: Option<HeaderName>,
+    /// Maximum number of requests served at the same time.
+    pub max_concurrency: Option<usize>,
+}
+
+impl LimitsConfig {
+    pub fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Result<Self, StartupError> {
+        let lookup = |name: &str| lookup(name).filter(|value| !value.trim().is_empty());
+
+        Ok(LimitsConfig {
+            routes: match lookup("RATE_LIMIT_ROUTES") {
+                Some(rules) => parse_route_limits(&rules)?,
+                None => HashMap::new(),
+            },
+            per_client: lookup("RATE_LIMIT_PER_CLIENT")
+                .map(|limit| {
+                    parse_rate_limit(&limit).ok_or_else(|| {
+                        invalid("RATE_LIMIT_PER_CLIENT", &limit, "expected `rate[:burst]`")
+                    })
+                })
+                .transpose()?,
+            client_header: lookup("RATE_LIMIT_CLIENT_HEADER")
+                .map(|header| {
+                    HeaderName::try_from(header.trim())
+                        .map_err(|err| invalid("RATE_LIMIT_CLIENT_HEADER", &header, err))
+                })
+                .transpose()?,
+            max_concurrency: lookup("CONCURRENCY_LIMIT")
+                .map(|limit| match limit.trim().parse() {
+                    Ok(limit) if limit > 0 => Ok(limit),
+                    _ => Err(invalid(
+                        "CONCURRENCY_LIMIT",
+                        &limit,
+                        "expected a positive number of requests",
+                    )),
+                })
+                .transpose()?,
+        })
+    }
+}
+
+fn parse_rate_limit(limit: &str) -> Option<RateLimit> {
+    let (rate, burst) = match limit.split_once(':') {
+        Some((rate, burst)) => (rate, Some(burst)),
+        None => (limit, None),
+    };
+    let rate: f64 = rate.trim().parse().ok().filter(|rate: &f64| *rate > 0.0)?;
+    let burst = match burst {
+        Some(burst) => burst
+            .trim()
+            .parse()
+            .ok()
+            .filter(|burst: &f64| *burst >= 1.0)?,
+        None => rate.max(1.0),
+    };
+    Some(RateLimit { rate, burst })
+}
+
+// Format: `route=rate[:burst]` separated by commas, e.g. `/health=100:200,/chain=10`.
+fn parse_route_limits(rules: &str) -> Result<HashMap<String, RateLimit>, StartupError> {
+    rules
+        .split(',')
+        .map(str::trim)
+        .filter(|rule| !rule.is_empty())
+        .map(|rule| {
+            rule.split_once('=')
+                .filter(|(route, _)| route.trim().starts_with('/'))
+                .and_then(|(route, limit)| Some((route.trim().to_owned(), parse_rate_limit(limit)?)))
+                .ok_or_else(|| {
+                    invalid(
+                        "RATE_LIMIT_ROUTES",
+                        rules,
+                        format!("expected `route=rate[:burst]` rules separated by commas, found {rule:?}"),
+                    )
+                })
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn full(limit: &RateLimit, now: Instant) -> Self {
+        TokenBucket {
+            tokens: limit.burst,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.rate).min(limit.burst);
+        self.updated = now;
+    }
+
+    /// Returns the time until a token is available, if none is.
+    fn check(&mut self, limit: &RateLimit, now: Instant) -> Result<(), Duration> {
+        self.refill(limit, now);
+        if self.tokens >= 1.0 {
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / limit.rate))
+        }
+    }
+
+    /// Takes the token found by `check`.
+    fn take(&mut self) {
+        self.tokens -= 1.0;
+    }
+
+    /// When the bucket is refilled up to the burst: from then on, it is the same as no bucket.
+    fn full_at(&self, limit: &RateLimit) -> Instant {
+        let missing = (limit.burst - self.tokens).max(0.0);
+        self.updated + Duration::from_secs_f64(missing / limit.rate)
+    }
+}
+
+/// Buckets of the clients, indexed by when they are full again: the soonest full is the one
+/// forgotten to make room for a new client, in `O(log n)`, and only once it is full, so that the
+/// limit of an active client is never reset.
+#[derive(Default)]
+struct ClientBuckets {
+    // The bucket, and its key in `by_full_at`.
+    buckets: HashMap<String, (TokenBucket, Instant)>,
+    by_full_at: BTreeSet<(Instant, String)>,
+}
+
+impl ClientBuckets {
+    /// Bucket of `client`, made room for among `MAX_CLIENT_BUCKETS` if new. Returns the time until
+    /// a bucket is full again when every client is active.
+    fn get(
+        &mut self,
+        client: &str,
+        limit: &RateLimit,
+        now: Instant,
+    ) -> Result<&mut TokenBucket, Duration> {
+        if !self.buckets.contains_key(client) {
+            if self.buckets.len() >= MAX_CLIENT_BUCKETS {
+                if let Some((full_at, _)) = self.by_full_at.first() {
+                    if *full_at > now {
+                        return Err(*full_at - now);
+                    }
+                }
+                if let Some((_, forgotten)) = self.by_full_at.pop_first() {
+                    self.buckets.remove(&forgotten);
+                }
+            }
+            self.by_full_at.insert((now, client.to_owned()));
+            self.buckets
+                .insert(client.to_owned(), (TokenBucket::full(limit, now), now));
+        }
+        Ok(&mut self.buckets.get_mut(client).expect("inserted above").0)
+    }
+
+    /// Moves the bucket of `client` in the index after its tokens were taken.
+    fn reindex(&mut self, client: &str, limit: &RateLimit) {
+        if let Some((bucket, indexed_at)) = self.buckets.get_mut(client) {
+            let full_at = bucket.full_at(limit);
+            let key = (*indexed_at, client.to_owned());
+            self.by_full_at.remove(&key);
+            self.by_full_at.insert((full_at, key.1));
+            *indexed_at = full_at;
+        }
+    }
+}
+
+/// Limit a request was rejected by, recorded as the `limit.kind` attribute.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LimitKind {
+    RouteRate,
+    ClientRate,
+    Concurrency,
+}
+
+impl LimitKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            LimitKind::RouteRate => "route_rate",
+            LimitKind::ClientRate => "client_rate",
+            LimitKind::Concurrency => "concurrency",
+        }
+    }
+}
+
+/// State of `limit_requests`: the token buckets and the permits of the concurrency cap.
+#[derive(Clone)]
+pub struct Limiter {
+    config: Arc<LimitsConfig>,
+    route_buckets: Arc<Mutex<HashMap<String, TokenBucket>>>,
+    client_buckets: Arc<Mutex<ClientBuckets>>,
+    concurrency: Option<Arc<Semaphore>>,
+    limited_requests: Counter<u64>,
+}
+
+impl Limiter {
+    pub fn new(config: LimitsConfig, meter: &Meter) -> Self {
+        Limiter {
+            concurrency: config
+                .max_concurrency
+                .map(|permits| Arc::new(Semaphore::new(permits))),
+            config: Arc::new(config),
+            route_buckets: Arc::default(),
+            client_buckets: Arc::default(),
+            limited_requests: meter
+                .u64_counter("http.server.limited_requests")
+                .with_description(
+                    "Number of HTTP requests rejected by a rate or concurrency limit.",
+                )
+                .with_unit("{request}")
+                .build(),
+        }
+    }
+
+    /// Admits a request of `client` on `route` if all the limits allow it, and only then takes
+    /// their tokens: a rejected request does not use up the tokens of the other limits. The
+    /// permit returned is held until the response.
+    fn admit(
+        &self,
+        client: Option<&str>,
+        route: &str,
+        now: Instant,
+    ) -> Result<Option<OwnedSemaphorePermit>, (LimitKind, Duration)> {
+        // Always locked in this order.
+        let mut client_buckets = self.client_buckets.lock().unwrap();
+        let mut route_buckets = self.route_buckets.lock().unwrap();
+
+        // The client is checked first, so that a greedy client is told about its own limit.
+        let client_limit = self.config.per_client.as_ref().zip(client);
+        let client_bucket = match client_limit {
+            Some((limit, client)) => Some((
+                limit,
+                client_buckets
+                    .get(client, limit, now)
+                    .map_err(|retry_after| (LimitKind::ClientRate, retry_after))?,
+            )),
+            None => None,
+        };
+        let route_bucket = self.config.routes.get(route).map(|limit| {
+            let bucket = route_buckets
+                .entry(route.to_owned())
+                .or_insert_with(|| TokenBucket::full(limit, now));
+            (limit, bucket)
+        });
+        let mut buckets = [
+            (LimitKind::ClientRate, client_bucket),
+            (LimitKind::RouteRate, route_bucket),
+        ];
+        for (kind, bucket) in &mut buckets {
+            if let Some((limit, bucket)) = bucket {
+                bucket
+                    .check(limit, now)
+                    .map_err(|retry_after| (*kind, retry_after))?;
+            }
+        }
+        let permit = match &self.concurrency {
+            Some(semaphore) => Some(
+                semaphore
+                    .clone()
+                    .try_acquire_owned()
+                    .map_err(|_| (LimitKind::Concurrency, CONCURRENCY_RETRY_AFTER))?,
+            ),
+            None => None,
+        };
+
+        for (_, bucket) in buckets {
+            if let Some((_, bucket)) = bucket {
+                bucket.take();
+            }
+        }
+        if let Some((limit, client)) = client_limit {
+            client_buckets.reindex(client, limit);
+        }
+        Ok(permit)
+    }
+
+    fn client(&self, request: &Request) -> Option<String> {
+        self.config
+            .client_header
+            .as_ref()
+            .and_then(|header| request.headers().get(header))
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_owned)
+            .or_else(|| {
+                request
+                    .extensions()
+                    .get::<ConnectInfo<SocketAddr>>()
+                    .map(|ConnectInfo(address)| address.ip().to_string())
+            })
+    }
+
+    fn reject(&self, route: &str, kind: LimitKind, retry_after: Duration) -> Response {
+        Span::current().set_attribute("limit.kind", kind.as_str());
+        self.limited_requests.add(
+            1,
+            &[
+                KeyValue::new(HTTP_ROUTE, route.to_owned()),
+                KeyValue::new("limit.kind", kind.as_str()),
+            ],
+        );
+        warn!(route, limit = kind.as_str(), "Request rejected by a limit");
+        match kind {
+            LimitKind::RouteRate | LimitKind::ClientRate => {
+                AppError::RateLimited { retry_after }.into_response()
+            }
+            LimitKind::Concurrency => AppError::Overloaded { retry_after }.into_response(),
+        }
+    }
+}
+
+/// Middleware rejecting the requests beyond the limits of `LimitsConfig`, to be installed with
+/// `Router::route_layer`, on the routes to protect:
+///
+/// - `429 Too Many Requests` when the bucket of the client, or the one of the route, is empty,
+/// - `503 Service Unavailable` when `CONCURRENCY_LIMIT` requests are already being served.
+///
+/// Both come with a `Retry-After` header. A rejected request takes no token. The rejections are counted in
+/// `http.server.limited_requests` and recorded on the server span as `limit.kind`.
+pub async fn limit_requests(
+    State(limiter): State<Limiter>,
+    matched_path: Option<MatchedPath>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let route = matched_path
+        .map(|path| path.as_str().to_owned())
+        .unwrap_or_default();
+    let client = limiter.client(&request);
+    let _permit = match limiter.admit(client.as_deref(), &route, Instant::now()) {
+        Ok(permit) => permit,
+        Err((kind, retry_after)) => return limiter.reject(&route, kind, retry_after),
+    };
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_app::{attribute, TestApp};
+    use axum::body::Body;
+    use axum::http::{header, Method, StatusCode};
+    use axum::routing::get;
+    use axum::{middleware, Router};
+    use opentelemetry::metrics::MeterProvider;
+    use opentelemetry_sdk::metrics::SdkMeterProvider;
+    use tokio::sync::Notify;
+
+    fn config_from(vars: &[(&str, &str)]) -> Result<LimitsConfig, StartupError> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        LimitsConfig::from_lookup(|name| vars.get(name).cloned())
+    }
+
+    #[test]
+    fn test_config() {
+        assert_eq!(config_from(&[]).unwrap(), LimitsConfig::default());
+
+        let config = config_from(&[
+            ("RATE_LIMIT_ROUTES", "/health=100:200, /chain=0.5"),
+            ("RATE_LIMIT_PER_CLIENT", "5"),
+            ("RATE_LIMIT_CLIENT_HEADER", "X-Client-Id"),
+            ("CONCURRENCY_LIMIT", "256"),
+        ])
+        .unwrap();
+        assert_eq!(
+            config.routes["/health"],
+            RateLimit {
+                rate: 100.0,
+                burst: 200.0
+            }
+        );
+        assert_eq!(
+            config.routes["/chain"],
+            RateLimit {
+                rate: 0.5,
+                burst: 1.0
+            }
+        );
+        assert_eq!(
+            config.per_client,
+            Some(RateLimit {
+                rate: 5.0,
+                burst: 5.0
+            })
+        );
+        assert_eq!(config.client_header.unwrap(), "x-client-id");
+        assert_eq!(config.max_concurrency, Some(256));
+
+        for (name, value) in [
+            ("RATE_LIMIT_ROUTES", "health=10"),
+            ("RATE_LIMIT_ROUTES", "/health=0"),
+            ("RATE_LIMIT_ROUTES", "/health=10:0.5"),
+            ("RATE_LIMIT_PER_CLIENT", "fast"),
+            ("RATE_LIMIT_CLIENT_HEADER", "x client"),
+            ("CONCURRENCY_LIMIT", "0"),
+        ] {
+            let err = config_from(&[(name, value)]).unwrap_err();
+            assert!(err.to_string().contains(name), "{err}");
+        }
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let limit = RateLimit {
+            rate: 2.0,
+            burst: 2.0,
+        };
+        let now = Instant::now();
+        let mut bucket = TokenBucket::full(&limit, now);
+        assert_eq!(bucket.check(&limit, now), Ok(()));
+        bucket.take();
+        assert_eq!(bucket.check(&limit, now), Ok(()));
+        bucket.take();
+        assert_eq!(bucket.check(&limit, now), Err(Duration::from_millis(500)));
+
+        // Refilled at 2 tokens per second, up to the burst.
+        let later = now + Duration::from_millis(500);
+        assert_eq!(bucket.check(&limit, later), Ok(()));
+        bucket.take();
+        let much_later = now + Duration::from_secs(60);
+        assert_eq!(bucket.full_at(&limit), now + Duration::from_millis(1500));
+        bucket.refill(&limit, much_later);
+        assert_eq!(bucket.tokens, 2.0);
+    }
+
+    #[tokio::test]
+    async fn test_limited_requests_are_rejected() {
+        let limiter = Limiter::new(
+            LimitsConfig {
+                per_client: Some(RateLimit {
+                    rate: 0.01,
+                    burst: 2.0,
+                }),
+                client_header: Some(HeaderName::from_static("x-client-id")),
+                max_concurrency: Some(1),
+                ..LimitsConfig::default()
+            },
+            &SdkMeterProvider::default().meter("test"),
+        );
+        let started = Arc::new(Notify::new());
+        let release = Arc::new(Notify::new());
+        let router = Router::new()
+            .route("/health", get(|| async {}))
+            .route(
+                "/slow",
+                get({
+                    let (started, release) = (started.clone(), release.clone());
+                    move || async move {
+                        started.notify_one();
+                        release.notified().await
+                    }
+                }),
+            )
+            .route_layer(middleware::from_fn_with_state(limiter, limit_requests));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        let client = reqwest::Client::new();
+        let get = |path: &str, client_id: &str| {
+            client
+                .get(format!("{url}{path}"))
+                .header("x-client-id", client_id)
+                .send()
+        };
+
+        // The burst of a client is used up...
+        assert_eq!(get("/health", "a").await.unwrap().status(), StatusCode::OK);
+        assert_eq!(get("/health", "a").await.unwrap().status(), StatusCode::OK);
+        let response = get("/health", "a").await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        // ...in 100 seconds at 0.01 token per second.
+        assert_eq!(response.headers()[header::RETRY_AFTER], "100");
+        // ...and not the one of another client.
+        assert_eq!(get("/health", "b").await.unwrap().status(), StatusCode::OK);
+
+        // A request is in flight: the next one exceeds the concurrency cap.
+        let slow = tokio::spawn(get("/slow", "c"));
+        started.notified().await;
+        let response = get("/health", "d").await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "1");
+        release.notify_one();
+        assert_eq!(slow.await.unwrap().unwrap().status(), StatusCode::OK);
+        assert_eq!(get("/health", "d").await.unwrap().status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_full_client_buckets_are_forgotten() {
+        let limiter = Limiter::new(
+            LimitsConfig {
+                per_client: Some(RateLimit {
+                    rate: 0.01,
+                    burst: 1.0,
+                }),
+                ..LimitsConfig::default()
+            },
+            &SdkMeterProvider::default().meter("test"),
+        );
+        let now = Instant::now();
+        let at = |millis: u64| now + Duration::from_millis(millis);
+        for client in 0..MAX_CLIENT_BUCKETS as u64 {
+            assert!(limiter
+                .admit(Some(&client.to_string()), "/", at(client))
+                .is_ok());
+        }
+
+        // Every client is active: a new one waits for the bucket of client 0, full again first.
+        let later = at(MAX_CLIENT_BUCKETS as u64);
+        assert_eq!(
+            limiter.admit(Some("new"), "/", later).unwrap_err(),
+            (LimitKind::ClientRate, at(100_000) - later)
+        );
+        assert!(matches!(
+            limiter.admit(Some("0"), "/", later),
+            Err((LimitKind::ClientRate, _))
+        ));
+
+        // Full again, the bucket of client 0 makes room for the new client. The others keep their
+        // limit.
+        assert!(limiter.admit(Some("new"), "/", at(100_000)).is_ok());
+        assert_eq!(
+            limiter.client_buckets.lock().unwrap().buckets.len(),
+            MAX_CLIENT_BUCKETS
+        );
+        assert!(matches!(
+            limiter.admit(Some("1"), "/", at(100_000)),
+            Err((LimitKind::ClientRate, _))
+        ));
+        assert!(matches!(
+            limiter.admit(Some("new"), "/", at(100_000)),
+            Err((LimitKind::ClientRate, _))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_route_limit_through_the_router() {
+        let app = TestApp::with_settings(&[
+            ("RATE_LIMIT_ROUTES", "/health=0.01:1"),
+            ("RATE_LIMIT_PER_CLIENT", "0.01:3"),
+            ("RATE_LIMIT_CLIENT_HEADER", "x-client-id"),
+            ("HEALTH_OUTCOME_WEIGHTS", "200=1"),
+        ]);
+        let request = |method: Method, uri: &str, client: &str| {
+            let request = Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("x-client-id", client)
+                .body(Body::empty())
+                .unwrap();
+            app.request(request)
+        };
+
+        assert_eq!(
+            request(Method::GET, "/health", "a").await.status(),
+            StatusCode::OK
+        );
+        // The token of the route is used up, whichever the client.
+        for client in ["b", "a"] {
+            let response = request(Method::GET, "/health", client).await;
+            assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+            assert_eq!(response.headers()[header::RETRY_AFTER], "100");
+        }
+        assert_eq!(
+            attribute(&app.span("GET /health"), "limit.kind"),
+            Some("route_rate".into())
+        );
+        assert_eq!(
+            app.counter(
+                "http.server.limited_requests",
+                &[
+                    KeyValue::new(HTTP_ROUTE, "/health"),
+                    KeyValue::new("limit.kind", "route_rate"),
+                ],
+            ),
+            2
+        );
+
+        // The rejected request took no token of client `a`: two are left, on the other routes.
+        for _ in 0..2 {
+            let response = request(Method::POST, "/jobs?work_ms=0", "a").await;
+            assert_eq!(response.status(), StatusCode::ACCEPTED);
+        }
+        let response = request(Method::POST, "/jobs?work_ms=0", "a").await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(
+            attribute(&app.span("POST /jobs"), "limit.kind"),
+            Some("client_rate".into())
+        );
+    }
+}
@@ -0,0 +1,407 @@
+use opentelemetry::logs::{AnyValue, LogRecord, Logger, LoggerProvider};
+use opentelemetry::trace::Status;
+use opentelemetry::{Context, InstrumentationScope, Key, KeyValue, StringValue, Value};
+use opentelemetry_sdk::error::OTelSdkResult;
+use opentelemetry_sdk::logs::{LogProcessor, SdkLogRecord, SdkLogger, SdkLoggerProvider};
+use opentelemetry_sdk::resource::Resource;
+use opentelemetry_sdk::trace::{Span, SpanData, SpanProcessor};
+use regex::{Captures, Regex};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Replaces the redacted values in `RedactionMode::Redact`.
+pub const REDACTED: &str = "[REDACTED]";
+
+/// What a redacted value is replaced with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RedactionMode {
+    /// `[REDACTED]`.
+    Redact,
+    /// `sha256:` followed by the first 16 hexadecimal digits of the SHA-256 of the value: equal
+    /// values still have equal hashes, e.g. to count the requests of one user.
+    Hash,
+}
+
+/// Rules applied to spans and log records before they are exported:
+///
+/// - the values of the attributes named in `keys` (case-insensitive) are redacted as a whole,
+/// - the matches of `pattern` are redacted in the other string values, in span and event names,
+///   in status descriptions and in log bodies.
+#[derive(Debug, Clone)]
+pub struct RedactionRules {
+    pub keys: Vec<String>,
+    pub pattern: Option<Regex>,
+    pub mode: RedactionMode,
+}
+
+impl RedactionRules {
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty() && self.pattern.is_none()
+    }
+
+    fn matches_key(&self, key: &Key) -> bool {
+        self.keys
+            .iter()
+            .any(|name| name.eq_ignore_ascii_case(key.as_str()))
+    }
+
+    fn mask(&self, value: &str) -> String {
+        match self.mode {
+            RedactionMode::Redact => REDACTED.to_owned(),
+            RedactionMode::Hash => {
+                let digest = Sha256::digest(value.as_bytes());
+                let hex: String = digest[..8]
+                    .iter()
+                    .map(|byte| format!("{byte:02x}"))
+                    .collect();
+                format!("sha256:{hex}")
+            }
+        }
+    }
+
+    // `None` when nothing matches, so that untouched values are not copied.
+    fn redact_matches(&self, value: &str) -> Option<String> {
+        let pattern = self.pattern.as_ref()?;
+        match pattern.replace_all(value, |captures: &Captures| self.mask(&captures[0])) {
+            Cow::Borrowed(_) => None,
+            Cow::Owned(redacted) => Some(redacted),
+        }
+    }
+
+    fn redact_name(&self, name: &mut Cow<'static, str>) {
+        if let Some(redacted) = self.redact_matches(name) {
+            *name = redacted.into();
+        }
+    }
+
+    fn redact_string(&self, value: &mut StringValue) {
+        if let Some(redacted) = self.redact_matches(value.as_str()) {
+            *value = redacted.into();
+        }
+    }
+
+    fn redact_attributes(&self, attributes: &mut [KeyValue]) {
+        for attribute in attributes {
+            if self.matches_key(&attribute.key) {
+                attribute.value = self.mask(&attribute.value.as_str()).into();
+                continue;
+            }
+            match &mut attribute.value {
+                Value::String(value) => self.redact_string(value),
+                Value::Array(opentelemetry::Array::String(values)) => values
+                    .iter_mut()
+                    .for_each(|value| self.redact_string(value)),
+                _ => {}
+            }
+        }
+    }
+
+    /// Redacts the attributes of the span, of its events and of its links.
+    pub fn redact_span(&self, span: &mut SpanData) {
+        self.redact_name(&mut span.name);
+        self.redact_attributes(&mut span.attributes);
+        for event in &mut span.events.events {
+            self.redact_name(&mut event.name);
+            self.redact_attributes(&mut event.attributes);
+        }
+        for link in &mut span.links.links {
+            self.redact_attributes(&mut link.attributes);
+        }
+        if let Status::Error { description } = &mut span.status {
+            self.redact_name(description);
+        }
+    }
+
+    // Returns whether `value` was changed. Maps are searched for matching keys too.
+    fn redact_any_value(&self, key: Option<&Key>, value: &mut AnyValue) -> bool {
+        if key.is_some_and(|key| self.matches_key(key)) {
+            let text = match &*value {
+                AnyValue::String(value) => value.to_string(),
+                AnyValue::Int(value) => value.to_string(),
+                AnyValue::Double(value) => value.to_string(),
+                AnyValue::Boolean(value) => value.to_string(),
+                other => format!("{other:?}"),
+            };
+            *value = AnyValue::String(self.mask(&text).into());
+            return true;
+        }
+        match value {
+            AnyValue::String(value) => match self.redact_matches(value.as_str()) {
+                Some(redacted) => {
+                    *value = redacted.into();
+                    true
+                }
+                None => false,
+            },
+            AnyValue::ListAny(values) => values.iter_mut().fold(false, |changed, value| {
+                self.redact_any_value(None, value) | changed
+            }),
+            AnyValue::Map(entries) => entries.iter_mut().fold(false, |changed, (key, value)| {
+                self.redact_any_value(Some(key), value) | changed
+            }),
+            _ => false,
+        }
+    }
+}
+
+/// Span processor redacting the spans before handing them to the exporting processor it wraps.
+#[derive(Debug)]
+pub struct RedactingSpanProcessor<P> {
+    inner: P,
+    rules: Arc<RedactionRules>,
+}
+
+impl<P: SpanProcessor> RedactingSpanProcessor<P> {
+    pub fn new(inner: P, rules: Arc<RedactionRules>) -> Self {
+        RedactingSpanProcessor { inner, rules }
+    }
+}
+
+impl<P: SpanProcessor> SpanProcessor for RedactingSpanProcessor<P> {
+    fn on_start(&self, span: &mut Span, cx: &Context) {
+        self.inner.on_start(span, cx);
+    }
+
+    fn on_end(&self, mut span: SpanData) {
+        if !self.rules.is_empty() {
+            self.rules.redact_span(&mut span);
+        }
+        self.inner.on_end(span);
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.inner.force_flush()
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        self.inner.shutdown_with_timeout(timeout)
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.inner.set_resource(resource);
+    }
+}
+
+/// Log processor redacting the log records before handing them to the exporting processor it wraps.
+#[derive(Debug)]
+pub struct RedactingLogProcessor<P> {
+    inner: P,
+    rules: Arc<RedactionRules>,
+    // The attributes of a record can only be appended to: a record with redacted attributes is a
+    // new record, which only a logger can create. This one emits nothing.
+    records: SdkLogger,
+}
+
+impl<P: LogProcessor> RedactingLogProcessor<P> {
+    pub fn new(inner: P, rules: Arc<RedactionRules>) -> Self {
+        RedactingLogProcessor {
+            inner,
+            rules,
+            records: SdkLoggerProvider::builder().build().logger("redaction"),
+        }
+    }
+
+    // `None` when nothing is redacted.
+    fn redact(&self, record: &SdkLogRecord) -> Option<SdkLogRecord> {
+        let mut redacted = false;
+        let body = record.body().cloned().map(|mut body| {
+            redacted |= self.rules.redact_any_value(None, &mut body);
+            body
+        });
+        let attributes: Vec<(Key, AnyValue)> = record
+            .attributes_iter()
+            .map(|(key, value)| {
+                let mut value = value.clone();
+                redacted |= self.rules.redact_any_value(Some(key), &mut value);
+                (key.clone(), value)
+            })
+            .collect();
+        if !redacted {
+            return None;
+        }
+
+        let mut copy = self.records.create_log_record();
+        if let Some(name) = record.event_name() {
+            copy.set_event_name(name);
+        }
+        if let Some(target) = record.target() {
+            copy.set_target(target.clone());
+        }
+        if let Some(timestamp) = record.timestamp() {
+            copy.set_timestamp(timestamp);
+        }
+        if let Some(timestamp) = record.observed_timestamp() {
+            copy.set_observed_timestamp(timestamp);
+        }
+        if let Some(context) = record.trace_context() {
+            copy.set_trace_context(context.trace_id, context.span_id, context.trace_flags);
+        }
+        if let Some(severity) = record.severity_text() {
+            copy.set_severity_text(severity);
+        }
+        if let Some(severity) = record.severity_number() {
+            copy.set_severity_number(severity);
+        }
+        if let Some(body) = body {
+            copy.set_body(body);
+        }
+        copy.add_attributes(attributes);
+        Some(copy)
+    }
+}
+
+impl<P: LogProcessor> LogProcessor for RedactingLogProcessor<P> {
+    fn emit(&self, record: &mut SdkLogRecord, instrumentation: &InstrumentationScope) {
+        if !self.rules.is_empty() {
+            if let Some(redacted) = self.redact(record) {
+                *record = redacted;
+            }
+        }
+        self.inner.emit(record, instrumentation);
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.inner.force_flush()
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        self.inner.shutdown_with_timeout(timeout)
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.inner.set_resource(resource);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::logs::Severity;
+    use opentelemetry::trace::{Span as _, Tracer, TracerProvider};
+    use opentelemetry_sdk::logs::{InMemoryLogExporter, SimpleLogProcessor};
+    use opentelemetry_sdk::trace::{InMemorySpanExporter, SdkTracerProvider, SimpleSpanProcessor};
+
+    const EMAIL: &str = "alice@example.com";
+
+    fn rules(mode: RedactionMode) -> Arc<RedactionRules> {
+        Arc::new(RedactionRules {
+            keys: vec!["enduser.id".to_owned(), "url.query".to_owned()],
+            pattern: Some(Regex::new(r"[\w.+-]+@[\w-]+\.[\w.]+").unwrap()),
+            mode,
+        })
+    }
+
+    fn export_span(rules: Arc<RedactionRules>) -> SpanData {
+        let exporter = InMemorySpanExporter::default();
+        let provider = SdkTracerProvider::builder()
+            .with_span_processor(RedactingSpanProcessor::new(
+                SimpleSpanProcessor::new(exporter.clone()),
+                rules,
+            ))
+            .build();
+
+        let mut span = provider.tracer("test").start(format!("login {EMAIL}"));
+        span.set_attribute(KeyValue::new("enduser.id", 4242));
+        span.set_attribute(KeyValue::new("URL.Query", "token=s3cret"));
+        span.set_attribute(KeyValue::new("user.contact", format!("<{EMAIL}>")));
+        span.set_attribute(KeyValue::new("http.route", "/login"));
+        span.add_event(
+            format!("Signed in as {EMAIL}"),
+            vec![KeyValue::new("enduser.id", "4242")],
+        );
+        span.set_status(Status::error(format!("unknown user {EMAIL}")));
+        span.end();
+
+        // The simple processor exports synchronously, and the exporter forgets its spans on shutdown.
+        exporter.get_finished_spans().unwrap().remove(0)
+    }
+
+    fn attribute<'a>(attributes: &'a [KeyValue], key: &str) -> &'a Value {
+        &attributes
+            .iter()
+            .find(|attribute| attribute.key.as_str() == key)
+            .unwrap()
+            .value
+    }
+
+    #[test]
+    fn test_redacted_values_never_reach_the_span_exporter() {
+        let span = export_span(rules(RedactionMode::Redact));
+
+        let exported = format!("{span:?}");
+        for secret in [EMAIL, "4242", "s3cret"] {
+            assert!(!exported.contains(secret), "{secret} was exported");
+        }
+        assert_eq!(span.name, "login [REDACTED]");
+        assert_eq!(attribute(&span.attributes, "enduser.id"), &REDACTED.into());
+        assert_eq!(
+            attribute(&span.attributes, "user.contact"),
+            &"<[REDACTED]>".into()
+        );
+        // Nothing else is changed.
+        assert_eq!(attribute(&span.attributes, "http.route"), &"/login".into());
+        assert_eq!(span.events[0].name, "Signed in as [REDACTED]");
+    }
+
+    #[test]
+    fn test_hash_mode() {
+        let span = export_span(rules(RedactionMode::Hash));
+
+        assert!(!format!("{span:?}").contains(EMAIL));
+        // The same value gets the same hash, whatever its type.
+        let user_id = attribute(&span.attributes, "enduser.id");
+        assert!(user_id.as_str().starts_with("sha256:"));
+        assert_eq!(user_id, attribute(&span.events[0].attributes, "enduser.id"));
+        assert_ne!(user_id, attribute(&span.attributes, "URL.Query"));
+    }
+
+    #[test]
+    fn test_redacted_values_never_reach_the_log_exporter() {
+        let exporter = InMemoryLogExporter::default();
+        let provider = SdkLoggerProvider::builder()
+            .with_log_processor(RedactingLogProcessor::new(
+                SimpleLogProcessor::new(exporter.clone()),
+                rules(RedactionMode::Redact),
+            ))
+            .build();
+
+        let logger = provider.logger("test");
+        let mut record = logger.create_log_record();
+        record.set_severity_number(Severity::Info);
+        record.set_body(format!("Password reset for {EMAIL}").into());
+        record.add_attribute("enduser.id", 4242);
+        record.add_attribute("url.path", "/reset");
+        logger.emit(record);
+
+        // Records without anything to redact are exported as is.
+        let mut record = logger.create_log_record();
+        record.set_body("Handling request".into());
+        logger.emit(record);
+
+        let logs = exporter.get_emitted_logs().unwrap();
+        let exported = format!("{logs:?}");
+        assert!(!exported.contains(EMAIL));
+        assert!(!exported.contains("4242"));
+
+        let record = &logs[0].record;
+        assert_eq!(record.severity_number(), Some(Severity::Info));
+        assert_eq!(
+            record.body(),
+            Some(&AnyValue::from("Password reset for [REDACTED]".to_owned()))
+        );
+        let attributes: Vec<_> = record.attributes_iter().cloned().collect();
+        assert_eq!(
+            attributes,
+            vec![
+                (Key::new("enduser.id"), AnyValue::from(REDACTED.to_owned())),
+                (Key::new("url.path"), AnyValue::from("/reset".to_owned())),
+            ]
+        );
+        assert_eq!(
+            logs[1].record.body(),
+            Some(&AnyValue::from("Handling request".to_owned()))
+        );
+    }
+}
@@ -0,0 +1,276 @@
+use axum::extract::{Request, State};
+use axum::http::{HeaderName, HeaderValue};
+use axum::middleware::Next;
+use axum::response::Response;
+use opentelemetry::baggage::{Baggage, BaggageExt};
+use opentelemetry::logs::LogRecord;
+use opentelemetry::{Context, InstrumentationScope, StringValue};
+use opentelemetry_sdk::error::OTelSdkResult;
+use opentelemetry_sdk::logs::{LogProcessor, SdkLogRecord};
+use opentelemetry_sdk::resource::Resource;
+use rand::RngExt;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+pub static X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+// Longer IDs, or IDs with spaces or control characters, are replaced rather than recorded.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Default of `BAGGAGE_KEYS`.
+pub const DEFAULT_BAGGAGE_KEYS: [&str; 2] = ["tenant", "user.tier"];
+pub const DEFAULT_BAGGAGE_MAX_ENTRIES: usize = 8;
+pub const DEFAULT_BAGGAGE_MAX_VALUE_LEN: usize = 128;
+
+/// Baggage entries recorded on the server spans and the log records. The baggage is set by the
+/// callers, so only the entries named in `keys` are recorded, at most `max_entries` of them, and
+/// values longer than `max_value_len` bytes are left out. The whole baggage is still propagated
+/// to the downstream services.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BaggageRules {
+    pub keys: Vec<String>,
+    pub max_entries: usize,
+    pub max_value_len: usize,
+}
+
+impl Default for BaggageRules {
+    fn default() -> Self {
+        BaggageRules {
+            keys: DEFAULT_BAGGAGE_KEYS.map(str::to_owned).to_vec(),
+            max_entries: DEFAULT_BAGGAGE_MAX_ENTRIES,
+            max_value_len: DEFAULT_BAGGAGE_MAX_VALUE_LEN,
+        }
+    }
+}
+
+impl BaggageRules {
+    /// The entries of `baggage` to record, in the order of `keys`.
+    pub fn entries<'a>(
+        &'a self,
+        baggage: &'a Baggage,
+    ) -> impl Iterator<Item = (&'a str, &'a StringValue)> {
+        self.keys
+            .iter()
+            .filter_map(|key| Some((key.as_str(), baggage.get(key)?)))
+            .filter(|(_, value)| value.as_str().len() <= self.max_value_len)
+            .take(self.max_entries)
+    }
+}
+
+/// ID of the request, from the `x-request-id` header of the client or generated.
+/// Available to the handlers as a request extension.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    // The client's ID is kept when it is printable ASCII, so that it can be logged safely.
+    fn from_request(request: &Request) -> Self {
+        request
+            .headers()
+            .get(&X_REQUEST_ID)
+            .and_then(|value| value.to_str().ok())
+            .filter(|id| {
+                !id.is_empty()
+                    && id.len() <= MAX_REQUEST_ID_LEN
+                    && id.bytes().all(|byte| byte.is_ascii_graphic())
+            })
+            .map(|id| RequestId(id.to_owned()))
+            .unwrap_or_else(RequestId::generate)
+    }
+
+    /// Random UUID (version 4).
+    fn generate() -> Self {
+        let bits: u128 = rand::rng().random();
+        // Version 4 and variant `10` bits.
+        let bits = (bits & !(0xf << 76)) | (0x4 << 76);
+        let bits = (bits & !(0x3 << 62)) | (0x2 << 62);
+        let hex = format!("{bits:032x}");
+        RequestId(format!(
+            "{}-{}-{}-{}-{}",
+            &hex[..8],
+            &hex[8..12],
+            &hex[12..16],
+            &hex[16..20],
+            &hex[20..]
+        ))
+    }
+}
+
+/// Middleware linking a request to its telemetry, to be installed inside `trace_http_request`:
+///
+/// - the `x-request-id` of the request, or a generated one, is recorded on the server span as
+///   `request.id` and returned in the `x-request-id` header of the response,
+/// - the W3C Baggage entries propagated by the caller (e.g. `tenant=acme,user.tier=gold`) and
+///   allowed by `BaggageRules` are recorded on the server span as `baggage.<key>` attributes.
+///   They also reach the log records through `BaggageLogProcessor`, and the whole baggage reaches
+///   the downstream services through the global propagator.
+pub async fn propagate_request_context(
+    State(baggage): State<Arc<BaggageRules>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let request_id = RequestId::from_request(&request);
+
+    let span = Span::current();
+    span.record("request.id", request_id.0.as_str());
+    for (key, value) in baggage.entries(span.context().baggage()) {
+        span.set_attribute(format!("baggage.{key}"), value.clone());
+    }
+
+    let header = HeaderValue::from_str(&request_id.0);
+    request.extensions_mut().insert(request_id);
+    let mut response = next.run(request).await;
+    if let Ok(header) = header {
+        response.headers_mut().insert(X_REQUEST_ID.clone(), header);
+    }
+    response
+}
+
+/// Log processor adding the baggage entries of the current context allowed by `BaggageRules` to
+/// each log record, as `baggage.<key>` attributes. The current context is the one of the span the
+/// record is emitted in.
+#[derive(Debug)]
+pub struct BaggageLogProcessor<P> {
+    inner: P,
+    rules: Arc<BaggageRules>,
+}
+
+impl<P: LogProcessor> BaggageLogProcessor<P> {
+    pub fn new(inner: P, rules: Arc<BaggageRules>) -> Self {
+        BaggageLogProcessor { inner, rules }
+    }
+}
+
+impl<P: LogProcessor> LogProcessor for BaggageLogProcessor<P> {
+    fn emit(&self, record: &mut SdkLogRecord, instrumentation: &InstrumentationScope) {
+        Context::map_current(|cx| {
+            for (key, value) in self.rules.entries(cx.baggage()) {
+                record.add_attribute(format!("baggage.{key}"), value.to_string());
+            }
+        });
+        self.inner.emit(record, instrumentation);
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.inner.force_flush()
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        self.inner.shutdown_with_timeout(timeout)
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.inner.set_resource(resource);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_app::{attribute, TestApp};
+    use axum::body::Body;
+    use opentelemetry::logs::{AnyValue, Logger, LoggerProvider};
+    use opentelemetry::{Key, KeyValue};
+    use opentelemetry_sdk::logs::{InMemoryLogExporter, SdkLoggerProvider, SimpleLogProcessor};
+
+    #[tokio::test]
+    async fn test_request_id_and_baggage_are_recorded() {
+        let app = TestApp::new();
+        let request = |headers: &[(&str, &str)]| {
+            let request = headers
+                .iter()
+                .fold(Request::get("/livez"), |request, (name, value)| {
+                    request.header(*name, *value)
+                });
+            app.request(request.body(Body::empty()).unwrap())
+        };
+
+        let response = request(&[
+            ("x-request-id", "req-42"),
+            ("baggage", "tenant=acme,user.tier=gold,session=abc"),
+        ])
+        .await;
+        assert_eq!(response.headers()["x-request-id"], "req-42");
+        let span = app.span("GET /livez");
+        assert_eq!(attribute(&span, "request.id"), Some("req-42".into()));
+        assert_eq!(attribute(&span, "baggage.tenant"), Some("acme".into()));
+        assert_eq!(attribute(&span, "baggage.user.tier"), Some("gold".into()));
+        // Not in `BAGGAGE_KEYS`.
+        assert_eq!(attribute(&span, "baggage.session"), None);
+
+        // Without a usable ID, one is generated.
+        let response = request(&[("x-request-id", "not a valid id")]).await;
+        let generated = response.headers()["x-request-id"]
+            .to_str()
+            .unwrap()
+            .to_owned();
+        assert_eq!(generated.len(), 36);
+        assert_eq!(&generated[14..15], "4");
+        let span = app.span("GET /livez");
+        assert_eq!(attribute(&span, "request.id"), Some(generated.into()));
+        assert_eq!(attribute(&span, "baggage.tenant"), None);
+    }
+
+    #[test]
+    fn test_baggage_is_added_to_log_records() {
+        let exporter = InMemoryLogExporter::default();
+        let provider = SdkLoggerProvider::builder()
+            .with_log_processor(BaggageLogProcessor::new(
+                SimpleLogProcessor::new(exporter.clone()),
+                Arc::new(BaggageRules::default()),
+            ))
+            .build();
+        let logger = provider.logger("test");
+
+        let _guard = Context::current_with_baggage([
+            KeyValue::new("tenant", "acme"),
+            KeyValue::new("session", "abc"),
+        ])
+        .attach();
+        let mut record = logger.create_log_record();
+        record.set_body("Checking health".into());
+        logger.emit(record);
+
+        let logs = exporter.get_emitted_logs().unwrap();
+        let attributes: Vec<_> = logs[0].record.attributes_iter().cloned().collect();
+        assert_eq!(
+            attributes,
+            vec![(
+                Key::new("baggage.tenant"),
+                AnyValue::from("acme".to_owned())
+            )]
+        );
+    }
+
+    #[test]
+    fn test_baggage_rules() {
+        let baggage = Baggage::from_iter([
+            KeyValue::new("tenant", "acme"),
+            KeyValue::new("user.tier", "gold"),
+            KeyValue::new("session", "abc"),
+            KeyValue::new("region", "x".repeat(200)),
+        ]);
+        let entries = |rules: &BaggageRules| -> Vec<_> {
+            rules
+                .entries(&baggage)
+                .map(|(key, value)| (key.to_owned(), value.to_string()))
+                .collect()
+        };
+        let entry = |key: &str, value: &str| (key.to_owned(), value.to_owned());
+
+        let mut rules = BaggageRules::default();
+        assert_eq!(
+            entries(&rules),
+            vec![entry("tenant", "acme"), entry("user.tier", "gold")]
+        );
+
+        // Entries beyond the limits are left out.
+        rules.keys = vec!["region".into(), "session".into(), "tenant".into()];
+        rules.max_entries = 1;
+        assert_eq!(entries(&rules), vec![entry("session", "abc")]);
+        rules.max_value_len = 200;
+        assert_eq!(entries(&rules), vec![entry("region", &"x".repeat(200))]);
+    }
+}
@@ -0,0 +1,276 @@
+use crate::attribute::unsigned;
+use crate::telemetry_config::TelemetryConfig;
+use opentelemetry::KeyValue;
+use opentelemetry_sdk::resource::{Resource, ResourceDetector, TelemetryResourceDetector};
+use opentelemetry_semantic_conventions::{
+    attribute::{
+        CONTAINER_ID, DEPLOYMENT_ENVIRONMENT_NAME, HOST_ARCH, HOST_NAME, OS_TYPE,
+        PROCESS_EXECUTABLE_NAME, PROCESS_EXECUTABLE_PATH, PROCESS_PID, PROCESS_RUNTIME_NAME,
+        SERVICE_VERSION,
+    },
+    SCHEMA_URL,
+};
+
+/// Builds the `Resource` describing the service, shared by traces, metrics and logs.
+///
+/// From the lowest to the highest precedence:
+/// 1. detected attributes: SDK, host, OS, process and container,
+/// 2. the defaults of the service: `service.version`,
+/// 3. `OTEL_RESOURCE_ATTRIBUTES`,
+/// 4. `deployment.environment.name`, resolved by `TelemetryConfig` from `DEPLOYMENT_ENVIRONMENT_NAME`
+///    first, then from `OTEL_RESOURCE_ATTRIBUTES`,
+/// 5. `OTEL_SERVICE_NAME`, which always sets `service.name`.
+pub fn resource(config: &TelemetryConfig) -> Resource {
+    build_resource(
+        config,
+        &[
+            Box::new(TelemetryResourceDetector),
+            Box::new(HostResourceDetector),
+            Box::new(OsResourceDetector),
+            Box::new(ProcessResourceDetector),
+            Box::new(ContainerResourceDetector),
+        ],
+    )
+}
+
+fn build_resource(config: &TelemetryConfig, detectors: &[Box<dyn ResourceDetector>]) -> Resource {
+    // `with_schema_url` puts its attributes below the others: only the URL is set with it.
+    Resource::builder_empty()
+        .with_schema_url([], SCHEMA_URL)
+        .with_detectors(detectors)
+        .with_attribute(KeyValue::new(SERVICE_VERSION, env!("CARGO_PKG_VERSION")))
+        .with_attributes(config.resource_attributes.clone())
+        .with_attribute(KeyValue::new(
+            DEPLOYMENT_ENVIRONMENT_NAME,
+            config.environment.clone(),
+        ))
+        .with_service_name(config.service_name.clone())
+        .build()
+}
+
+/// Detects `host.name` and `host.arch`.
+pub struct HostResourceDetector;
+
+impl ResourceDetector for HostResourceDetector {
+    fn detect(&self) -> Resource {
+        let host_name = hostname::get()
+            .ok()
+            .and_then(|name| name.into_string().ok())
+            .map(|name| KeyValue::new(HOST_NAME, name));
+        // Values of the `host.arch` semantic convention, when they differ from Rust's.
+        let arch = match std::env::consts::ARCH {
+            "x86_64" => "amd64",
+            "aarch64" => "arm64",
+            "arm" => "arm32",
+            "powerpc" => "ppc32",
+            "powerpc64" => "ppc64",
+            arch => arch,
+        };
+        Resource::builder_empty()
+            .with_attributes(host_name)
+            .with_attribute(KeyValue::new(HOST_ARCH, arch))
+            .build()
+    }
+}
+
+/// Detects `os.type`.
+pub struct OsResourceDetector;
+
+impl ResourceDetector for OsResourceDetector {
+    fn detect(&self) -> Resource {
+        let os_type = match std::env::consts::OS {
+            "macos" => "darwin",
+            os => os,
+        };
+        Resource::builder_empty()
+            .with_attribute(KeyValue::new(OS_TYPE, os_type))
+            .build()
+    }
+}
+
+/// Detects `process.pid`, `process.executable.name`, `process.executable.path` and `process.runtime.name`.
+pub struct ProcessResourceDetector;
+
+impl ResourceDetector for ProcessResourceDetector {
+    fn detect(&self) -> Resource {
+        let executable = std::env::current_exe().ok();
+        let executable_name = executable
+            .as_ref()
+            .and_then(|path| path.file_name())
+            .map(|name| {
+                KeyValue::new(PROCESS_EXECUTABLE_NAME, name.to_string_lossy().into_owned())
+            });
+        let executable_path = executable
+            .as_ref()
+            .map(|path| KeyValue::new(PROCESS_EXECUTABLE_PATH, path.display().to_string()));
+        Resource::builder_empty()
+            .with_attribute(KeyValue::new(PROCESS_PID, unsigned(std::process::id())))
+            .with_attribute(KeyValue::new(PROCESS_RUNTIME_NAME, "rustc"))
+            .with_attributes(executable_name.into_iter().chain(executable_path))
+            .build()
+    }
+}
+
+/// Detects `container.id` on Linux, from `/proc/self/cgroup` (cgroup v1)
+/// or `/proc/self/mountinfo` (cgroup v2, where the cgroup path is namespaced).
+/// Nothing is detected outside a container.
+pub struct ContainerResourceDetector;
+
+impl ResourceDetector for ContainerResourceDetector {
+    fn detect(&self) -> Resource {
+        let read = |path| std::fs::read_to_string(path).ok();
+        let container_id = read("/proc/self/cgroup")
+            .and_then(|cgroup| container_id_from_cgroup(&cgroup))
+            .or_else(|| {
+                read("/proc/self/mountinfo")
+                    .and_then(|mountinfo| container_id_from_mountinfo(&mountinfo))
+            });
+        Resource::builder_empty()
+            .with_attributes(container_id.map(|id| KeyValue::new(CONTAINER_ID, id)))
+            .build()
+    }
+}
+
+// Lines are `hierarchy-ID:controllers:path`, the container ID being the last segment of the path,
+// e.g. `/docker/<id>` or `/kubepods/burstable/pod<uid>/cri-containerd-<id>.scope`.
+fn container_id_from_cgroup(cgroup: &str) -> Option<String> {
+    cgroup
+        .lines()
+        .filter_map(|line| line.splitn(3, ':').nth(2))
+        .find_map(|path| path.rsplit('/').next().and_then(container_id))
+}
+
+// The container runtime mounts files from its container directory, e.g.
+// `/var/lib/docker/containers/<id>/hostname` or `/var/lib/containerd/.../containers/<id>/...`.
+fn container_id_from_mountinfo(mountinfo: &str) -> Option<String> {
+    mountinfo
+        .lines()
+        .flat_map(str::split_whitespace)
+        .find_map(|path| {
+            let mut segments = path.split('/');
+            segments.find(|segment| *segment == "containers")?;
+            segments.next().and_then(container_id)
+        })
+}
+
+// Container IDs are 64 hexadecimal characters, prefixed by the runtime in systemd scopes
+// (`docker-<id>.scope`, `cri-containerd-<id>.scope`, `crio-<id>.scope`, `libpod-<id>.scope`).
+fn container_id(segment: &str) -> Option<String> {
+    let id = segment.trim_end_matches(".scope").rsplit('-').next()?;
+    (id.len() == 64 && id.bytes().all(|byte| byte.is_ascii_hexdigit())).then(|| id.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::{Key, Value};
+    use opentelemetry_semantic_conventions::attribute::SERVICE_NAME;
+    use std::collections::HashMap;
+
+    const ID: &str = "3c9b8b2cf4e1d4b1a6e0e2f9a5c7d8e9f0a1b2c3d4e5f60718293a4b5c6d7e8f";
+
+    struct StaticDetector(Vec<KeyValue>);
+
+    impl ResourceDetector for StaticDetector {
+        fn detect(&self) -> Resource {
+            Resource::builder_empty()
+                .with_attributes(self.0.clone())
+                .build()
+        }
+    }
+
+    fn get(resource: &Resource, key: &'static str) -> Option<Value> {
+        resource.get(&Key::from_static_str(key))
+    }
+
+    #[test]
+    fn test_precedence() {
+        let detector = StaticDetector(vec![
+            KeyValue::new(HOST_NAME, "detected-host"),
+            KeyValue::new(OS_TYPE, "linux"),
+            KeyValue::new(SERVICE_VERSION, "0.0.0"),
+            KeyValue::new(SERVICE_NAME, "detected-service"),
+        ]);
+        let vars = HashMap::from([
+            (
+                "OTEL_RESOURCE_ATTRIBUTES",
+                "host.name=env-host,deployment.environment.name=prod,service.name=env-service",
+            ),
+            ("OTEL_SERVICE_NAME", "checkout"),
+        ]);
+        let config =
+            TelemetryConfig::from_lookup(|name| vars.get(name).map(|value| value.to_string()))
+                .unwrap();
+
+        let resource = build_resource(&config, &[Box::new(detector)]);
+        // Detected, nothing overrides it.
+        assert_eq!(get(&resource, OS_TYPE), Some("linux".into()));
+        assert_eq!(resource.schema_url(), Some(SCHEMA_URL));
+        // The defaults of the service override the detected attributes...
+        assert_eq!(
+            get(&resource, SERVICE_VERSION),
+            Some(env!("CARGO_PKG_VERSION").into())
+        );
+        // ...`OTEL_RESOURCE_ATTRIBUTES` overrides both...
+        assert_eq!(get(&resource, HOST_NAME), Some("env-host".into()));
+        assert_eq!(
+            get(&resource, DEPLOYMENT_ENVIRONMENT_NAME),
+            Some("prod".into())
+        );
+        // ...and `OTEL_SERVICE_NAME` overrides everything.
+        assert_eq!(get(&resource, SERVICE_NAME), Some("checkout".into()));
+
+        // `DEPLOYMENT_ENVIRONMENT_NAME` (e.g. from `--environment`) wins over the attributes.
+        let config = TelemetryConfig::from_lookup(|name| match name {
+            "DEPLOYMENT_ENVIRONMENT_NAME" => Some("staging".to_owned()),
+            name => vars.get(name).map(|value| value.to_string()),
+        })
+        .unwrap();
+        let resource = build_resource(&config, &[]);
+        assert_eq!(
+            get(&resource, DEPLOYMENT_ENVIRONMENT_NAME),
+            Some("staging".into())
+        );
+    }
+
+    #[test]
+    fn test_process_detector() {
+        let resource = ProcessResourceDetector.detect();
+        assert_eq!(
+            get(&resource, PROCESS_PID),
+            Some(i64::from(std::process::id()).into())
+        );
+        assert!(get(&resource, PROCESS_EXECUTABLE_NAME).is_some());
+    }
+
+    #[test]
+    fn test_container_id_from_cgroup() {
+        let docker = format!("12:devices:/docker/{ID}\n11:cpu:/docker/{ID}\n");
+        assert_eq!(container_id_from_cgroup(&docker).as_deref(), Some(ID));
+
+        let kubernetes = format!(
+            "0::/kubepods.slice/kubepods-burstable.slice/kubepods-burstable-pod1234.slice/cri-containerd-{ID}.scope\n"
+        );
+        assert_eq!(container_id_from_cgroup(&kubernetes).as_deref(), Some(ID));
+
+        // cgroup v2 in a cgroup namespace, or no container at all.
+        assert_eq!(container_id_from_cgroup("0::/\n"), None);
+        assert_eq!(
+            container_id_from_cgroup("0::/user.slice/session-2.scope\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_container_id_from_mountinfo() {
+        let mountinfo = format!(
+            "1419 1418 0:85 / /proc rw,nosuid - proc proc rw\n\
+             1440 1417 254:1 /var/lib/docker/containers/{ID}/hostname /etc/hostname rw,relatime - ext4 /dev/vda1 rw\n"
+        );
+        assert_eq!(container_id_from_mountinfo(&mountinfo).as_deref(), Some(ID));
+        assert_eq!(
+            container_id_from_mountinfo("1419 1418 0:85 / /proc rw,nosuid - proc proc rw\n"),
+            None
+        );
+    }
+}
@@ -0,0 +1,242 @@
+use crate::attribute::unsigned;
+use opentelemetry::metrics::Meter;
+use opentelemetry::KeyValue;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::runtime::RuntimeMetrics;
+
+const WORKER: &str = "tokio.worker";
+
+/// Registers observable instruments reporting the state of the Tokio runtime: they are read at
+/// each collection of the meter provider, so they cost nothing between two exports.
+///
+/// Poll counts, local queue depths, blocking pool and spawned tasks are only available when the
+/// service is built with `RUSTFLAGS="--cfg tokio_unstable"`.
+pub fn register_runtime_metrics(meter: &Meter, runtime: RuntimeMetrics) {
+    let metrics = runtime.clone();
+    meter
+        .u64_observable_gauge("tokio.runtime.workers")
+        .with_description("Number of worker threads of the runtime.")
+        .with_unit("{thread}")
+        .with_callback(move |observer| observer.observe(metrics.num_workers() as u64, &[]))
+        .build();
+    let metrics = runtime.clone();
+    meter
+        .u64_observable_gauge("tokio.runtime.alive_tasks")
+        .with_description("Number of tasks spawned and not yet completed.")
+        .with_unit("{task}")
+        .with_callback(move |observer| observer.observe(metrics.num_alive_tasks() as u64, &[]))
+        .build();
+    let metrics = runtime.clone();
+    meter
+        .u64_observable_gauge("tokio.runtime.global_queue_depth")
+        .with_description("Number of tasks waiting in the global queue of the runtime.")
+        .with_unit("{task}")
+        .with_callback(move |observer| observer.observe(metrics.global_queue_depth() as u64, &[]))
+        .build();
+
+    // The ratio is computed over the time elapsed since the previous collection.
+    let busy = Mutex::new(BusyTime::new(&runtime));
+    let metrics = runtime.clone();
+    meter
+        .f64_observable_gauge("tokio.runtime.worker.busy_ratio")
+        .with_description(
+            "Share of the time a worker spent polling tasks since the previous collection.",
+        )
+        .with_unit("1")
+        .with_callback(move |observer| {
+            let ratios = busy.lock().unwrap().ratios(&metrics);
+            for (worker, ratio) in ratios.into_iter().enumerate() {
+                observer.observe(ratio, &[worker_attribute(worker)]);
+            }
+        })
+        .build();
+    let metrics = runtime.clone();
+    meter
+        .u64_observable_counter("tokio.runtime.worker.park_count")
+        .with_description("Number of times a worker parked, having no task to run.")
+        .with_unit("{park}")
+        .with_callback(move |observer| {
+            for worker in 0..metrics.num_workers() {
+                observer.observe(
+                    metrics.worker_park_count(worker),
+                    &[worker_attribute(worker)],
+                );
+            }
+        })
+        .build();
+
+    #[cfg(tokio_unstable)]
+    register_unstable_metrics(meter, runtime);
+}
+
+#[cfg(tokio_unstable)]
+fn register_unstable_metrics(meter: &Meter, runtime: RuntimeMetrics) {
+    let metrics = runtime.clone();
+    meter
+        .u64_observable_counter("tokio.runtime.worker.poll_count")
+        .with_description("Number of tasks polled by a worker.")
+        .with_unit("{poll}")
+        .with_callback(move |observer| {
+            for worker in 0..metrics.num_workers() {
+                observer.observe(
+                    metrics.worker_poll_count(worker),
+                    &[worker_attribute(worker)],
+                );
+            }
+        })
+        .build();
+    let metrics = runtime.clone();
+    meter
+        .u64_observable_gauge("tokio.runtime.worker.local_queue_depth")
+        .with_description("Number of tasks waiting in the local queue of a worker.")
+        .with_unit("{task}")
+        .with_callback(move |observer| {
+            for worker in 0..metrics.num_workers() {
+                observer.observe(
+                    metrics.worker_local_queue_depth(worker) as u64,
+                    &[worker_attribute(worker)],
+                );
+            }
+        })
+        .build();
+    let metrics = runtime.clone();
+    meter
+        .u64_observable_gauge("tokio.runtime.blocking_threads")
+        .with_description("Number of threads of the blocking pool, by state.")
+        .with_unit("{thread}")
+        .with_callback(move |observer| {
+            let total = metrics.num_blocking_threads();
+            let idle = metrics.num_idle_blocking_threads();
+            observer.observe(
+                (total - idle) as u64,
+                &[KeyValue::new("tokio.thread.state", "busy")],
+            );
+            observer.observe(idle as u64, &[KeyValue::new("tokio.thread.state", "idle")]);
+        })
+        .build();
+    let metrics = runtime.clone();
+    meter
+        .u64_observable_gauge("tokio.runtime.blocking_queue_depth")
+        .with_description("Number of tasks waiting for a thread of the blocking pool.")
+        .with_unit("{task}")
+        .with_callback(move |observer| observer.observe(metrics.blocking_queue_depth() as u64, &[]))
+        .build();
+    meter
+        .u64_observable_counter("tokio.runtime.spawned_tasks")
+        .with_description("Number of tasks spawned since the runtime started.")
+        .with_unit("{task}")
+        .with_callback(move |observer| observer.observe(runtime.spawned_tasks_count(), &[]))
+        .build();
+}
+
+fn worker_attribute(worker: usize) -> KeyValue {
+    KeyValue::new(WORKER, unsigned(worker))
+}
+
+/// Busy time of the workers at the previous collection.
+struct BusyTime {
+    at: Instant,
+    busy: Vec<Duration>,
+}
+
+impl BusyTime {
+    fn new(metrics: &RuntimeMetrics) -> Self {
+        BusyTime {
+            at: Instant::now(),
+            busy: Self::read(metrics),
+        }
+    }
+
+    fn read(metrics: &RuntimeMetrics) -> Vec<Duration> {
+        (0..metrics.num_workers())
+            .map(|worker| metrics.worker_total_busy_duration(worker))
+            .collect()
+    }
+
+    fn ratios(&mut self, metrics: &RuntimeMetrics) -> Vec<f64> {
+        let previous = std::mem::replace(self, Self::new(metrics));
+        let elapsed = self.at.duration_since(previous.at).as_secs_f64();
+        self.busy
+            .iter()
+            .zip(&previous.busy)
+            .map(|(busy, previous)| {
+                if elapsed > 0.0 {
+                    (busy.saturating_sub(*previous).as_secs_f64() / elapsed).min(1.0)
+                } else {
+                    0.0
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::metrics::MeterProvider;
+    use opentelemetry_sdk::metrics::data::{AggregatedMetrics, MetricData};
+    use opentelemetry_sdk::metrics::{InMemoryMetricExporter, PeriodicReader, SdkMeterProvider};
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_runtime_metrics_are_observed() {
+        let exporter = InMemoryMetricExporter::default();
+        let provider = SdkMeterProvider::builder()
+            .with_reader(PeriodicReader::builder(exporter.clone()).build())
+            .build();
+        register_runtime_metrics(
+            &provider.meter("test"),
+            tokio::runtime::Handle::current().metrics(),
+        );
+
+        // Keeps a worker busy for a while.
+        tokio::spawn(async {
+            let start = Instant::now();
+            while start.elapsed() < Duration::from_millis(50) {
+                std::hint::spin_loop();
+            }
+        })
+        .await
+        .unwrap();
+        provider.force_flush().unwrap();
+
+        let metrics = exporter.get_finished_metrics().unwrap();
+        let metrics: Vec<_> = metrics
+            .iter()
+            .flat_map(|resource| resource.scope_metrics())
+            .flat_map(|scope| scope.metrics())
+            .collect();
+        let metric = |name: &str| {
+            metrics
+                .iter()
+                .find(|metric| metric.name() == name)
+                .unwrap_or_else(|| panic!("{name} was not exported"))
+                .data()
+        };
+
+        let AggregatedMetrics::U64(MetricData::Gauge(workers)) = metric("tokio.runtime.workers")
+        else {
+            panic!("expected a gauge");
+        };
+        assert_eq!(workers.data_points().next().unwrap().value(), 2);
+
+        let AggregatedMetrics::F64(MetricData::Gauge(busy)) =
+            metric("tokio.runtime.worker.busy_ratio")
+        else {
+            panic!("expected a gauge");
+        };
+        let ratios: Vec<f64> = busy.data_points().map(|point| point.value()).collect();
+        assert_eq!(ratios.len(), 2);
+        assert!(ratios.iter().all(|ratio| (0.0..=1.0).contains(ratio)));
+        // The busy loop ran on one of the workers.
+        assert!(ratios.iter().any(|ratio| *ratio > 0.0));
+
+        for name in [
+            "tokio.runtime.alive_tasks",
+            "tokio.runtime.global_queue_depth",
+            "tokio.runtime.worker.park_count",
+        ] {
+            metric(name);
+        }
+    }
+}
@@ -0,0 +1,314 @@
+use opentelemetry::trace::{Link, SpanContext, SpanKind, Status, TraceContextExt, TraceId};
+use opentelemetry::{Context, KeyValue, Value};
+use opentelemetry_sdk::error::OTelSdkResult;
+use opentelemetry_sdk::resource::Resource;
+use opentelemetry_sdk::trace::{
+    Sampler, SamplingDecision, SamplingResult, ShouldSample, Span, SpanData, SpanProcessor,
+};
+use opentelemetry_semantic_conventions::attribute::{HTTP_RESPONSE_STATUS_CODE, HTTP_ROUTE};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Sampling rule overriding the defaults for the requests of one route (e.g. `/health`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct RouteRule {
+    pub route: String,
+    pub ratio: f64,
+    pub slow_threshold: Option<Duration>,
+}
+
+/// Rules of the rule-based sampling strategy:
+///
+/// - traces are sampled up-front at the ratio of their route (`default_ratio` for unlisted routes),
+/// - spans ending with an error status or a 4xx/5xx `http.response.status_code` are always kept,
+/// - spans lasting longer than the slow threshold of their route are always kept.
+///
+/// The last two rules apply to each span on its own, not to its trace: a span kept that way is
+/// exported without its unsampled parent and children, and shows as an orphan in the backends.
+/// The tail-based strategy keeps whole traces instead.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SamplingRules {
+    pub default_ratio: f64,
+    pub slow_threshold: Duration,
+    pub routes: Vec<RouteRule>,
+}
+
+impl SamplingRules {
+    fn route_rule(&self, route: Option<&str>) -> Option<&RouteRule> {
+        route.and_then(|route| self.routes.iter().find(|rule| rule.route == route))
+    }
+
+    fn ratio(&self, route: Option<&str>) -> f64 {
+        self.route_rule(route)
+            .map_or(self.default_ratio, |rule| rule.ratio)
+    }
+
+    fn slow_threshold(&self, route: Option<&str>) -> Duration {
+        self.route_rule(route)
+            .and_then(|rule| rule.slow_threshold)
+            .unwrap_or(self.slow_threshold)
+    }
+
+    /// Whether a span that was not sampled up-front must be exported anyway.
+    fn must_keep(&self, span: &SpanData) -> bool {
+        if is_error(span) {
+            return true;
+        }
+        let duration = span
+            .end_time
+            .duration_since(span.start_time)
+            .unwrap_or_default();
+        duration >= self.slow_threshold(route_of(&span.attributes))
+    }
+}
+
+// Unlike the span status, which only 5xx responses set to error on server spans, 4xx responses
+// count as errors here: 401 and 403 are outcomes worth keeping.
+pub(crate) fn is_error(span: &SpanData) -> bool {
+    matches!(span.status, Status::Error { .. })
+        || span.attributes.iter().any(|kv| {
+            kv.key.as_str() == HTTP_RESPONSE_STATUS_CODE
+                && matches!(kv.value, Value::I64(status) if status >= 400)
+        })
+}
+
+fn route_of(attributes: &[KeyValue]) -> Option<&str> {
+    attributes
+        .iter()
+        .find(|kv| kv.key.as_str() == HTTP_ROUTE)
+        .and_then(|kv| match &kv.value {
+            Value::String(route) => Some(route.as_str()),
+            _ => None,
+        })
+}
+
+/// Head sampler of the rule-based strategy.
+///
+/// Root spans are sampled at the ratio of their `http.route` attribute, and children follow their
+/// parent. Spans that are not sampled are still recorded (`RecordOnly`) so that
+/// `RuleBasedSpanProcessor` can keep them if they turn out to be errors or slow.
+#[derive(Debug, Clone)]
+pub struct RuleBasedSampler {
+    rules: Arc<SamplingRules>,
+}
+
+impl RuleBasedSampler {
+    pub fn new(rules: Arc<SamplingRules>) -> Self {
+        RuleBasedSampler { rules }
+    }
+}
+
+impl ShouldSample for RuleBasedSampler {
+    fn should_sample(
+        &self,
+        parent_context: Option<&Context>,
+        trace_id: TraceId,
+        name: &str,
+        span_kind: &SpanKind,
+        attributes: &[KeyValue],
+        links: &[Link],
+    ) -> SamplingResult {
+        let parent = parent_context
+            .filter(|cx| cx.has_active_span())
+            .map(|cx| cx.span().span_context().clone());
+
+        let decision = match &parent {
+            Some(parent) if parent.is_sampled() => SamplingDecision::RecordAndSample,
+            Some(_) => SamplingDecision::RecordOnly,
+            None => {
+                let ratio = self.rules.ratio(route_of(attributes));
+                let result = Sampler::TraceIdRatioBased(ratio)
+                    .should_sample(None, trace_id, name, span_kind, attributes, links);
+                match result.decision {
+                    SamplingDecision::RecordAndSample => SamplingDecision::RecordAndSample,
+                    _ => SamplingDecision::RecordOnly,
+                }
+            }
+        };
+
+        SamplingResult {
+            decision,
+            attributes: Vec::new(),
+            trace_state: parent
+                .map(|parent| parent.trace_state().clone())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// Span processor of the rule-based strategy, wrapping the exporting processor.
+///
+/// Sampled spans are forwarded as is. The others are dropped, unless `SamplingRules` says they
+/// must be kept: they are then marked as sampled and forwarded. The decision is taken per span,
+/// as it ends: the rest of its trace has already been dropped, or is yet to end.
+#[derive(Debug)]
+pub struct RuleBasedSpanProcessor<P> {
+    inner: P,
+    rules: Arc<SamplingRules>,
+}
+
+impl<P: SpanProcessor> RuleBasedSpanProcessor<P> {
+    pub fn new(inner: P, rules: Arc<SamplingRules>) -> Self {
+        RuleBasedSpanProcessor { inner, rules }
+    }
+}
+
+impl<P: SpanProcessor> SpanProcessor for RuleBasedSpanProcessor<P> {
+    fn on_start(&self, span: &mut Span, cx: &Context) {
+        self.inner.on_start(span, cx);
+    }
+
+    fn on_end(&self, mut span: SpanData) {
+        if !span.span_context.is_sampled() {
+            if !self.rules.must_keep(&span) {
+                return;
+            }
+            let context = &span.span_context;
+            span.span_context = SpanContext::new(
+                context.trace_id(),
+                context.span_id(),
+                context.trace_flags().with_sampled(true),
+                context.is_remote(),
+                context.trace_state().clone(),
+            );
+        }
+        self.inner.on_end(span);
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.inner.force_flush()
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        self.inner.shutdown_with_timeout(timeout)
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.inner.set_resource(resource);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_app::RecordingProcessor;
+    use opentelemetry::trace::{SpanId, TraceFlags, TraceState};
+    use opentelemetry_sdk::trace::{SpanEvents, SpanLinks};
+    use std::time::SystemTime;
+
+    fn rules() -> Arc<SamplingRules> {
+        Arc::new(SamplingRules {
+            default_ratio: 1.0,
+            slow_threshold: Duration::from_millis(500),
+            routes: vec![RouteRule {
+                route: "/health".to_owned(),
+                ratio: 0.0,
+                slow_threshold: Some(Duration::from_millis(100)),
+            }],
+        })
+    }
+
+    fn unsampled_span(route: &str, duration: Duration, status: Status) -> SpanData {
+        let start_time = SystemTime::now();
+        SpanData {
+            span_context: SpanContext::new(
+                TraceId::from(1),
+                SpanId::from(1),
+                TraceFlags::default(),
+                false,
+                TraceState::default(),
+            ),
+            parent_span_id: SpanId::INVALID,
+            parent_span_is_remote: false,
+            span_kind: SpanKind::Server,
+            name: "GET /health".into(),
+            start_time,
+            end_time: start_time + duration,
+            attributes: vec![KeyValue::new(HTTP_ROUTE, route.to_owned())],
+            dropped_attributes_count: 0,
+            events: SpanEvents::default(),
+            links: SpanLinks::default(),
+            status,
+            instrumentation_scope: Default::default(),
+        }
+    }
+
+    fn sample_root(route: &str) -> SamplingDecision {
+        RuleBasedSampler::new(rules())
+            .should_sample(
+                None,
+                TraceId::from(42),
+                "GET",
+                &SpanKind::Server,
+                &[KeyValue::new(HTTP_ROUTE, route.to_owned())],
+                &[],
+            )
+            .decision
+    }
+
+    #[test]
+    fn test_root_span_ratio_per_route() {
+        assert_eq!(sample_root("/orders"), SamplingDecision::RecordAndSample);
+        assert_eq!(sample_root("/health"), SamplingDecision::RecordOnly);
+    }
+
+    #[test]
+    fn test_child_span_follows_parent() {
+        let parent = SpanContext::new(
+            TraceId::from(42),
+            SpanId::from(7),
+            TraceFlags::SAMPLED,
+            true,
+            TraceState::default(),
+        );
+        let cx = Context::new().with_remote_span_context(parent);
+        let result = RuleBasedSampler::new(rules()).should_sample(
+            Some(&cx),
+            TraceId::from(42),
+            "health_handler",
+            &SpanKind::Internal,
+            &[KeyValue::new(HTTP_ROUTE, "/health")],
+            &[],
+        );
+        assert_eq!(result.decision, SamplingDecision::RecordAndSample);
+    }
+
+    #[test]
+    fn test_processor_keeps_errors_and_slow_spans_only() {
+        let recorder = RecordingProcessor::default();
+        let processor = RuleBasedSpanProcessor::new(recorder.clone(), rules());
+
+        processor.on_end(unsampled_span(
+            "/health",
+            Duration::from_millis(10),
+            Status::Unset,
+        ));
+        processor.on_end(unsampled_span(
+            "/orders",
+            Duration::from_millis(200),
+            Status::Unset,
+        ));
+        processor.on_end(unsampled_span(
+            "/health",
+            Duration::from_millis(10),
+            Status::error("boom"),
+        ));
+        processor.on_end(unsampled_span(
+            "/health",
+            Duration::from_millis(150),
+            Status::Unset,
+        ));
+        // A 4xx response leaves the status of the server span unset.
+        let mut forbidden = unsampled_span("/health", Duration::from_millis(10), Status::Unset);
+        forbidden
+            .attributes
+            .push(KeyValue::new(HTTP_RESPONSE_STATUS_CODE, 403));
+        processor.on_end(forbidden);
+
+        let spans = recorder.spans.lock().unwrap();
+        assert_eq!(spans.len(), 3);
+        assert!(spans.iter().all(|span| span.span_context.is_sampled()));
+        assert!(matches!(spans[0].status, Status::Error { .. }));
+        assert!(is_error(&spans[2]));
+    }
+}
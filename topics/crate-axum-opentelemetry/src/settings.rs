@@ -0,0 +1,711 @@
+use crate::error::StartupError;
+use crate::telemetry_config::{
+    DEFAULT_DEPLOYMENT_ENVIRONMENT, DEFAULT_OTLP_GRPC_ENDPOINT, DEFAULT_OTLP_HTTP_ENDPOINT,
+};
+use clap::Parser;
+use reqwest::Url;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use thiserror::Error;
+
+const DEFAULT_BIND_ADDRESS: &str = "0.0.0.0:3000";
+
+/// Routes which can be left out with `ROUTES`. The probes are always served, and the admin routes
+/// whenever `ADMIN_TOKEN` is set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Route {
+    Health,
+    Chain,
+    Jobs,
+}
+
+impl Route {
+    pub const ALL: [Route; 3] = [Route::Health, Route::Chain, Route::Jobs];
+
+    pub fn path(self) -> &'static str {
+        match self {
+            Route::Health => "/health",
+            Route::Chain => "/chain",
+            Route::Jobs => "/jobs",
+        }
+    }
+}
+
+/// Axum service exporting its traces, metrics and logs with OpenTelemetry.
+///
+/// Settings are read from, by increasing precedence: defaults, the configuration file, the
+/// environment variables and the flags.
+#[derive(Debug, Default, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// TOML file of settings, overridden by the environment variables.
+    #[arg(long, env = "CONFIG_FILE", value_name = "FILE")]
+    pub config: Option<PathBuf>,
+    /// Prints the effective settings and their source, secrets redacted, then exits.
+    #[arg(long)]
+    pub print_config: bool,
+    /// Address the server listens on (`BIND_ADDRESS`).
+    #[arg(long, value_name = "ADDRESS")]
+    pub bind_address: Option<String>,
+    /// Endpoint of the OTLP collector (`OTEL_EXPORTER_OTLP_ENDPOINT`).
+    #[arg(long, value_name = "URL")]
+    pub otlp_endpoint: Option<String>,
+    /// Name of the deployment environment (`DEPLOYMENT_ENVIRONMENT_NAME`).
+    #[arg(long, value_name = "NAME")]
+    pub environment: Option<String>,
+    /// Any other setting, by its environment variable or its key in the configuration file.
+    #[arg(long = "set", value_name = "NAME=VALUE", value_parser = parse_assignment)]
+    pub set: Vec<(String, String)>,
+}
+
+fn parse_assignment(assignment: &str) -> Result<(String, String), String> {
+    assignment
+        .split_once('=')
+        .map(|(name, value)| (name.trim().to_owned(), value.to_owned()))
+        .ok_or_else(|| "expected NAME=VALUE".to_owned())
+}
+
+/// How a value is shown by `--print-config`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Secret {
+    No,
+    Whole,
+    /// `name=value` pairs whose values only are secret, e.g. an `authorization` header.
+    HeaderValues,
+}
+
+struct Setting {
+    name: &'static str,
+    default: Option<&'static str>,
+    secret: Secret,
+}
+
+const fn setting(name: &'static str, default: Option<&'static str>) -> Setting {
+    Setting {
+        name,
+        default,
+        secret: Secret::No,
+    }
+}
+
+/// Every setting of the service, named after its environment variable. The defaults are only
+/// shown by `--print-config`: the parsers of the settings apply their own.
+const SETTINGS: &[Setting] = &[
+    setting("BIND_ADDRESS", Some(DEFAULT_BIND_ADDRESS)),
+    setting("ROUTES", Some("/health,/chain,/jobs")),
+    setting("DOWNSTREAM_URL", None),
+    setting("SHUTDOWN_DRAIN_DELAY_SECS", Some("0")),
+    setting("HEALTH_OUTCOME_WEIGHTS", Some("200=1,401=1,403=1")),
+    // Random when not set, and logged at startup.
+    setting("HEALTH_SEED", None),
+    Setting {
+        name: "ADMIN_TOKEN",
+        default: None,
+        secret: Secret::Whole,
+    },
+    setting(
+        "DEPLOYMENT_ENVIRONMENT_NAME",
+        Some(DEFAULT_DEPLOYMENT_ENVIRONMENT),
+    ),
+    setting("OTEL_EXPORTER_OTLP_PROTOCOL", Some("grpc")),
+    // The default depends on the protocol.
+    setting("OTEL_EXPORTER_OTLP_ENDPOINT", None),
+    Setting {
+        name: "OTEL_EXPORTER_OTLP_HEADERS",
+        default: None,
+        secret: Secret::HeaderValues,
+    },
+    setting("OTEL_EXPORTER_OTLP_COMPRESSION", Some("none")),
+    setting("OTEL_EXPORTER_OTLP_CERTIFICATE", None),
+    setting("OTEL_SERVICE_NAME", Some(env!("CARGO_PKG_NAME"))),
+    setting("OTEL_RESOURCE_ATTRIBUTES", None),
+    setting("OTEL_TRACES_SAMPLER", Some("parentbased_always_on")),
+    setting("OTEL_TRACES_SAMPLER_ARG", None),
+    setting("SAMPLING_SLOW_THRESHOLD_MS", Some("500")),
+    setting("SAMPLING_ROUTE_RULES", None),
+    setting("TAIL_SAMPLING_DECISION_WAIT_MS", Some("5000")),
+    setting("TAIL_SAMPLING_MAX_SPANS", Some("10000")),
+    setting("RUST_LOG", Some("info")),
+    setting("LOG_FORMAT", Some("text")),
+    setting("REDACTION_KEYS", None),
+    setting("REDACTION_PATTERN", None),
+    setting("REDACTION_MODE", Some("redact")),
+    setting("BAGGAGE_KEYS", Some("tenant,user.tier")),
+    setting("BAGGAGE_MAX_ENTRIES", Some("8")),
+    setting("BAGGAGE_MAX_VALUE_LEN", Some("128")),
+    setting("RATE_LIMIT_ROUTES", None),
+    setting("RATE_LIMIT_PER_CLIENT", None),
+    setting("RATE_LIMIT_CLIENT_HEADER", None),
+    setting("CONCURRENCY_LIMIT", None),
+];
+
+/// Error raised when the configuration file or a flag cannot be read, before any value is parsed.
+#[derive(Debug, Error)]
+pub enum SettingsError {
+    #[error("Unable to read the configuration file {path:?}: {reason}")]
+    File { path: PathBuf, reason: String },
+    #[error("Unknown setting {key:?} in {origin}")]
+    Unknown { key: String, origin: Source },
+}
+
+/// Where the value of a setting comes from, from the lowest to the highest precedence.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    Default,
+    File(PathBuf),
+    Env,
+    Cli,
+}
+
+impl std::fmt::Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Source::Default => write!(f, "default"),
+            Source::File(path) => write!(f, "{}", path.display()),
+            Source::Env => write!(f, "environment"),
+            Source::Cli => write!(f, "command line"),
+        }
+    }
+}
+
+/// Value of a setting, with the key that set it in its source.
+#[derive(Debug)]
+struct Entry {
+    key: String,
+    value: String,
+}
+
+/// Values of the settings, layered: defaults, then the configuration file, the environment
+/// variables and the command line, each overriding the previous ones.
+///
+/// The keys of the configuration file are the names of the environment variables in lowercase;
+/// a table prefixes the keys it holds, so that these two files are the same:
+///
+/// ```toml
+/// otel_exporter_otlp_endpoint = "http://collector:4317"
+///
+/// [otel]
+/// exporter_otlp_endpoint = "http://collector:4317"
+/// ```
+#[derive(Debug)]
+pub struct Settings {
+    /// From the lowest to the highest precedence.
+    layers: Vec<(Source, HashMap<&'static str, Entry>)>,
+}
+
+impl Settings {
+    pub fn load(cli: &Cli) -> Result<Self, SettingsError> {
+        let file = match &cli.config {
+            Some(path) => Some((
+                path.as_path(),
+                std::fs::read_to_string(path).map_err(|err| SettingsError::File {
+                    path: path.clone(),
+                    reason: err.to_string(),
+                })?,
+            )),
+            None => None,
+        };
+        Self::from_sources(
+            file.as_ref()
+                .map(|(path, content)| (*path, content.as_str())),
+            |name| std::env::var(name).ok(),
+            cli,
+        )
+    }
+
+    fn from_sources(
+        file: Option<(&Path, &str)>,
+        env: impl Fn(&str) -> Option<String>,
+        cli: &Cli,
+    ) -> Result<Self, SettingsError> {
+        let mut layers = Vec::new();
+        if let Some((path, content)) = file {
+            let table = content
+                .parse::<toml::Table>()
+                .map_err(|err| SettingsError::File {
+                    path: path.to_owned(),
+                    reason: err.to_string(),
+                })?;
+            let mut values = HashMap::new();
+            flatten(&table, "", "", &mut values, path)?;
+            layers.push((Source::File(path.to_owned()), values));
+        }
+
+        // Variables set to an empty string are treated as unset, as required by the specification.
+        let env = SETTINGS
+            .iter()
+            .filter_map(|setting| {
+                let value = env(setting.name).filter(|value| !value.trim().is_empty())?;
+                let key = setting.name.to_owned();
+                Some((setting.name, Entry { key, value }))
+            })
+            .collect();
+        layers.push((Source::Env, env));
+
+        let flags = [
+            ("BIND_ADDRESS", "--bind-address", &cli.bind_address),
+            (
+                "OTEL_EXPORTER_OTLP_ENDPOINT",
+                "--otlp-endpoint",
+                &cli.otlp_endpoint,
+            ),
+            (
+                "DEPLOYMENT_ENVIRONMENT_NAME",
+                "--environment",
+                &cli.environment,
+            ),
+        ];
+        let mut values: HashMap<_, _> = flags
+            .into_iter()
+            .filter_map(|(name, flag, value)| {
+                let key = flag.to_owned();
+                Some((
+                    name,
+                    Entry {
+                        key,
+                        value: value.clone()?,
+                    },
+                ))
+            })
+            .collect();
+        for (key, value) in &cli.set {
+            let entry = Entry {
+                key: format!("--set {key}"),
+                value: value.clone(),
+            };
+            values.insert(known(key, &Source::Cli)?, entry);
+        }
+        layers.push((Source::Cli, values));
+
+        Ok(Settings { layers })
+    }
+
+    /// Value of the setting `name`, from the source of highest precedence which sets it.
+    pub fn get(&self, name: &str) -> Option<String> {
+        self.source_of(name).map(|(value, _)| value.to_owned())
+    }
+
+    fn source_of(&self, name: &str) -> Option<(&str, &Source)> {
+        self.entry_of(name)
+            .map(|(entry, source)| (entry.value.as_str(), source))
+    }
+
+    fn entry_of(&self, name: &str) -> Option<(&Entry, &Source)> {
+        self.layers
+            .iter()
+            .rev()
+            .find_map(|(source, values)| values.get(name).map(|entry| (entry, source)))
+    }
+
+    /// Reads a part of the configuration with `from_lookup`, e.g. `ServiceConfig::from_lookup`.
+    /// An invalid value is reported with the source and the key that set it.
+    pub fn read<T, E: Into<StartupError>>(
+        &self,
+        from_lookup: impl FnOnce(&dyn Fn(&str) -> Option<String>) -> Result<T, E>,
+    ) -> Result<T, StartupError> {
+        from_lookup(&|name| self.get(name)).map_err(|err| {
+            let err = err.into();
+            let name = match &err {
+                StartupError::Config(err) => err.name(),
+                StartupError::InvalidVariable { name, .. } => name,
+                _ => return err,
+            };
+            // The defaults are valid: an invalid value always has a source.
+            let Some((entry, source)) = self.entry_of(name) else {
+                return err;
+            };
+            let origin = match source {
+                Source::Default => return err,
+                Source::File(path) => format!("`{}` in {}", entry.key, path.display()),
+                Source::Env => format!("the environment variable {}", entry.key),
+                Source::Cli => format!("`{}` on the command line", entry.key),
+            };
+            StartupError::InvalidSetting {
+                error: Box::new(err),
+                origin,
+            }
+        })
+    }
+
+    fn default_of(&self, setting: &Setting) -> Option<String> {
+        match setting.name {
+            "OTEL_EXPORTER_OTLP_ENDPOINT" => Some(
+                match self.get("OTEL_EXPORTER_OTLP_PROTOCOL").as_deref() {
+                    Some("http/protobuf") => DEFAULT_OTLP_HTTP_ENDPOINT,
+                    _ => DEFAULT_OTLP_GRPC_ENDPOINT,
+                }
+                .to_owned(),
+            ),
+            "DOWNSTREAM_URL" => Some(ServiceConfig::default_downstream_url(
+                &self
+                    .get("BIND_ADDRESS")
+                    .and_then(|address| address.trim().parse().ok())
+                    .unwrap_or_else(|| DEFAULT_BIND_ADDRESS.parse().unwrap()),
+            )),
+            _ => setting.default.map(str::to_owned),
+        }
+    }
+
+    /// Effective settings, in the format of the configuration file, each followed by its source.
+    /// Secrets are redacted: the output can be shared.
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+        for setting in SETTINGS {
+            let key = setting.name.to_lowercase();
+            let (value, source) = match self.source_of(setting.name) {
+                Some((value, source)) => (value.to_owned(), source.clone()),
+                None => match self.default_of(setting) {
+                    Some(value) => (value, Source::Default),
+                    None => {
+                        let _ = writeln!(output, "# {key} is not set");
+                        continue;
+                    }
+                },
+            };
+            let value = match setting.secret {
+                Secret::No => value,
+                Secret::Whole => "<redacted>".to_owned(),
+                Secret::HeaderValues => value
+                    .split(',')
+                    .map(|pair| match pair.split_once('=') {
+                        Some((name, _)) => format!("{}=<redacted>", name.trim()),
+                        None => "<redacted>".to_owned(),
+                    })
+                    .collect::<Vec<_>>()
+                    .join(","),
+            };
+            let _ = writeln!(output, "{key} = {}  # {source}", toml::Value::String(value));
+        }
+        output
+    }
+}
+
+fn known(key: &str, origin: &Source) -> Result<&'static str, SettingsError> {
+    let name = key.to_uppercase();
+    SETTINGS
+        .iter()
+        .find(|setting| setting.name == name)
+        .map(|setting| setting.name)
+        .ok_or_else(|| SettingsError::Unknown {
+            key: key.to_owned(),
+            origin: origin.clone(),
+        })
+}
+
+// Scalars are taken as written in an environment variable, and arrays as comma-separated lists.
+// The dotted path of each key (e.g. `otel.service_name`) is kept for the error messages.
+fn flatten(
+    table: &toml::Table,
+    prefix: &str,
+    dotted_prefix: &str,
+    values: &mut HashMap<&'static str, Entry>,
+    path: &Path,
+) -> Result<(), SettingsError> {
+    for (key, value) in table {
+        let dotted_key = format!("{dotted_prefix}{key}");
+        let key = format!("{prefix}{key}");
+        if let toml::Value::Table(table) = value {
+            flatten(
+                table,
+                &format!("{key}_"),
+                &format!("{dotted_key}."),
+                values,
+                path,
+            )?;
+            continue;
+        }
+        let invalid = |reason: &str| SettingsError::File {
+            path: path.to_owned(),
+            reason: format!("{key}: {reason}"),
+        };
+        let value = match value {
+            toml::Value::Array(items) => items
+                .iter()
+                .map(|item| scalar(item).ok_or_else(|| invalid("expected a list of scalars")))
+                .collect::<Result<Vec<_>, _>>()?
+                .join(","),
+            value => scalar(value).ok_or_else(|| invalid("expected a scalar or a list"))?,
+        };
+        let name = known(&key, &Source::File(path.to_owned()))?;
+        values.insert(
+            name,
+            Entry {
+                key: dotted_key,
+                value,
+            },
+        );
+    }
+    Ok(())
+}
+
+fn scalar(value: &toml::Value) -> Option<String> {
+    match value {
+        toml::Value::String(value) => Some(value.clone()),
+        toml::Value::Integer(value) => Some(value.to_string()),
+        toml::Value::Float(value) => Some(value.to_string()),
+        toml::Value::Boolean(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+/// Settings of the server itself, beside those of the telemetry and of the limits.
+#[derive(Debug, Clone)]
+pub struct ServiceConfig {
+    pub bind_address: SocketAddr,
+    /// Optional routes served, each once.
+    pub routes: Vec<Route>,
+    /// URL called by the `/chain` route: the `/health` route of the service itself by default,
+    /// making a two-hop trace.
+    pub downstream_url: Url,
+    /// Whether `/readyz` checks the downstream service: only when `DOWNSTREAM_URL` is set, as the
+    /// service itself is no dependency, and its `/health` answers random errors.
+    pub check_downstream: bool,
+    /// Time during which the service keeps serving while reported not ready, once a shutdown
+    /// signal is received: it gives load balancers the time to stop sending requests.
+    pub drain_delay: Duration,
+    /// Token protecting the admin routes, which are only served when it is set.
+    pub admin_token: Option<String>,
+}
+
+impl ServiceConfig {
+    pub fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Result<Self, StartupError> {
+        let lookup = |name: &str| lookup(name).filter(|value| !value.trim().is_empty());
+
+        let bind_address = match lookup("BIND_ADDRESS") {
+            Some(address) => address
+                .trim()
+                .parse()
+                .map_err(|err| invalid("BIND_ADDRESS", &address, err))?,
+            None => DEFAULT_BIND_ADDRESS.parse().unwrap(),
+        };
+
+        let routes = match lookup("ROUTES") {
+            Some(paths) => {
+                let mut routes = Vec::new();
+                for path in paths
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|path| !path.is_empty())
+                {
+                    let route = Route::ALL
+                        .into_iter()
+                        .find(|route| route.path() == path)
+                        .ok_or_else(|| {
+                            let known: Vec<_> = Route::ALL.map(Route::path).to_vec();
+                            invalid(
+                                "ROUTES",
+                                &paths,
+                                format!(
+                                    "unknown route {path:?}, expected some of {}",
+                                    known.join(",")
+                                ),
+                            )
+                        })?;
+                    if !routes.contains(&route) {
+                        routes.push(route);
+                    }
+                }
+                routes
+            }
+            None => Route::ALL.to_vec(),
+        };
+
+        let check_downstream = lookup("DOWNSTREAM_URL").is_some();
+        let downstream_url =
+            lookup("DOWNSTREAM_URL").unwrap_or_else(|| Self::default_downstream_url(&bind_address));
+        let downstream_url = Url::parse(downstream_url.trim())
+            .map_err(|err| invalid("DOWNSTREAM_URL", &downstream_url, err))?;
+
+        let drain_delay = match lookup("SHUTDOWN_DRAIN_DELAY_SECS") {
+            Some(secs) => Duration::from_secs(
+                secs.trim()
+                    .parse()
+                    .map_err(|err| invalid("SHUTDOWN_DRAIN_DELAY_SECS", &secs, err))?,
+            ),
+            None => Duration::ZERO,
+        };
+
+        Ok(ServiceConfig {
+            bind_address,
+            routes,
+            downstream_url,
+            check_downstream,
+            drain_delay,
+            admin_token: lookup("ADMIN_TOKEN"),
+        })
+    }
+
+    fn default_downstream_url(bind_address: &SocketAddr) -> String {
+        format!("http://localhost:{}/health", bind_address.port())
+    }
+}
+
+/// Error of a setting whose `value` cannot be used, shared by the `from_lookup` parsers.
+pub(crate) fn invalid(name: &'static str, value: &str, reason: impl ToString) -> StartupError {
+    StartupError::InvalidVariable {
+        name,
+        value: value.to_owned(),
+        reason: reason.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::health_outcomes::OutcomesConfig;
+    use crate::rate_limit::LimitsConfig;
+    use crate::telemetry_config::TelemetryConfig;
+
+    const FILE: &str = r#"
+bind_address = "127.0.0.1:8080"
+routes = ["/health", "/jobs"]
+deployment_environment_name = "staging"
+
+[otel]
+exporter_otlp_endpoint = "http://file-collector:4317"
+exporter_otlp_headers = "authorization=Bearer%20s3cret,x-tenant=acme"
+service_name = "health-api"
+"#;
+
+    fn settings(env: &[(&str, &str)], args: &[&str]) -> Result<Settings, SettingsError> {
+        let env: HashMap<&str, &str> = env.iter().copied().collect();
+        let cli =
+            Cli::try_parse_from(std::iter::once("service").chain(args.iter().copied())).unwrap();
+        Settings::from_sources(
+            Some((Path::new("service.toml"), FILE)),
+            |name| env.get(name).map(|value| value.to_string()),
+            &cli,
+        )
+    }
+
+    #[test]
+    fn test_sources_are_layered() {
+        let settings = settings(
+            &[
+                ("OTEL_EXPORTER_OTLP_ENDPOINT", "http://env-collector:4317"),
+                ("DEPLOYMENT_ENVIRONMENT_NAME", "prod"),
+                // Empty: unset.
+                ("OTEL_SERVICE_NAME", ""),
+            ],
+            &[
+                "--otlp-endpoint",
+                "http://cli-collector:4317",
+                "--set",
+                "log_format=json",
+            ],
+        )
+        .unwrap();
+
+        // Command line > environment > file.
+        assert_eq!(
+            settings.get("OTEL_EXPORTER_OTLP_ENDPOINT").unwrap(),
+            "http://cli-collector:4317"
+        );
+        assert_eq!(settings.get("DEPLOYMENT_ENVIRONMENT_NAME").unwrap(), "prod");
+        assert_eq!(settings.get("OTEL_SERVICE_NAME").unwrap(), "health-api");
+        assert_eq!(settings.get("LOG_FORMAT").unwrap(), "json");
+        assert_eq!(settings.get("RUST_LOG"), None);
+
+        let service = ServiceConfig::from_lookup(|name| settings.get(name)).unwrap();
+        assert_eq!(service.bind_address, "127.0.0.1:8080".parse().unwrap());
+        assert_eq!(service.routes, [Route::Health, Route::Jobs]);
+        // The default downstream URL follows the port of the server.
+        assert_eq!(
+            service.downstream_url.as_str(),
+            "http://localhost:8080/health"
+        );
+        assert!(!service.check_downstream);
+        let downstream = |name: &str| {
+            (name == "DOWNSTREAM_URL").then(|| "http://inventory:8080/readyz".to_owned())
+        };
+        assert!(
+            ServiceConfig::from_lookup(downstream)
+                .unwrap()
+                .check_downstream
+        );
+        let telemetry = TelemetryConfig::from_lookup(|name| settings.get(name)).unwrap();
+        assert_eq!(telemetry.environment, "prod");
+    }
+
+    #[test]
+    fn test_printed_config_redacts_secrets() {
+        let settings = settings(&[("ADMIN_TOKEN", "t0ken")], &[]).unwrap();
+        let output = settings.render();
+
+        assert!(output.contains(
+            "otel_exporter_otlp_headers = \"authorization=<redacted>,x-tenant=<redacted>\"  # service.toml"
+        ));
+        assert!(output.contains("admin_token = \"<redacted>\"  # environment"));
+        assert!(!output.contains("s3cret") && !output.contains("t0ken"));
+        assert!(output.contains("rust_log = \"info\"  # default"));
+        assert!(output.contains("downstream_url = \"http://localhost:8080/health\"  # default"));
+        assert!(output.contains("# redaction_keys is not set"));
+        // The output is a valid configuration file.
+        output.parse::<toml::Table>().unwrap();
+    }
+
+    #[test]
+    fn test_invalid_settings() {
+        let err = settings(&[], &["--set", "OTEL_EXPORTER_OTLP_ENDPOIN=http://x"]).unwrap_err();
+        assert!(err.to_string().contains("OTEL_EXPORTER_OTLP_ENDPOIN"));
+
+        let settings = settings(&[("ROUTES", "/health,/admin")], &[]).unwrap();
+        let err = ServiceConfig::from_lookup(|name| settings.get(name)).unwrap_err();
+        assert!(err.to_string().contains("ROUTES"));
+
+        // The errors of `read` name the source and the key of the invalid value.
+        let err = settings
+            .read(|lookup| ServiceConfig::from_lookup(lookup))
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .ends_with("(set by the environment variable ROUTES)"),
+            "{err}"
+        );
+        let settings = Settings::from_sources(
+            Some((
+                Path::new("service.toml"),
+                "[otel]\nexporter_otlp_compression = \"zstd\"",
+            )),
+            |_| None,
+            &Cli::default(),
+        )
+        .unwrap();
+        let err = settings
+            .read(|lookup| TelemetryConfig::from_lookup(lookup))
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .ends_with("(set by `otel.exporter_otlp_compression` in service.toml)"),
+            "{err}"
+        );
+        let cli = Cli::try_parse_from(["service", "--set", "log_format=xml"]).unwrap();
+        let settings = Settings::from_sources(None, |_| None, &cli).unwrap();
+        let err = settings
+            .read(|lookup| TelemetryConfig::from_lookup(lookup))
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .ends_with("(set by `--set log_format` on the command line)"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_defaults_are_valid() {
+        let settings = Settings { layers: Vec::new() };
+        let defaults = |name: &str| {
+            SETTINGS
+                .iter()
+                .find(|setting| setting.name == name)
+                .and_then(|setting| settings.default_of(setting))
+        };
+        TelemetryConfig::from_lookup(defaults).unwrap();
+        LimitsConfig::from_lookup(defaults).unwrap();
+        ServiceConfig::from_lookup(defaults).unwrap();
+        OutcomesConfig::from_lookup(defaults).unwrap();
+    }
+}
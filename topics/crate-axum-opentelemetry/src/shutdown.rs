@@ -0,0 +1,30 @@
+use tokio::signal;
+use tracing::info;
+
+/// Completes when the process receives SIGINT (Ctrl+C) or SIGTERM.
+///
+/// Passed to `axum::serve(...).with_graceful_shutdown`: the server stops accepting connections
+/// and waits for the in-flight requests to complete.
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        signal::ctrl_c()
+            .await
+            .expect("Unable to install the SIGINT handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("Unable to install the SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => info!("SIGINT received, draining in-flight requests..."),
+        _ = terminate => info!("SIGTERM received, draining in-flight requests..."),
+    }
+}
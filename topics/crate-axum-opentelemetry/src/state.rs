@@ -0,0 +1,109 @@
+use crate::error::StartupError;
+use crate::exemplars::Exemplars;
+use crate::health::{Health, HttpHealthCheck};
+use crate::health_outcomes::{HealthOutcomes, OutcomesConfig};
+use crate::http_client::TracedHttpClient;
+use crate::jobs::{job_queue, JobQueue, JobWorker, QUEUE_CAPACITY};
+use crate::metrics::AppMetrics;
+use crate::rate_limit::{Limiter, LimitsConfig};
+use crate::request_context::BaggageRules;
+use crate::settings::ServiceConfig;
+use crate::telemetry_config::TelemetryConfig;
+use axum::extract::FromRef;
+use opentelemetry::metrics::Meter;
+use reqwest::Url;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::info;
+
+const DOWNSTREAM_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Maximum time given to each dependency check of `/readyz`.
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// State shared by the handlers and middlewares of the router.
+#[derive(Clone)]
+pub struct AppState {
+    pub metrics: AppMetrics,
+    pub health: Health,
+    pub http_client: TracedHttpClient,
+    /// URL called by the `/chain` route.
+    pub downstream_url: Url,
+    pub jobs: JobQueue,
+    pub outcomes: HealthOutcomes,
+    /// Baggage entries recorded on the server spans.
+    pub baggage: Arc<BaggageRules>,
+}
+
+/// Builds the state of the router from the settings, for `serve` and `crate::test_app::TestApp`.
+/// Also returns the limiter of the routes, and the job worker, which the caller spawns.
+pub fn build(
+    service: &ServiceConfig,
+    telemetry: &TelemetryConfig,
+    limits: LimitsConfig,
+    outcomes: OutcomesConfig,
+    meter: &Meter,
+    exemplars: Exemplars,
+) -> Result<(AppState, Limiter, JobWorker), StartupError> {
+    let http_client = TracedHttpClient::new(
+        reqwest::Client::builder()
+            .timeout(DOWNSTREAM_TIMEOUT)
+            .build()?,
+    );
+    let mut health = Health::new(HEALTH_CHECK_TIMEOUT);
+    if service.check_downstream {
+        health = health.with_check(HttpHealthCheck::new(
+            "downstream",
+            http_client.clone(),
+            service.downstream_url.clone(),
+        ));
+    }
+
+    let limiter = Limiter::new(limits, meter);
+    let (jobs, job_worker) = job_queue(QUEUE_CAPACITY, meter);
+    let outcomes = HealthOutcomes::new(outcomes);
+    info!(
+        seed = outcomes.seed(),
+        "Health outcomes are drawn from the seed, set HEALTH_SEED to draw them again"
+    );
+    let state = AppState {
+        metrics: AppMetrics::new(meter, exemplars),
+        health,
+        http_client,
+        downstream_url: service.downstream_url.clone(),
+        jobs,
+        outcomes,
+        baggage: Arc::new(telemetry.baggage.clone()),
+    };
+    Ok((state, limiter, job_worker))
+}
+
+impl FromRef<AppState> for AppMetrics {
+    fn from_ref(state: &AppState) -> Self {
+        state.metrics.clone()
+    }
+}
+
+impl FromRef<AppState> for Health {
+    fn from_ref(state: &AppState) -> Self {
+        state.health.clone()
+    }
+}
+
+impl FromRef<AppState> for JobQueue {
+    fn from_ref(state: &AppState) -> Self {
+        state.jobs.clone()
+    }
+}
+
+impl FromRef<AppState> for HealthOutcomes {
+    fn from_ref(state: &AppState) -> Self {
+        state.outcomes.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<BaggageRules> {
+    fn from_ref(state: &AppState) -> Self {
+        state.baggage.clone()
+    }
+}
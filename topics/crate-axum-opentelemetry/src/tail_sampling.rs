@@ -0,0 +1,493 @@
+use crate::sampling::is_error;
+use opentelemetry::metrics::{Counter, Meter};
+use opentelemetry::trace::{SpanId, SpanKind, TraceId};
+use opentelemetry::{Context, KeyValue};
+use opentelemetry_sdk::error::OTelSdkResult;
+use opentelemetry_sdk::resource::Resource;
+use opentelemetry_sdk::trace::{
+    Sampler, SamplingDecision, ShouldSample, Span, SpanData, SpanProcessor,
+};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Rules of the tail-based sampling strategy.
+///
+/// The spans of a trace are buffered until its local root span ends, or at most `decision_wait`.
+/// The whole trace is then kept if one of its spans ended with an error status or a 4xx/5xx
+/// `http.response.status_code`, or if it lasted at least `latency_threshold`; the other traces are
+/// sampled at `ratio`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TailSamplingRules {
+    pub ratio: f64,
+    pub latency_threshold: Duration,
+    pub decision_wait: Duration,
+    /// Spans buffered at most. When the buffer is full, the oldest trace is decided early.
+    pub max_spans: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Reason {
+    Error,
+    Slow,
+    Sampled,
+    NotSampled,
+}
+
+impl Reason {
+    fn keep(self) -> bool {
+        self != Reason::NotSampled
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Reason::Error => "error",
+            Reason::Slow => "slow",
+            Reason::Sampled => "sampled",
+            Reason::NotSampled => "not_sampled",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct PendingTrace {
+    first_seen: Instant,
+    spans: Vec<SpanData>,
+}
+
+#[derive(Debug, Default)]
+struct Buffer {
+    pending: HashMap<TraceId, PendingTrace>,
+    // Trace IDs by arrival: the oldest trace is the first one still pending.
+    arrivals: VecDeque<TraceId>,
+    span_count: usize,
+    // Recent decisions, applied to the spans ending after their trace was decided.
+    kept: HashSet<TraceId>,
+    decided: HashSet<TraceId>,
+    decisions: VecDeque<TraceId>,
+}
+
+/// Span processor of the tail-based strategy, wrapping the exporting processor.
+///
+/// Every span must be recorded and sampled up-front (`Sampler::AlwaysOn`): the decision is taken
+/// here once the local trace is complete, and applies to all of its spans.
+/// Memory is bounded by `TailSamplingRules::max_spans`; decisions are counted in the
+/// `tail_sampling.traces` metric, and traces decided early in `tail_sampling.evicted_traces`.
+#[derive(Debug)]
+pub struct TailSamplingSpanProcessor<P> {
+    inner: P,
+    rules: TailSamplingRules,
+    buffer: Mutex<Buffer>,
+    traces: Counter<u64>,
+    evicted_traces: Counter<u64>,
+}
+
+impl<P: SpanProcessor> TailSamplingSpanProcessor<P> {
+    pub fn new(inner: P, rules: TailSamplingRules, meter: &Meter) -> Self {
+        TailSamplingSpanProcessor {
+            inner,
+            rules,
+            buffer: Mutex::new(Buffer::default()),
+            traces: meter
+                .u64_counter("tail_sampling.traces")
+                .with_description("Number of traces decided by the tail sampler.")
+                .with_unit("{trace}")
+                .build(),
+            evicted_traces: meter
+                .u64_counter("tail_sampling.evicted_traces")
+                .with_description(
+                    "Number of traces decided before they were complete, the buffer being full.",
+                )
+                .with_unit("{trace}")
+                .build(),
+        }
+    }
+
+    fn reason(&self, trace_id: TraceId, spans: &[SpanData]) -> Reason {
+        if spans.iter().any(is_error) {
+            return Reason::Error;
+        }
+        let start = spans.iter().map(|span| span.start_time).min();
+        let end = spans.iter().map(|span| span.end_time).max();
+        let duration = match (start, end) {
+            (Some(start), Some(end)) => end.duration_since(start).unwrap_or_default(),
+            _ => Duration::ZERO,
+        };
+        if duration >= self.rules.latency_threshold {
+            return Reason::Slow;
+        }
+        let result = Sampler::TraceIdRatioBased(self.rules.ratio).should_sample(
+            None,
+            trace_id,
+            "",
+            &SpanKind::Internal,
+            &[],
+            &[],
+        );
+        match result.decision {
+            SamplingDecision::RecordAndSample => Reason::Sampled,
+            _ => Reason::NotSampled,
+        }
+    }
+
+    // Removes the trace from the buffer and returns its spans if it is kept.
+    fn decide(&self, buffer: &mut Buffer, trace_id: TraceId) -> Vec<SpanData> {
+        let Some(trace) = buffer.pending.remove(&trace_id) else {
+            return Vec::new();
+        };
+        buffer.span_count -= trace.spans.len();
+
+        let reason = self.reason(trace_id, &trace.spans);
+        self.traces.add(
+            1,
+            &[
+                KeyValue::new("decision", if reason.keep() { "kept" } else { "dropped" }),
+                KeyValue::new("reason", reason.as_str()),
+            ],
+        );
+
+        // As many decisions as spans are remembered: enough for the late spans of recent traces.
+        if buffer.decisions.len() >= self.rules.max_spans.max(1) {
+            if let Some(oldest) = buffer.decisions.pop_front() {
+                buffer.decided.remove(&oldest);
+                buffer.kept.remove(&oldest);
+            }
+        }
+        buffer.decisions.push_back(trace_id);
+        buffer.decided.insert(trace_id);
+        if reason.keep() {
+            buffer.kept.insert(trace_id);
+            trace.spans
+        } else {
+            Vec::new()
+        }
+    }
+
+    // Decides the traces waiting for longer than `decision_wait`, then the oldest ones while the
+    // buffer is over capacity.
+    fn decide_expired(&self, buffer: &mut Buffer, now: Instant) -> Vec<SpanData> {
+        let mut kept = Vec::new();
+        while let Some(&trace_id) = buffer.arrivals.front() {
+            let over_capacity = buffer.span_count > self.rules.max_spans;
+            match buffer.pending.get(&trace_id) {
+                // Already decided when its root span ended.
+                None => {}
+                Some(trace) if now.duration_since(trace.first_seen) >= self.rules.decision_wait => {
+                }
+                Some(_) if over_capacity => self.evicted_traces.add(1, &[]),
+                Some(_) => break,
+            }
+            buffer.arrivals.pop_front();
+            kept.extend(self.decide(buffer, trace_id));
+        }
+        kept
+    }
+
+    fn decide_all(&self) -> Vec<SpanData> {
+        let mut buffer = self.buffer.lock().unwrap_or_else(|err| err.into_inner());
+        let trace_ids: Vec<TraceId> = buffer.arrivals.drain(..).collect();
+        trace_ids
+            .into_iter()
+            .flat_map(|trace_id| self.decide(&mut buffer, trace_id))
+            .collect()
+    }
+
+    fn forward(&self, spans: Vec<SpanData>) {
+        spans.into_iter().for_each(|span| self.inner.on_end(span));
+    }
+}
+
+// The local root has no parent in this process: it ends last, once the local trace is complete.
+fn is_local_root(span: &SpanData) -> bool {
+    span.parent_span_id == SpanId::INVALID || span.parent_span_is_remote
+}
+
+impl<P: SpanProcessor> SpanProcessor for TailSamplingSpanProcessor<P> {
+    fn on_start(&self, span: &mut Span, cx: &Context) {
+        self.inner.on_start(span, cx);
+    }
+
+    fn on_end(&self, span: SpanData) {
+        let trace_id = span.span_context.trace_id();
+        // Spans are exported outside of the lock, which the exporting processor may hold for long.
+        let kept = {
+            let mut buffer = self.buffer.lock().unwrap_or_else(|err| err.into_inner());
+            if buffer.decided.contains(&trace_id) {
+                let keep = buffer.kept.contains(&trace_id);
+                drop(buffer);
+                if keep {
+                    self.inner.on_end(span);
+                }
+                return;
+            }
+
+            let root = is_local_root(&span);
+            let now = Instant::now();
+            let buffer = &mut *buffer;
+            let trace = buffer.pending.entry(trace_id).or_insert_with(|| {
+                buffer.arrivals.push_back(trace_id);
+                PendingTrace {
+                    first_seen: now,
+                    spans: Vec::new(),
+                }
+            });
+            trace.spans.push(span);
+            buffer.span_count += 1;
+
+            let mut kept = if root {
+                self.decide(buffer, trace_id)
+            } else {
+                Vec::new()
+            };
+            kept.extend(self.decide_expired(buffer, now));
+            // Traces decided on their root leave their ID behind: keep the queue bounded as well.
+            if buffer.arrivals.len() > 2 * self.rules.max_spans.max(1) {
+                let pending = &buffer.pending;
+                buffer
+                    .arrivals
+                    .retain(|trace_id| pending.contains_key(trace_id));
+            }
+            kept
+        };
+        self.forward(kept);
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.forward(self.decide_all());
+        self.inner.force_flush()
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        self.forward(self.decide_all());
+        self.inner.shutdown_with_timeout(timeout)
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.inner.set_resource(resource);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::AppError;
+    use crate::http_trace::trace_http_request;
+    use crate::test_app::RecordingProcessor;
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use axum::routing::get;
+    use axum::{middleware, Router};
+    use opentelemetry::metrics::MeterProvider;
+    use opentelemetry::trace::{SpanContext, Status, TraceFlags, TraceState, TracerProvider};
+    use opentelemetry_sdk::metrics::SdkMeterProvider;
+    use opentelemetry_sdk::trace::{SdkTracerProvider, SpanEvents, SpanLinks};
+    use opentelemetry_semantic_conventions::attribute::HTTP_RESPONSE_STATUS_CODE;
+    use std::time::SystemTime;
+    use tower::ServiceExt;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    fn rules(max_spans: usize) -> TailSamplingRules {
+        TailSamplingRules {
+            ratio: 0.0,
+            latency_threshold: Duration::from_millis(500),
+            decision_wait: Duration::from_secs(60),
+            max_spans,
+        }
+    }
+
+    fn span(
+        trace_id: u128,
+        span_id: u64,
+        parent: u64,
+        duration: Duration,
+        status: Status,
+    ) -> SpanData {
+        let end_time = SystemTime::now();
+        SpanData {
+            span_context: SpanContext::new(
+                TraceId::from(trace_id),
+                SpanId::from(span_id),
+                TraceFlags::SAMPLED,
+                false,
+                TraceState::default(),
+            ),
+            parent_span_id: SpanId::from(parent),
+            parent_span_is_remote: false,
+            span_kind: SpanKind::Internal,
+            name: "health_handler".into(),
+            start_time: end_time - duration,
+            end_time,
+            attributes: Vec::new(),
+            dropped_attributes_count: 0,
+            events: SpanEvents::default(),
+            links: SpanLinks::default(),
+            status,
+            instrumentation_scope: Default::default(),
+        }
+    }
+
+    fn fast(trace_id: u128, span_id: u64, parent: u64) -> SpanData {
+        span(
+            trace_id,
+            span_id,
+            parent,
+            Duration::from_millis(5),
+            Status::Unset,
+        )
+    }
+
+    fn processor(
+        rules: TailSamplingRules,
+    ) -> (
+        TailSamplingSpanProcessor<RecordingProcessor>,
+        RecordingProcessor,
+    ) {
+        let recorder = RecordingProcessor::default();
+        let meter = SdkMeterProvider::default().meter("test");
+        (
+            TailSamplingSpanProcessor::new(recorder.clone(), rules, &meter),
+            recorder,
+        )
+    }
+
+    #[test]
+    fn test_keeps_whole_traces_with_an_error_or_slow() {
+        let (processor, recorder) = processor(rules(100));
+
+        // Trace 1: the child fails, e.g. a 401 of `health_handler`.
+        processor.on_end(span(
+            1,
+            11,
+            10,
+            Duration::from_millis(5),
+            Status::error("401"),
+        ));
+        // Trace 2: fast and successful.
+        processor.on_end(fast(2, 21, 20));
+        // Trace 3: slow root.
+        processor.on_end(fast(3, 31, 30));
+        // Trace 4: the server span of a 403, whose status is left unset.
+        let mut forbidden = fast(4, 40, 0);
+        forbidden
+            .attributes
+            .push(KeyValue::new(HTTP_RESPONSE_STATUS_CODE, 403));
+        assert!(
+            recorder.span_ids().is_empty(),
+            "nothing is exported before the root ends"
+        );
+
+        processor.on_end(fast(1, 10, 0));
+        processor.on_end(fast(2, 20, 0));
+        processor.on_end(span(3, 30, 0, Duration::from_secs(1), Status::Unset));
+        processor.on_end(forbidden);
+
+        assert_eq!(recorder.span_ids(), vec![11, 10, 31, 30, 40]);
+    }
+
+    #[test]
+    fn test_late_spans_follow_the_decision() {
+        let (processor, recorder) = processor(rules(100));
+
+        processor.on_end(span(
+            1,
+            10,
+            0,
+            Duration::from_millis(5),
+            Status::error("500"),
+        ));
+        processor.on_end(fast(2, 20, 0));
+        // Spawned work ending after the root.
+        processor.on_end(fast(1, 11, 10));
+        processor.on_end(fast(2, 21, 20));
+
+        assert_eq!(recorder.span_ids(), vec![10, 11]);
+    }
+
+    #[test]
+    fn test_incomplete_traces_are_decided_after_the_wait() {
+        let (processor, recorder) = processor(TailSamplingRules {
+            decision_wait: Duration::ZERO,
+            ..rules(100)
+        });
+
+        // The root of trace 1 never ends in this process.
+        processor.on_end(span(
+            1,
+            11,
+            10,
+            Duration::from_millis(5),
+            Status::error("503"),
+        ));
+        assert_eq!(recorder.span_ids(), vec![11]);
+        assert!(processor.buffer.lock().unwrap().pending.is_empty());
+    }
+
+    #[test]
+    fn test_memory_is_bounded() {
+        let (processor, recorder) = processor(rules(3));
+
+        // Two children of trace 1, whose root is still running, and a failing child of trace 2.
+        processor.on_end(fast(1, 11, 10));
+        processor.on_end(fast(1, 12, 10));
+        processor.on_end(span(
+            2,
+            21,
+            20,
+            Duration::from_millis(5),
+            Status::error("403"),
+        ));
+        // Over capacity: trace 1, the oldest, is decided early and dropped as a whole.
+        processor.on_end(fast(3, 31, 30));
+
+        {
+            let buffer = processor.buffer.lock().unwrap();
+            assert_eq!(buffer.span_count, 2);
+            assert!(!buffer.pending.contains_key(&TraceId::from(1)));
+        }
+        // Its root is dropped too, rather than exported without its children.
+        processor.on_end(fast(1, 10, 0));
+
+        // Many complete traces never grow the buffer.
+        for trace_id in 100..1100 {
+            processor.on_end(fast(trace_id, 1, 0));
+        }
+        let buffer = processor.buffer.lock().unwrap();
+        assert!(buffer.span_count <= 3);
+        assert!(buffer.arrivals.len() <= 6);
+        assert!(buffer.decisions.len() <= 3);
+        drop(buffer);
+
+        // Pending traces are decided on shutdown: only trace 2 has an error.
+        processor.shutdown().unwrap();
+        assert_eq!(recorder.span_ids(), vec![21]);
+    }
+
+    #[tokio::test]
+    async fn test_keeps_a_forbidden_request_traced_by_the_middleware() {
+        let (processor, recorder) = processor(rules(100));
+        let provider = SdkTracerProvider::builder()
+            .with_span_processor(processor)
+            .build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let router = Router::new()
+            .route("/health", get(|| async { StatusCode::OK }))
+            .route(
+                "/forbidden",
+                get(|| async { Err::<(), _>(AppError::Forbidden) }),
+            )
+            .layer(middleware::from_fn(trace_http_request));
+        for uri in ["/health", "/forbidden"] {
+            let request = Request::get(uri).body(Body::empty()).unwrap();
+            router.clone().oneshot(request).await.unwrap();
+        }
+
+        // The 200 is not sampled at a ratio of 0, the 403 is kept on its status code alone.
+        let spans = recorder.spans.lock().unwrap();
+        let names: Vec<_> = spans.iter().map(|span| span.name.to_string()).collect();
+        assert_eq!(names, vec!["GET /forbidden"]);
+        assert_eq!(spans[0].status, Status::Unset);
+    }
+}
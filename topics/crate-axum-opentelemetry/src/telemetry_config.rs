@@ -0,0 +1,755 @@
+use crate::redaction::{RedactionMode, RedactionRules};
+use crate::request_context::{
+    BaggageRules, DEFAULT_BAGGAGE_MAX_ENTRIES, DEFAULT_BAGGAGE_MAX_VALUE_LEN,
+};
+use crate::sampling::{RouteRule, SamplingRules};
+use crate::tail_sampling::TailSamplingRules;
+use axum::http::{HeaderMap, HeaderName, HeaderValue, Uri};
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::Compression;
+use opentelemetry_sdk::trace::Sampler;
+use opentelemetry_semantic_conventions::attribute::DEPLOYMENT_ENVIRONMENT_NAME;
+use regex::Regex;
+use reqwest::Url;
+use std::path::PathBuf;
+use std::time::Duration;
+use thiserror::Error;
+use tracing_subscriber::EnvFilter;
+
+pub const DEFAULT_OTLP_GRPC_ENDPOINT: &str = "http://localhost:4317";
+pub const DEFAULT_OTLP_HTTP_ENDPOINT: &str = "http://localhost:4318";
+pub const DEFAULT_DEPLOYMENT_ENVIRONMENT: &str = "develop";
+const DEFAULT_LOG_DIRECTIVES: &str = "info";
+const DEFAULT_SLOW_THRESHOLD: Duration = Duration::from_millis(500);
+const DEFAULT_DECISION_WAIT: Duration = Duration::from_secs(5);
+const DEFAULT_MAX_BUFFERED_SPANS: usize = 10_000;
+
+/// Error raised when an environment variable holds a value that cannot be used.
+#[derive(Debug, Error)]
+#[error("invalid value {value:?} for {name}: {reason}")]
+pub struct ConfigError {
+    name: &'static str,
+    value: String,
+    reason: String,
+}
+
+impl ConfigError {
+    fn new(name: &'static str, value: &str, reason: impl ToString) -> Self {
+        ConfigError {
+            name,
+            value: value.to_owned(),
+            reason: reason.to_string(),
+        }
+    }
+
+    /// Setting holding the invalid value.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+/// Telemetry settings, read from the standard OpenTelemetry environment variables (or from the
+/// other sources of `crate::settings::Settings`).
+///
+/// | Variable | Default |
+/// | --- | --- |
+/// | `OTEL_EXPORTER_OTLP_PROTOCOL` (`grpc` or `http/protobuf`) | `grpc` |
+/// | `OTEL_EXPORTER_OTLP_ENDPOINT` | `http://localhost:4317` (gRPC), `http://localhost:4318` (HTTP) |
+/// | `OTEL_EXPORTER_OTLP_HEADERS` | none |
+/// | `OTEL_EXPORTER_OTLP_COMPRESSION` (`gzip` or `none`) | `none` |
+/// | `OTEL_EXPORTER_OTLP_CERTIFICATE` | none, the system roots are trusted |
+/// | `OTEL_SERVICE_NAME` | the crate name |
+/// | `OTEL_RESOURCE_ATTRIBUTES` | none |
+/// | `DEPLOYMENT_ENVIRONMENT_NAME` (overrides `OTEL_RESOURCE_ATTRIBUTES`) | `develop` |
+/// | `OTEL_TRACES_SAMPLER` / `OTEL_TRACES_SAMPLER_ARG` | `parentbased_always_on` |
+/// | `SAMPLING_SLOW_THRESHOLD_MS` (`rule_based` and `tail_based` samplers only) | `500` |
+/// | `SAMPLING_ROUTE_RULES` (`rule_based` sampler only) | none |
+/// | `TAIL_SAMPLING_DECISION_WAIT_MS` (`tail_based` sampler only) | `5000` |
+/// | `TAIL_SAMPLING_MAX_SPANS` (`tail_based` sampler only) | `10000` |
+/// | `RUST_LOG` | `info` |
+/// | `LOG_FORMAT` (`text` or `json`) | `text` |
+/// | `REDACTION_KEYS` | none |
+/// | `REDACTION_PATTERN` | none |
+/// | `REDACTION_MODE` (`redact` or `hash`) | `redact` |
+/// | `BAGGAGE_KEYS` | `tenant,user.tier` |
+/// | `BAGGAGE_MAX_ENTRIES` | `8` |
+/// | `BAGGAGE_MAX_VALUE_LEN` | `128` |
+#[derive(Debug, Clone)]
+pub struct TelemetryConfig {
+    pub otlp_protocol: OtlpProtocol,
+    /// Base URL: the HTTP exporters append the path of each signal (`/v1/traces`...).
+    pub otlp_endpoint: String,
+    /// Sent with every export request, e.g. for authentication.
+    pub otlp_headers: HeaderMap,
+    pub otlp_compression: Option<Compression>,
+    /// PEM file of the certificate authority that signed the certificate of the collector.
+    pub otlp_certificate: Option<PathBuf>,
+    pub service_name: String,
+    /// `deployment.environment.name` of the resource: `DEPLOYMENT_ENVIRONMENT_NAME`, else the one
+    /// of `OTEL_RESOURCE_ATTRIBUTES`.
+    pub environment: String,
+    pub resource_attributes: Vec<KeyValue>,
+    pub sampler: TracesSampler,
+    pub log_directives: String,
+    pub log_format: LogFormat,
+    /// Applied to spans and log records before export (see `crate::redaction`).
+    pub redaction: RedactionRules,
+    /// Baggage entries recorded on the server spans and the log records.
+    pub baggage: BaggageRules,
+}
+
+/// Transport of the OTLP exporters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OtlpProtocol {
+    Grpc,
+    /// Protobuf messages posted to `/v1/traces`, `/v1/metrics` and `/v1/logs`.
+    HttpProtobuf,
+}
+
+/// Format of the logs written on the standard output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human-readable lines.
+    Text,
+    /// One JSON object per line, for log shippers (see `crate::json_log`).
+    Json,
+}
+
+/// Sampling strategy of the tracer provider.
+#[derive(Debug, Clone)]
+pub enum TracesSampler {
+    /// One of the samplers of the specification, applied when spans start.
+    Sdk(Sampler),
+    /// Ratio per route, completed by keeping every error and slow span (see `crate::sampling`).
+    RuleBased(SamplingRules),
+    /// Whole local traces kept when they contain an error or are slow, the others sampled at a
+    /// ratio, once they are complete (see `crate::tail_sampling`).
+    TailBased(TailSamplingRules),
+}
+
+impl TelemetryConfig {
+    // Variables set to an empty string are treated as unset, as required by the specification.
+    pub(crate) fn from_lookup(
+        lookup: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, ConfigError> {
+        let lookup = |name: &str| lookup(name).filter(|value| !value.trim().is_empty());
+
+        let otlp_protocol = match lookup("OTEL_EXPORTER_OTLP_PROTOCOL")
+            .as_deref()
+            .map(str::trim)
+        {
+            None | Some("grpc") => OtlpProtocol::Grpc,
+            Some("http/protobuf") => OtlpProtocol::HttpProtobuf,
+            Some(other) => {
+                return Err(ConfigError::new(
+                    "OTEL_EXPORTER_OTLP_PROTOCOL",
+                    other,
+                    "expected grpc or http/protobuf",
+                ))
+            }
+        };
+
+        let otlp_endpoint = match (lookup("OTEL_EXPORTER_OTLP_ENDPOINT"), otlp_protocol) {
+            (Some(endpoint), _) => parse_endpoint(&endpoint)?,
+            (None, OtlpProtocol::Grpc) => DEFAULT_OTLP_GRPC_ENDPOINT.to_owned(),
+            (None, OtlpProtocol::HttpProtobuf) => DEFAULT_OTLP_HTTP_ENDPOINT.to_owned(),
+        };
+
+        let otlp_headers = match lookup("OTEL_EXPORTER_OTLP_HEADERS") {
+            Some(headers) => parse_headers(&headers)?,
+            None => HeaderMap::new(),
+        };
+
+        let otlp_compression = match lookup("OTEL_EXPORTER_OTLP_COMPRESSION")
+            .as_deref()
+            .map(str::trim)
+        {
+            None | Some("none") => None,
+            Some("gzip") => Some(Compression::Gzip),
+            Some(other) => {
+                return Err(ConfigError::new(
+                    "OTEL_EXPORTER_OTLP_COMPRESSION",
+                    other,
+                    "expected gzip or none",
+                ))
+            }
+        };
+
+        let otlp_certificate =
+            lookup("OTEL_EXPORTER_OTLP_CERTIFICATE").map(|path| PathBuf::from(path.trim()));
+
+        let service_name = lookup("OTEL_SERVICE_NAME")
+            .map(|name| name.trim().to_owned())
+            .unwrap_or_else(|| env!("CARGO_PKG_NAME").to_owned());
+
+        let resource_attributes = match lookup("OTEL_RESOURCE_ATTRIBUTES") {
+            Some(attributes) => parse_resource_attributes(&attributes)?,
+            None => Vec::new(),
+        };
+
+        // The dedicated setting wins, whichever source each comes from (e.g. `--environment`).
+        let environment = lookup("DEPLOYMENT_ENVIRONMENT_NAME")
+            .map(|name| name.trim().to_owned())
+            .or_else(|| {
+                resource_attributes
+                    .iter()
+                    .find(|kv| kv.key.as_str() == DEPLOYMENT_ENVIRONMENT_NAME)
+                    .map(|kv| kv.value.to_string())
+            })
+            .unwrap_or_else(|| DEFAULT_DEPLOYMENT_ENVIRONMENT.to_owned());
+
+        let slow_threshold = match lookup("SAMPLING_SLOW_THRESHOLD_MS") {
+            Some(millis) => parse_millis("SAMPLING_SLOW_THRESHOLD_MS", &millis)?,
+            None => DEFAULT_SLOW_THRESHOLD,
+        };
+
+        let sampler = match lookup("OTEL_TRACES_SAMPLER").as_deref().map(str::trim) {
+            Some("rule_based") => TracesSampler::RuleBased(SamplingRules {
+                default_ratio: parse_ratio(lookup("OTEL_TRACES_SAMPLER_ARG").as_deref())?,
+                slow_threshold,
+                routes: match lookup("SAMPLING_ROUTE_RULES") {
+                    Some(rules) => parse_route_rules(&rules)?,
+                    None => Vec::new(),
+                },
+            }),
+            Some("tail_based") => TracesSampler::TailBased(TailSamplingRules {
+                ratio: parse_ratio(lookup("OTEL_TRACES_SAMPLER_ARG").as_deref())?,
+                latency_threshold: slow_threshold,
+                decision_wait: match lookup("TAIL_SAMPLING_DECISION_WAIT_MS") {
+                    Some(millis) => parse_millis("TAIL_SAMPLING_DECISION_WAIT_MS", &millis)?,
+                    None => DEFAULT_DECISION_WAIT,
+                },
+                max_spans: match lookup("TAIL_SAMPLING_MAX_SPANS") {
+                    Some(spans) => match spans.trim().parse() {
+                        Ok(spans) if spans > 0 => spans,
+                        _ => {
+                            return Err(ConfigError::new(
+                                "TAIL_SAMPLING_MAX_SPANS",
+                                &spans,
+                                "expected a positive number of spans",
+                            ))
+                        }
+                    },
+                    None => DEFAULT_MAX_BUFFERED_SPANS,
+                },
+            }),
+            sampler => TracesSampler::Sdk(parse_sampler(
+                sampler,
+                lookup("OTEL_TRACES_SAMPLER_ARG").as_deref(),
+            )?),
+        };
+
+        let log_directives = match lookup("RUST_LOG") {
+            Some(directives) => {
+                EnvFilter::try_new(&directives)
+                    .map_err(|err| ConfigError::new("RUST_LOG", &directives, err))?;
+                directives
+            }
+            None => DEFAULT_LOG_DIRECTIVES.to_owned(),
+        };
+
+        let log_format = match lookup("LOG_FORMAT").as_deref().map(str::trim) {
+            None | Some("text") => LogFormat::Text,
+            Some("json") => LogFormat::Json,
+            Some(other) => {
+                return Err(ConfigError::new(
+                    "LOG_FORMAT",
+                    other,
+                    "expected text or json",
+                ))
+            }
+        };
+
+        let redaction = RedactionRules {
+            keys: lookup("REDACTION_KEYS")
+                .map(|keys| {
+                    keys.split(',')
+                        .map(str::trim)
+                        .filter(|key| !key.is_empty())
+                        .map(str::to_owned)
+                        .collect()
+                })
+                .unwrap_or_default(),
+            pattern: lookup("REDACTION_PATTERN")
+                .map(|pattern| {
+                    Regex::new(&pattern)
+                        .map_err(|err| ConfigError::new("REDACTION_PATTERN", &pattern, err))
+                })
+                .transpose()?,
+            mode: match lookup("REDACTION_MODE").as_deref().map(str::trim) {
+                None | Some("redact") => RedactionMode::Redact,
+                Some("hash") => RedactionMode::Hash,
+                Some(other) => {
+                    return Err(ConfigError::new(
+                        "REDACTION_MODE",
+                        other,
+                        "expected redact or hash",
+                    ))
+                }
+            },
+        };
+
+        let baggage = BaggageRules {
+            keys: match lookup("BAGGAGE_KEYS") {
+                Some(keys) => keys
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|key| !key.is_empty())
+                    .map(str::to_owned)
+                    .collect(),
+                None => BaggageRules::default().keys,
+            },
+            max_entries: match lookup("BAGGAGE_MAX_ENTRIES") {
+                Some(entries) => parse_count("BAGGAGE_MAX_ENTRIES", &entries)?,
+                None => DEFAULT_BAGGAGE_MAX_ENTRIES,
+            },
+            max_value_len: match lookup("BAGGAGE_MAX_VALUE_LEN") {
+                Some(len) => parse_count("BAGGAGE_MAX_VALUE_LEN", &len)?,
+                None => DEFAULT_BAGGAGE_MAX_VALUE_LEN,
+            },
+        };
+
+        Ok(TelemetryConfig {
+            otlp_protocol,
+            otlp_endpoint,
+            otlp_headers,
+            otlp_compression,
+            otlp_certificate,
+            service_name,
+            environment,
+            resource_attributes,
+            sampler,
+            log_directives,
+            log_format,
+            redaction,
+            baggage,
+        })
+    }
+
+    /// `otlp_endpoint` as a URL, already validated by `from_lookup`.
+    pub fn otlp_endpoint_url(&self) -> Url {
+        Url::parse(&self.otlp_endpoint).expect("validated by from_lookup")
+    }
+
+    /// Filter built from `RUST_LOG`, already validated by `from_lookup`.
+    pub fn env_filter(&self) -> EnvFilter {
+        EnvFilter::new(&self.log_directives)
+    }
+}
+
+fn parse_endpoint(endpoint: &str) -> Result<String, ConfigError> {
+    const NAME: &str = "OTEL_EXPORTER_OTLP_ENDPOINT";
+
+    let uri: Uri = endpoint
+        .trim()
+        .parse()
+        .map_err(|err| ConfigError::new(NAME, endpoint, err))?;
+    match uri.scheme_str() {
+        Some("http") | Some("https") => {}
+        _ => {
+            return Err(ConfigError::new(
+                NAME,
+                endpoint,
+                "expected an absolute URL with an http or https scheme",
+            ))
+        }
+    }
+    if uri.host().is_none() {
+        return Err(ConfigError::new(NAME, endpoint, "missing host"));
+    }
+    // Also used as a URL, by the health check of the collector.
+    Url::parse(endpoint.trim()).map_err(|err| ConfigError::new(NAME, endpoint, err))?;
+
+    Ok(endpoint.trim().to_owned())
+}
+
+// Format: `key1=value1,key2=value2`, keys and values being URL-encoded (`team=a%2Cb` for `a,b`).
+fn parse_resource_attributes(attributes: &str) -> Result<Vec<KeyValue>, ConfigError> {
+    const NAME: &str = "OTEL_RESOURCE_ATTRIBUTES";
+
+    attributes
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let invalid = |reason: String| ConfigError::new(NAME, attributes, reason);
+            let (key, value) = pair
+                .split_once('=')
+                .filter(|(key, _)| !key.trim().is_empty())
+                .ok_or_else(|| {
+                    invalid(format!(
+                        "expected `key=value` pairs separated by commas, found {pair:?}"
+                    ))
+                })?;
+            let decode = |text: &str| {
+                percent_decode(text.trim())
+                    .ok_or_else(|| invalid(format!("invalid percent-encoding in {pair:?}")))
+            };
+            Ok(KeyValue::new(decode(key)?, decode(value)?))
+        })
+        .collect()
+}
+
+// Format: `key1=value1,key2=value2`, values being URL-encoded (`Authorization=Bearer%20token`).
+fn parse_headers(headers: &str) -> Result<HeaderMap, ConfigError> {
+    const NAME: &str = "OTEL_EXPORTER_OTLP_HEADERS";
+
+    headers
+        .split(',')
+        .map(str::trim)
+        .enumerate()
+        .filter(|(_, pair)| !pair.is_empty())
+        .map(|(index, pair)| {
+            let invalid = |reason: &str| {
+                // Only the position of the entry is reported: headers usually hold credentials,
+                // even in a malformed entry (`authorization Bearer <token>`).
+                ConfigError::new(NAME, "<redacted>", format!("entry {}: {reason}", index + 1))
+            };
+            let (name, value) = pair
+                .split_once('=')
+                .ok_or_else(|| invalid("expected `key=value` pairs separated by commas"))?;
+            let name = HeaderName::from_bytes(name.trim().as_bytes())
+                .map_err(|_| invalid("invalid header name"))?;
+            let value = percent_decode(value.trim())
+                .and_then(|value| HeaderValue::from_str(&value).ok())
+                .ok_or_else(|| invalid("invalid header value"))?;
+            Ok((name, value))
+        })
+        .collect()
+}
+
+fn percent_decode(value: &str) -> Option<String> {
+    let mut decoded = Vec::with_capacity(value.len());
+    let mut bytes = value.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte == b'%' {
+            let hex = [bytes.next()?, bytes.next()?];
+            decoded.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            decoded.push(byte);
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+fn parse_ratio(arg: Option<&str>) -> Result<f64, ConfigError> {
+    match arg {
+        None => Ok(1.0),
+        Some(arg) => match arg.trim().parse::<f64>() {
+            Ok(ratio) if (0.0..=1.0).contains(&ratio) => Ok(ratio),
+            _ => Err(ConfigError::new(
+                "OTEL_TRACES_SAMPLER_ARG",
+                arg,
+                "expected a ratio between 0.0 and 1.0",
+            )),
+        },
+    }
+}
+
+fn parse_count(name: &'static str, count: &str) -> Result<usize, ConfigError> {
+    count
+        .trim()
+        .parse()
+        .map_err(|_| ConfigError::new(name, count, "expected a non-negative integer"))
+}
+
+fn parse_millis(name: &'static str, millis: &str) -> Result<Duration, ConfigError> {
+    millis
+        .trim()
+        .parse()
+        .map(Duration::from_millis)
+        .map_err(|_| ConfigError::new(name, millis, "expected a number of milliseconds"))
+}
+
+// Format: `route=ratio[:slow_threshold_ms]` separated by commas, e.g. `/health=0.01:250,/orders=0.5`.
+fn parse_route_rules(rules: &str) -> Result<Vec<RouteRule>, ConfigError> {
+    const NAME: &str = "SAMPLING_ROUTE_RULES";
+
+    let invalid = |rule: &str| {
+        ConfigError::new(
+            NAME,
+            rules,
+            format!("expected `route=ratio[:slow_threshold_ms]` rules separated by commas, found {rule:?}"),
+        )
+    };
+
+    rules
+        .split(',')
+        .map(str::trim)
+        .filter(|rule| !rule.is_empty())
+        .map(|rule| {
+            let (route, settings) = rule
+                .split_once('=')
+                .filter(|(route, _)| route.trim().starts_with('/'))
+                .ok_or_else(|| invalid(rule))?;
+            let (ratio, slow_threshold) = match settings.split_once(':') {
+                Some((ratio, millis)) => (ratio, Some(millis)),
+                None => (settings, None),
+            };
+            Ok(RouteRule {
+                route: route.trim().to_owned(),
+                ratio: match ratio.trim().parse::<f64>() {
+                    Ok(ratio) if (0.0..=1.0).contains(&ratio) => ratio,
+                    _ => return Err(invalid(rule)),
+                },
+                slow_threshold: slow_threshold
+                    .map(|millis| parse_millis(NAME, millis).map_err(|_| invalid(rule)))
+                    .transpose()?,
+            })
+        })
+        .collect()
+}
+
+fn parse_sampler(sampler: Option<&str>, arg: Option<&str>) -> Result<Sampler, ConfigError> {
+    let ratio = || parse_ratio(arg);
+
+    let sampler = match sampler.map(str::trim) {
+        None | Some("parentbased_always_on") => Sampler::ParentBased(Box::new(Sampler::AlwaysOn)),
+        Some("parentbased_always_off") => Sampler::ParentBased(Box::new(Sampler::AlwaysOff)),
+        Some("parentbased_traceidratio") => {
+            Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(ratio()?)))
+        }
+        Some("always_on") => Sampler::AlwaysOn,
+        Some("always_off") => Sampler::AlwaysOff,
+        Some("traceidratio") => Sampler::TraceIdRatioBased(ratio()?),
+        Some(other) => {
+            return Err(ConfigError::new(
+                "OTEL_TRACES_SAMPLER",
+                other,
+                "expected one of always_on, always_off, traceidratio, parentbased_always_on, \
+                 parentbased_always_off, parentbased_traceidratio, rule_based, tail_based",
+            ))
+        }
+    };
+
+    Ok(sampler)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn config_from(vars: &[(&str, &str)]) -> Result<TelemetryConfig, ConfigError> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        TelemetryConfig::from_lookup(|name| vars.get(name).cloned())
+    }
+
+    #[test]
+    fn test_defaults() {
+        let config = config_from(&[]).unwrap();
+        assert_eq!(config.otlp_protocol, OtlpProtocol::Grpc);
+        assert_eq!(config.otlp_endpoint, DEFAULT_OTLP_GRPC_ENDPOINT);
+        assert!(config.otlp_headers.is_empty());
+        assert_eq!(config.otlp_compression, None);
+        assert_eq!(config.otlp_certificate, None);
+        assert_eq!(config.service_name, env!("CARGO_PKG_NAME"));
+        assert!(config.resource_attributes.is_empty());
+        assert!(matches!(
+            config.sampler,
+            TracesSampler::Sdk(Sampler::ParentBased(_))
+        ));
+        assert_eq!(config.log_directives, DEFAULT_LOG_DIRECTIVES);
+        assert_eq!(config.log_format, LogFormat::Text);
+        assert!(config.redaction.is_empty());
+        assert_eq!(config.baggage, BaggageRules::default());
+    }
+
+    #[test]
+    fn test_values_from_env() {
+        let config = config_from(&[
+            ("OTEL_EXPORTER_OTLP_ENDPOINT", "https://collector:4317"),
+            ("OTEL_SERVICE_NAME", "checkout"),
+            (
+                "OTEL_RESOURCE_ATTRIBUTES",
+                "deployment.environment.name=prod, team=core%2Cedge, owner%20email=a%40b.c",
+            ),
+            ("OTEL_TRACES_SAMPLER", "traceidratio"),
+            ("OTEL_TRACES_SAMPLER_ARG", "0.25"),
+            ("RUST_LOG", "crate_axum_opentelemetry=debug,tower_http=info"),
+            ("LOG_FORMAT", "json"),
+            ("REDACTION_KEYS", "enduser.id, url.query"),
+            ("REDACTION_PATTERN", r"[\w.+-]+@[\w-]+\.[\w.]+"),
+            ("REDACTION_MODE", "hash"),
+            ("BAGGAGE_KEYS", "tenant, region"),
+            ("BAGGAGE_MAX_ENTRIES", "1"),
+            ("BAGGAGE_MAX_VALUE_LEN", "64"),
+        ])
+        .unwrap();
+        assert_eq!(config.otlp_endpoint, "https://collector:4317");
+        assert_eq!(config.service_name, "checkout");
+        assert_eq!(
+            config.resource_attributes,
+            vec![
+                KeyValue::new("deployment.environment.name", "prod"),
+                KeyValue::new("team", "core,edge"),
+                KeyValue::new("owner email", "a@b.c"),
+            ]
+        );
+        assert!(matches!(
+            config.sampler,
+            TracesSampler::Sdk(Sampler::TraceIdRatioBased(r)) if r == 0.25
+        ));
+        assert_eq!(config.log_format, LogFormat::Json);
+        assert_eq!(config.redaction.keys, vec!["enduser.id", "url.query"]);
+        assert!(config.redaction.pattern.is_some());
+        assert_eq!(config.redaction.mode, RedactionMode::Hash);
+        assert_eq!(
+            config.baggage,
+            BaggageRules {
+                keys: vec!["tenant".into(), "region".into()],
+                max_entries: 1,
+                max_value_len: 64,
+            }
+        );
+    }
+
+    #[test]
+    fn test_otlp_transport() {
+        let config = config_from(&[
+            ("OTEL_EXPORTER_OTLP_PROTOCOL", "http/protobuf"),
+            (
+                "OTEL_EXPORTER_OTLP_HEADERS",
+                "Authorization=Bearer%20s3cret, x-tenant=acme",
+            ),
+            ("OTEL_EXPORTER_OTLP_COMPRESSION", "gzip"),
+            (
+                "OTEL_EXPORTER_OTLP_CERTIFICATE",
+                "/etc/ssl/collector-ca.pem",
+            ),
+        ])
+        .unwrap();
+        assert_eq!(config.otlp_protocol, OtlpProtocol::HttpProtobuf);
+        assert_eq!(config.otlp_endpoint, DEFAULT_OTLP_HTTP_ENDPOINT);
+        assert_eq!(config.otlp_headers["authorization"], "Bearer s3cret");
+        assert_eq!(config.otlp_headers["x-tenant"], "acme");
+        assert_eq!(config.otlp_compression, Some(Compression::Gzip));
+        assert_eq!(
+            config.otlp_certificate,
+            Some(PathBuf::from("/etc/ssl/collector-ca.pem"))
+        );
+    }
+
+    #[test]
+    fn test_rule_based_sampler() {
+        let config = config_from(&[
+            ("OTEL_TRACES_SAMPLER", "rule_based"),
+            ("OTEL_TRACES_SAMPLER_ARG", "0.1"),
+            ("SAMPLING_SLOW_THRESHOLD_MS", "300"),
+            ("SAMPLING_ROUTE_RULES", "/health=0.01:250, /orders=0.5"),
+        ])
+        .unwrap();
+        let TracesSampler::RuleBased(rules) = config.sampler else {
+            panic!("expected the rule-based sampler");
+        };
+        assert_eq!(
+            rules,
+            SamplingRules {
+                default_ratio: 0.1,
+                slow_threshold: Duration::from_millis(300),
+                routes: vec![
+                    RouteRule {
+                        route: "/health".to_owned(),
+                        ratio: 0.01,
+                        slow_threshold: Some(Duration::from_millis(250)),
+                    },
+                    RouteRule {
+                        route: "/orders".to_owned(),
+                        ratio: 0.5,
+                        slow_threshold: None,
+                    },
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn test_tail_based_sampler() {
+        let config = config_from(&[
+            ("OTEL_TRACES_SAMPLER", "tail_based"),
+            ("OTEL_TRACES_SAMPLER_ARG", "0.05"),
+            ("SAMPLING_SLOW_THRESHOLD_MS", "300"),
+            ("TAIL_SAMPLING_MAX_SPANS", "2048"),
+        ])
+        .unwrap();
+        let TracesSampler::TailBased(rules) = config.sampler else {
+            panic!("expected the tail-based sampler");
+        };
+        assert_eq!(
+            rules,
+            TailSamplingRules {
+                ratio: 0.05,
+                latency_threshold: Duration::from_millis(300),
+                decision_wait: DEFAULT_DECISION_WAIT,
+                max_spans: 2048,
+            }
+        );
+    }
+
+    #[test]
+    fn test_empty_values_are_ignored() {
+        let config = config_from(&[("OTEL_SERVICE_NAME", ""), ("RUST_LOG", " ")]).unwrap();
+        assert_eq!(config.service_name, env!("CARGO_PKG_NAME"));
+        assert_eq!(config.log_directives, DEFAULT_LOG_DIRECTIVES);
+    }
+
+    #[test]
+    fn test_invalid_values() {
+        let invalid = [
+            ("OTEL_EXPORTER_OTLP_ENDPOINT", "localhost:4317"),
+            ("OTEL_EXPORTER_OTLP_ENDPOINT", "http://localhost:99999"),
+            ("OTEL_EXPORTER_OTLP_PROTOCOL", "http/json"),
+            ("OTEL_EXPORTER_OTLP_HEADERS", "authorization"),
+            ("OTEL_EXPORTER_OTLP_HEADERS", "authorization=Bearer%2"),
+            (
+                "OTEL_EXPORTER_OTLP_HEADERS",
+                "x-tenant=acme,authorization Bearer s3cret==",
+            ),
+            ("OTEL_EXPORTER_OTLP_COMPRESSION", "zip"),
+            ("OTEL_RESOURCE_ATTRIBUTES", "team"),
+            ("OTEL_RESOURCE_ATTRIBUTES", "team=core%2"),
+            ("OTEL_TRACES_SAMPLER", "sometimes"),
+            ("OTEL_TRACES_SAMPLER_ARG", "1.5"),
+            ("RUST_LOG", "crate=loud"),
+            ("LOG_FORMAT", "yaml"),
+            ("SAMPLING_SLOW_THRESHOLD_MS", "fast"),
+            ("SAMPLING_ROUTE_RULES", "/health=2"),
+            ("SAMPLING_ROUTE_RULES", "health=0.5"),
+            ("SAMPLING_ROUTE_RULES", "/health=0.5:soon"),
+            ("TAIL_SAMPLING_DECISION_WAIT_MS", "soon"),
+            ("TAIL_SAMPLING_MAX_SPANS", "0"),
+            ("REDACTION_PATTERN", "[a-z"),
+            ("REDACTION_MODE", "encrypt"),
+            ("BAGGAGE_MAX_ENTRIES", "-1"),
+        ];
+        for (name, value) in invalid {
+            let mut vars = vec![(name, value)];
+            if name == "OTEL_TRACES_SAMPLER_ARG" {
+                vars.push(("OTEL_TRACES_SAMPLER", "traceidratio"));
+            }
+            if name.starts_with("SAMPLING_") {
+                vars.push(("OTEL_TRACES_SAMPLER", "rule_based"));
+            }
+            if name.starts_with("TAIL_SAMPLING_") {
+                vars.push(("OTEL_TRACES_SAMPLER", "tail_based"));
+            }
+            let err = config_from(&vars).unwrap_err();
+            assert_eq!(err.name, name);
+            if name == "OTEL_EXPORTER_OTLP_HEADERS" {
+                // Only the position of the entry is reported, it may hold a credential.
+                let err = err.to_string();
+                assert!(!err.contains("Bearer") && !err.contains("s3cret"), "{err}");
+                assert!(err.contains(if value.contains(',') {
+                    "entry 2"
+                } else {
+                    "entry 1"
+                }));
+            } else {
+                assert!(err.to_string().contains(value));
+            }
+        }
+    }
+}
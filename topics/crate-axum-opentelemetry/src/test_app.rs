@@ -0,0 +1,274 @@
+//! In-process harness for the tests of the service: the router is built from settings as by
+//! `serve`, without binding a port, and called through `tower::ServiceExt`. The spans and metrics
+//! of the requests are kept in memory, so that tests can assert e.g. that a request produced the
+//! spans `GET /chain` → `chain_handler` with `http.response.status_code = 502`.
+//!
+//! The subscriber is installed for the current thread only: the tests run on the default
+//! single-threaded runtime of `#[tokio::test]`, so that the spawned tasks are traced too.
+
+use crate::app;
+use crate::exemplars::Exemplars;
+use crate::health_outcomes::OutcomesConfig;
+use crate::open_telemetry::{propagator, LogFilterHandle};
+use crate::rate_limit::LimitsConfig;
+use crate::settings::ServiceConfig;
+use crate::state;
+use crate::telemetry_config::TelemetryConfig;
+use axum::body::Body;
+use axum::http::Request;
+use axum::response::Response;
+use axum::Router;
+use opentelemetry::metrics::MeterProvider;
+use opentelemetry::trace::TracerProvider;
+use opentelemetry::{global, Context, Key, KeyValue, Value};
+use opentelemetry_sdk::error::OTelSdkResult;
+use opentelemetry_sdk::metrics::data::{AggregatedMetrics, MetricData, Sum};
+use opentelemetry_sdk::metrics::{InMemoryMetricExporter, PeriodicReader, SdkMeterProvider};
+use opentelemetry_sdk::trace::{
+    InMemorySpanExporter, SdkTracerProvider, Span, SpanData, SpanProcessor,
+};
+use std::sync::{Arc, Mutex, Once};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tower::ServiceExt;
+use tracing::subscriber::DefaultGuard;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::EnvFilter;
+
+/// Installs the propagator of the service, once for all the tests: the global propagator is shared
+/// by the tests running in parallel.
+pub fn install_propagator() {
+    static INSTALLED: Once = Once::new();
+    INSTALLED.call_once(|| global::set_text_map_propagator(propagator()));
+}
+
+/// The service, with its telemetry recorded in memory until dropped.
+pub struct TestApp {
+    router: Router,
+    span_exporter: InMemorySpanExporter,
+    metric_exporter: InMemoryMetricExporter,
+    meter_provider: SdkMeterProvider,
+    _tracer_provider: SdkTracerProvider,
+    _subscriber: DefaultGuard,
+}
+
+impl TestApp {
+    /// The service with its default settings.
+    pub fn new() -> Self {
+        Self::with_settings(&[])
+    }
+
+    /// The service with `settings` (e.g. `[("ROUTES", "/health")]`) over the defaults. The
+    /// environment and the configuration file are ignored.
+    pub fn with_settings(settings: &[(&str, &str)]) -> Self {
+        let lookup = |name: &str| {
+            settings
+                .iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| value.to_string())
+        };
+        let service = ServiceConfig::from_lookup(lookup).unwrap();
+        let limits = LimitsConfig::from_lookup(lookup).unwrap();
+        let outcomes = OutcomesConfig::from_lookup(lookup).unwrap();
+        let telemetry = TelemetryConfig::from_lookup(lookup).unwrap();
+
+        install_propagator();
+        let span_exporter = InMemorySpanExporter::default();
+        let tracer_provider = SdkTracerProvider::builder()
+            .with_simple_exporter(span_exporter.clone())
+            .build();
+        let metric_exporter = InMemoryMetricExporter::default();
+        let meter_provider = SdkMeterProvider::builder()
+            .with_reader(PeriodicReader::builder(metric_exporter.clone()).build())
+            .build();
+        let (filter, log_filter) = LogFilterHandle::new(EnvFilter::new("info"));
+        let subscriber = tracing_subscriber::registry()
+            .with(filter)
+            .with(tracing_opentelemetry::layer().with_tracer(tracer_provider.tracer("test")));
+        let subscriber = tracing::subscriber::set_default(subscriber);
+
+        let (state, limiter, job_worker) = state::build(
+            &service,
+            &telemetry,
+            limits,
+            outcomes,
+            &meter_provider.meter("test"),
+            Exemplars::default(),
+        )
+        .unwrap();
+        // Stops with the runtime of the test.
+        tokio::spawn(job_worker.run());
+        let router = app::router(state, &service, limiter, log_filter);
+
+        TestApp {
+            router,
+            span_exporter,
+            metric_exporter,
+            meter_provider,
+            _tracer_provider: tracer_provider,
+            _subscriber: subscriber,
+        }
+    }
+
+    /// Sends `request` to the router, as the server would.
+    pub async fn request(&self, request: Request<Body>) -> Response {
+        self.router.clone().oneshot(request).await.unwrap()
+    }
+
+    pub async fn get(&self, uri: &str) -> Response {
+        self.request(Request::get(uri).body(Body::empty()).unwrap())
+            .await
+    }
+
+    /// Serves the router on `listener` until the end of the test, e.g. as the `DOWNSTREAM_URL` of
+    /// its own `/chain` route.
+    pub fn serve(&self, listener: TcpListener) {
+        let router = self.router.clone();
+        tokio::spawn(async move { axum::serve(listener, router).await });
+    }
+
+    /// Spans ended so far, in the order they ended.
+    pub fn spans(&self) -> Vec<SpanData> {
+        self.span_exporter.get_finished_spans().unwrap()
+    }
+
+    /// Last span named `name` ended so far.
+    pub fn span(&self, name: &str) -> SpanData {
+        let spans = self.spans();
+        let names: Vec<_> = spans.iter().map(|span| span.name.to_string()).collect();
+        spans
+            .into_iter()
+            .rev()
+            .find(|span| span.name == name)
+            .unwrap_or_else(|| panic!("no span named {name:?}, only {names:?}"))
+    }
+
+    /// Waits for a span named `name` to end, e.g. the one of a background job, and returns it.
+    pub async fn wait_for_span(&self, name: &str) -> SpanData {
+        let ended = async {
+            loop {
+                let spans = self.spans();
+                if let Some(span) = spans.into_iter().rev().find(|span| span.name == name) {
+                    return span;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(10), ended)
+            .await
+            .unwrap_or_else(|_| panic!("no span named {name:?} ended"))
+    }
+
+    /// Checks that the last span named `names[0]` has a child named `names[1]`, which has a child
+    /// named `names[2]`, and so on. Returns the spans of the chain, in the same order.
+    pub fn span_chain(&self, names: &[&str]) -> Vec<SpanData> {
+        let spans = self.spans();
+        let mut chain = vec![self.span(names[0])];
+        for name in &names[1..] {
+            let parent = chain.last().unwrap();
+            let children = spans
+                .iter()
+                .filter(|span| span.parent_span_id == parent.span_context.span_id());
+            let child = children
+                .clone()
+                .find(|span| span.name == *name)
+                .unwrap_or_else(|| {
+                    let children: Vec<_> = children.map(|span| span.name.to_string()).collect();
+                    panic!(
+                        "no span named {name:?} under {:?}, only {children:?}",
+                        parent.name
+                    )
+                });
+            chain.push(child.clone());
+        }
+        chain
+    }
+
+    /// Sum of the data points of the `u64` counter `name` having every attribute of `attributes`,
+    /// as collected now.
+    pub fn counter(&self, name: &str, attributes: &[KeyValue]) -> u64 {
+        self.with_metric(name, |data| match data {
+            AggregatedMetrics::U64(MetricData::Sum(sum)) => sum_of(sum, attributes),
+            _ => panic!("{name} is not a u64 counter"),
+        })
+        .unwrap_or(0)
+    }
+
+    /// Same as `counter`, for the `i64` up-down counter `name`.
+    pub fn up_down_counter(&self, name: &str, attributes: &[KeyValue]) -> i64 {
+        self.with_metric(name, |data| match data {
+            AggregatedMetrics::I64(MetricData::Sum(sum)) => sum_of(sum, attributes),
+            _ => panic!("{name} is not an i64 up-down counter"),
+        })
+        .unwrap_or(0)
+    }
+
+    // Calls `f` with the data of the metric `name`, as collected now.
+    fn with_metric<R>(&self, name: &str, f: impl FnOnce(&AggregatedMetrics) -> R) -> Option<R> {
+        self.meter_provider.force_flush().unwrap();
+        let exports = self.metric_exporter.get_finished_metrics().unwrap();
+        // The sums are cumulative: the last collection holds the whole count.
+        let data = exports
+            .iter()
+            .rev()
+            .flat_map(|resource| resource.scope_metrics())
+            .flat_map(|scope| scope.metrics())
+            .find(|metric| metric.name() == name)
+            .map(|metric| f(metric.data()));
+        data
+    }
+}
+
+fn sum_of<T: Copy + std::iter::Sum<T>>(sum: &Sum<T>, attributes: &[KeyValue]) -> T {
+    sum.data_points()
+        .filter(|point| {
+            attributes
+                .iter()
+                .all(|expected| point.attributes().any(|kv| kv == expected))
+        })
+        .map(|point| point.value())
+        .sum()
+}
+
+/// Value of the attribute `key` of `span`.
+pub fn attribute(span: &SpanData, key: &'static str) -> Option<Value> {
+    span.attributes
+        .iter()
+        .find(|kv| kv.key == Key::from_static_str(key))
+        .map(|kv| kv.value.clone())
+}
+
+/// Span processor standing for the exporting one, in the tests of the sampling processors: keeps
+/// the spans it receives.
+#[derive(Debug, Clone, Default)]
+pub struct RecordingProcessor {
+    pub spans: Arc<Mutex<Vec<SpanData>>>,
+}
+
+impl RecordingProcessor {
+    /// IDs of the received spans, in order.
+    pub fn span_ids(&self) -> Vec<u64> {
+        self.spans
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|span| u64::from_be_bytes(span.span_context.span_id().to_bytes()))
+            .collect()
+    }
+}
+
+impl SpanProcessor for RecordingProcessor {
+    fn on_start(&self, _span: &mut Span, _cx: &Context) {}
+
+    fn on_end(&self, span: SpanData) {
+        self.spans.lock().unwrap().push(span);
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        Ok(())
+    }
+
+    fn shutdown_with_timeout(&self, _timeout: Duration) -> OTelSdkResult {
+        Ok(())
+    }
+}